@@ -5,30 +5,213 @@
 // or https://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use crate::control::ChildOutput;
+use crate::control::OutputReader;
 use crate::errors::Error;
 use crate::errors::bail;
+use crate::eval_context::LateOutput;
 use crate::runtime;
+use std::fmt;
 use std::io::BufReader;
+use std::path::PathBuf;
 use std::process;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::atomic::AtomicI32;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+/// Limits on the resources that the subprocess may use. Applied whenever the subprocess is started.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct ResourceLimits {
+    /// Maximum size of the subprocess's address space.
+    pub(crate) memory_bytes: Option<u64>,
+    /// Maximum CPU time used by the subprocess over its lifetime.
+    pub(crate) cpu: Option<Duration>,
+}
+
+impl ResourceLimits {
+    /// Returns a copy of these limits, updated by `args`, which should be of the form
+    /// `memory=4G cpu=60s`. A value of `off` removes the limit.
+    pub(crate) fn updated(mut self, args: &str) -> Result<ResourceLimits, Error> {
+        for arg in args.split_whitespace() {
+            let Some((key, value)) = arg.split_once('=') else {
+                bail!("Expected key=value, got `{}`", arg);
+            };
+            let off = value == "off" || value == "0";
+            match key {
+                "memory" => {
+                    self.memory_bytes = if off {
+                        None
+                    } else {
+                        Some(parse_memory_size(value)?)
+                    }
+                }
+                "cpu" => self.cpu = crate::eval_context::parse_duration_limit(value)?,
+                _ => bail!(
+                    "Unknown limit `{}`. Supported limits are memory and cpu",
+                    key
+                ),
+            }
+        }
+        Ok(self)
+    }
+}
+
+impl fmt::Display for ResourceLimits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.memory_bytes {
+            Some(bytes) => write!(f, "memory={}", format_memory_size(bytes))?,
+            None => write!(f, "memory=off")?,
+        }
+        match self.cpu {
+            Some(cpu) => write!(f, " cpu={cpu:?}"),
+            None => write!(f, " cpu=off"),
+        }
+    }
+}
+
+/// Parses sizes like "512M" or "4G". A number without a suffix is taken to be bytes.
+pub(crate) fn parse_memory_size(text: &str) -> Result<u64, Error> {
+    let text = text.trim();
+    let (number, multiplier) = match text.char_indices().last() {
+        Some((index, 'K' | 'k')) => (&text[..index], 1 << 10),
+        Some((index, 'M' | 'm')) => (&text[..index], 1 << 20),
+        Some((index, 'G' | 'g')) => (&text[..index], 1 << 30),
+        Some((index, 'T' | 't')) => (&text[..index], 1 << 40),
+        _ => (text, 1),
+    };
+    let Ok(number) = number.parse::<u64>() else {
+        bail!("Invalid memory size `{}`", text);
+    };
+    match number.checked_mul(multiplier) {
+        Some(bytes) => Ok(bytes),
+        None => bail!("Memory size `{}` is too large", text),
+    }
+}
+
+fn format_memory_size(bytes: u64) -> String {
+    for (suffix, multiplier) in [
+        ("T", 1 << 40),
+        ("G", 1 << 30),
+        ("M", 1 << 20),
+        ("K", 1 << 10),
+    ] {
+        if bytes >= multiplier && bytes.is_multiple_of(multiplier) {
+            return format!("{}{suffix}", bytes / multiplier);
+        }
+    }
+    bytes.to_string()
+}
+
+/// Resource limits that are read by the subprocess after it's forked, but before it execs. Atomics
+/// are used since we can't safely lock a mutex at that point. Zero means no limit.
+#[derive(Default)]
+struct SharedLimits {
+    memory_bytes: AtomicU64,
+    cpu_seconds: AtomicU64,
+}
+
+impl SharedLimits {
+    fn set(&self, limits: &ResourceLimits) {
+        self.memory_bytes
+            .store(limits.memory_bytes.unwrap_or(0), Ordering::SeqCst);
+        // Limits on CPU time have a granularity of seconds, so we round up.
+        let cpu_seconds = limits
+            .cpu
+            .map(|cpu| cpu.as_secs() + u64::from(cpu.subsec_nanos() > 0))
+            .unwrap_or(0);
+        self.cpu_seconds.store(cpu_seconds, Ordering::SeqCst);
+    }
+
+    #[cfg(unix)]
+    fn apply(&self) -> std::io::Result<()> {
+        fn rlimit(soft: u64, hard: u64) -> libc::rlimit {
+            libc::rlimit {
+                rlim_cur: soft as libc::rlim_t,
+                rlim_max: hard as libc::rlim_t,
+            }
+        }
+        fn check(result: libc::c_int) -> std::io::Result<()> {
+            if result != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        }
+        let memory_bytes = self.memory_bytes.load(Ordering::SeqCst);
+        if memory_bytes != 0 {
+            // Safety: setrlimit is async-signal-safe and is passed a valid rlimit.
+            check(unsafe {
+                libc::setrlimit(libc::RLIMIT_AS, &rlimit(memory_bytes, memory_bytes))
+            })?;
+        }
+        let cpu_seconds = self.cpu_seconds.load(Ordering::SeqCst);
+        if cpu_seconds != 0 {
+            // Exceeding the soft limit sends SIGXCPU, which terminates the process. The hard limit
+            // is a little higher, so that we get SIGXCPU rather than SIGKILL.
+            check(unsafe {
+                libc::setrlimit(libc::RLIMIT_CPU, &rlimit(cpu_seconds, cpu_seconds + 1))
+            })?;
+        }
+        Ok(())
+    }
+}
 
 pub(crate) struct ChildProcess {
     process_handle: Arc<Mutex<std::process::Child>>,
     /// Whether cleanup of `process_handle` is the responsibility of another
     /// instance.
     process_disowned: bool,
-    stdout: std::io::Lines<BufReader<std::process::ChildStdout>>,
-    // Only none while in drop.
-    stdin: Option<std::process::ChildStdin>,
+    output: crossbeam_channel::Receiver<ReaderEvent>,
+    /// Distinguishes this process from all others that we've started, including earlier processes
+    /// of the same instance.
+    generation: u64,
+    /// Where we send commands. Shared with the thread that reads our output, which acknowledges some
+    /// control messages. Only none while in drop.
+    commands: Arc<Commands>,
     command: Arc<Mutex<process::Command>>,
     stderr_sender: Arc<Mutex<crossbeam_channel::Sender<String>>>,
+    late_stdout_sender: crossbeam_channel::Sender<LateOutput>,
+    /// The limits that applied when the current process was started.
+    limits: ResourceLimits,
+    shared_limits: Arc<SharedLimits>,
+    child_fds: Arc<ChildFds>,
+}
+
+/// Where commands for the subprocess are written. Only none while the subprocess is being dropped.
+type Commands = Mutex<Option<Box<dyn std::io::Write + Send>>>;
+
+/// Sent by the thread that reads output from the subprocess.
+enum ReaderEvent {
+    Output(ChildOutput),
+    Failed(std::io::Error),
+    /// The subprocess has terminated. Contains whatever was left unread on stdout.
+    Terminated(String),
+}
+
+/// The ends of the control and command pipes for a subprocess that we're about to start, or -1.
+/// Read between fork and exec, so that they can be given fixed fds in the subprocess.
+struct ChildFds {
+    control: AtomicI32,
+    commands: AtomicI32,
+}
+
+impl ChildFds {
+    fn new() -> ChildFds {
+        ChildFds {
+            control: AtomicI32::new(-1),
+            commands: AtomicI32::new(-1),
+        }
+    }
 }
 
 impl ChildProcess {
     pub(crate) fn new(
         mut command: std::process::Command,
         stderr_sender: crossbeam_channel::Sender<String>,
+        late_stdout_sender: crossbeam_channel::Sender<LateOutput>,
+        limits: ResourceLimits,
     ) -> Result<ChildProcess, Error> {
         // Avoid a fork bomb. We could call runtime_hook here but then all the work that we did up
         // to this point would be wasted. Also, it's possible that we could already have started
@@ -43,13 +226,50 @@ impl ChildProcess {
             .args(user_args)
             .env(runtime::EVCXR_IS_RUNTIME_VAR, "1")
             .env("RUST_BACKTRACE", "1")
-            .stdin(std::process::Stdio::piped())
             .stdout(std::process::Stdio::piped())
             .stderr(std::process::Stdio::piped());
+        let shared_limits = Arc::new(SharedLimits::default());
+        shared_limits.set(&limits);
+        let child_fds = Arc::new(ChildFds::new());
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            let shared_limits = Arc::clone(&shared_limits);
+            let child_fds = Arc::clone(&child_fds);
+            command
+                .env(
+                    crate::control::CONTROL_FD_ENV,
+                    crate::control::CONTROL_FD.to_string(),
+                )
+                .env(
+                    crate::control::COMMAND_FD_ENV,
+                    crate::control::COMMAND_FD.to_string(),
+                )
+                // The subprocess replaces stdin with a pipe of its own.
+                .stdin(std::process::Stdio::null());
+            // Safety: The closure only calls setrlimit, dup2 and fcntl and reads atomics, all of
+            // which are safe to do between fork and exec.
+            unsafe {
+                command.pre_exec(move || {
+                    shared_limits.apply()?;
+                    move_child_fds(&child_fds)
+                });
+            }
+        }
+        #[cfg(not(unix))]
+        command.stdin(std::process::Stdio::piped());
+        #[cfg(not(unix))]
+        if limits != ResourceLimits::default() {
+            bail!("Resource limits are only supported on Unix");
+        }
         ChildProcess::new_internal(
             Arc::new(Mutex::new(command)),
             None,
             Arc::new(Mutex::new(stderr_sender)),
+            late_stdout_sender,
+            limits,
+            shared_limits,
+            child_fds,
         )
     }
 
@@ -57,18 +277,53 @@ impl ChildProcess {
         command: Arc<Mutex<std::process::Command>>,
         process_handle: Option<Arc<Mutex<std::process::Child>>>,
         stderr_sender: Arc<Mutex<crossbeam_channel::Sender<String>>>,
+        late_stdout_sender: crossbeam_channel::Sender<LateOutput>,
+        limits: ResourceLimits,
+        shared_limits: Arc<SharedLimits>,
+        child_fds: Arc<ChildFds>,
     ) -> Result<ChildProcess, Error> {
+        // On other platforms, control messages are sent via stdout and commands via stdin.
+        #[cfg(unix)]
+        let (control_reader, mut commands, child_ends) = {
+            use std::os::fd::AsRawFd;
+            let (control_reader, control_writer) = std::io::pipe()?;
+            let (command_reader, command_writer) = std::io::pipe()?;
+            child_fds
+                .control
+                .store(control_writer.as_raw_fd(), Ordering::SeqCst);
+            child_fds
+                .commands
+                .store(command_reader.as_raw_fd(), Ordering::SeqCst);
+            let commands: Box<dyn std::io::Write + Send> = Box::new(command_writer);
+            (
+                Some(control_reader),
+                Some(commands),
+                (control_writer, command_reader),
+            )
+        };
+        #[cfg(not(unix))]
+        let (control_reader, mut commands) = (None, None);
         let process = command.lock().unwrap().spawn();
+        // The subprocess has its own copies of the ends that it uses. Closing ours means that we'll
+        // see EOF on the control pipe when the subprocess terminates and vice versa.
+        #[cfg(unix)]
+        {
+            child_fds.control.store(-1, Ordering::SeqCst);
+            child_fds.commands.store(-1, Ordering::SeqCst);
+            drop(child_ends);
+        }
         let mut process = match process {
             Ok(c) => c,
             Err(error) => bail!("Failed to run '{:?}': {:?}", command, error),
         };
 
-        let stdin = process.stdin.take();
+        if let Some(stdin) = process.stdin.take() {
+            commands = Some(Box::new(stdin));
+        }
         // Handle stderr by patching it through to a channel in our output struct.
         let mut child_stderr =
             std::io::BufRead::lines(BufReader::new(process.stderr.take().unwrap()));
-        let stdout = std::io::BufRead::lines(BufReader::new(process.stdout.take().unwrap()));
+        let output_reader = OutputReader::new(process.stdout.take().unwrap(), control_reader);
 
         // If we already have an Arc<Mutex<>> wrapping an old process, then
         // reuse it, putting our new process into it. If we don't, then create a
@@ -84,6 +339,25 @@ impl ChildProcess {
             None => Arc::new(Mutex::new(process)),
         };
 
+        static NEXT_GENERATION: AtomicU64 = AtomicU64::new(0);
+        let generation = NEXT_GENERATION.fetch_add(1, Ordering::Relaxed);
+        let commands = Arc::new(Mutex::new(commands));
+        let (output_sender, output) = crossbeam_channel::unbounded();
+        std::thread::spawn({
+            let late_stdout_sender = late_stdout_sender.clone();
+            // Only a subprocess with a command pipe can receive acknowledgements while it's busy.
+            let acknowledgements = cfg!(unix).then(|| Arc::clone(&commands));
+            move || {
+                read_output(
+                    output_reader,
+                    output_sender,
+                    late_stdout_sender,
+                    generation,
+                    acknowledgements,
+                )
+            }
+        });
+
         std::thread::spawn({
             let stderr_sender = Arc::clone(&stderr_sender);
             move || {
@@ -98,13 +372,45 @@ impl ChildProcess {
         Ok(ChildProcess {
             process_handle,
             process_disowned: false,
-            stdout,
-            stdin,
+            output,
+            generation,
+            commands,
             command,
             stderr_sender,
+            late_stdout_sender,
+            limits,
+            shared_limits,
+            child_fds,
         })
     }
 
+    /// Returns the generation of the current process, which changes each time it's restarted.
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Sets the directories that the subprocess may write to when it's next restarted. If empty,
+    /// the subprocess won't be sandboxed.
+    pub(crate) fn set_sandbox_dirs(&mut self, writable_dirs: &[PathBuf]) -> Result<(), Error> {
+        crate::sandbox::configure_command(&mut self.command.lock().unwrap(), writable_dirs)
+    }
+
+    /// Checks that the subprocess would be able to sandbox itself, permitting writes only within
+    /// `writable_dirs`. The current subprocess isn't affected.
+    pub(crate) fn check_sandbox(&self, writable_dirs: &[PathBuf]) -> Result<(), Error> {
+        crate::sandbox::probe(&self.command.lock().unwrap(), writable_dirs)
+    }
+
+    /// Sets the limits that will apply when the subprocess is next restarted.
+    pub(crate) fn set_limits(&mut self, limits: ResourceLimits) -> Result<(), Error> {
+        if cfg!(not(unix)) && limits != ResourceLimits::default() {
+            bail!("Resource limits are only supported on Unix");
+        }
+        self.shared_limits.set(&limits);
+        self.limits = limits;
+        Ok(())
+    }
+
     /// Returns a handle to our subprocess. This handle may be used to terminate
     /// the subprocess. The returned handle is valid across restarts. i.e. if
     /// restart is called, then there is no need to call this method again to
@@ -114,6 +420,17 @@ impl ChildProcess {
         self.process_handle.clone()
     }
 
+    /// Exchanges which process our handle and `other`'s handle refer to, so that handles previously
+    /// returned by `process_handle` on either instance now refer to the other process. Each
+    /// instance continues to use its own process.
+    pub(crate) fn swap_process_handles(&mut self, other: &mut ChildProcess) {
+        std::mem::swap(
+            &mut *self.process_handle.lock().unwrap(),
+            &mut *other.process_handle.lock().unwrap(),
+        );
+        std::mem::swap(&mut self.process_handle, &mut other.process_handle);
+    }
+
     /// Terminates this process if it hasn't already, then restarts
     pub(crate) fn restart(&mut self) -> Result<ChildProcess, Error> {
         // If the process hasn't already terminated for some reason, kill it.
@@ -130,34 +447,64 @@ impl ChildProcess {
             Arc::clone(&self.command),
             Some(self.process_handle.clone()),
             Arc::clone(&self.stderr_sender),
+            self.late_stdout_sender.clone(),
+            self.limits,
+            Arc::clone(&self.shared_limits),
+            Arc::clone(&self.child_fds),
         )
     }
 
     pub(crate) fn send(&mut self, command: &str) -> Result<(), Error> {
-        use std::io::Write;
-        writeln!(self.stdin.as_mut().unwrap(), "{command}")
-            .map_err(|_| self.get_termination_error())?;
-        self.stdin.as_mut().unwrap().flush()?;
-        Ok(())
+        send_command(&self.commands, command).map_err(|_| self.get_termination_error())
     }
 
-    pub(crate) fn recv_line(&mut self) -> Result<String, Error> {
-        Ok(self
-            .stdout
-            .next()
-            .ok_or_else(|| self.get_termination_error())??)
+    /// Sends `text` to be read from stdin by the user's code.
+    pub(crate) fn send_input(&mut self, text: &str) -> Result<(), Error> {
+        if cfg!(unix) {
+            for line in text.split('\n') {
+                self.send(&format!("{}{line}", crate::user_stdin::INPUT_COMMAND))?;
+            }
+            Ok(())
+        } else {
+            self.send(text)
+        }
+    }
+
+    /// Returns the next line of stdout or control message from the subprocess.
+    pub(crate) fn recv(&mut self) -> Result<ChildOutput, Error> {
+        match self.output.recv() {
+            Ok(ReaderEvent::Output(output)) => Ok(output),
+            Ok(ReaderEvent::Failed(error)) => Err(error.into()),
+            Ok(ReaderEvent::Terminated(content)) => Err(self.termination_error(content)),
+            Err(_) => Err(self.get_termination_error()),
+        }
     }
 
     fn get_termination_error(&mut self) -> Error {
+        // Whatever output remains is likely to explain why the subprocess terminated.
+        let mut content = String::new();
+        for event in self.output.iter() {
+            match event {
+                ReaderEvent::Output(ChildOutput::Stdout(line)) => {
+                    content.push_str(&line);
+                    content.push('\n');
+                }
+                ReaderEvent::Output(ChildOutput::Control(message)) => {
+                    content.push_str(&message.header);
+                    content.push('\n');
+                }
+                ReaderEvent::Terminated(remaining) => content.push_str(&remaining),
+                ReaderEvent::Failed(_) => {}
+            }
+        }
+        self.termination_error(content)
+    }
+
+    fn termination_error(&mut self, content: String) -> Error {
         // Wait until the stderr handling thread has released its lock on stderr_sender, which it
         // will do when there's nothing more to read from stderr. We don't need to keep the lock,
         // just wait until we can aquire it, then drop it straight away.
         std::mem::drop(self.stderr_sender.lock().unwrap());
-        let mut content = String::new();
-        while let Some(Ok(line)) = self.stdout.next() {
-            content.push_str(&line);
-            content.push('\n');
-        }
         Error::SubprocessTerminated(match self.process_handle.lock().unwrap().wait() {
             Ok(exit_status) => {
                 #[cfg(target_os = "macos")]
@@ -173,6 +520,11 @@ impl ChildProcess {
                         );
                     }
                 }
+                if let Some(explanation) = self.limit_explanation(&exit_status) {
+                    return Error::SubprocessTerminated(format!(
+                        "{content}Subprocess terminated with status: {exit_status}. {explanation}"
+                    ));
+                }
                 format!("{content}Subprocess terminated with status: {exit_status}",)
             }
             Err(wait_error) => format!("Subprocess didn't start: {wait_error}"),
@@ -180,11 +532,152 @@ impl ChildProcess {
     }
 }
 
+impl ChildProcess {
+    /// Returns an explanation of the subprocess having terminated with `exit_status` if it's likely
+    /// that it was due to one of our resource limits.
+    #[cfg(unix)]
+    fn limit_explanation(&self, exit_status: &std::process::ExitStatus) -> Option<String> {
+        use std::os::unix::process::ExitStatusExt;
+        match (exit_status.signal(), self.limits) {
+            (Some(libc::SIGXCPU), ResourceLimits { cpu: Some(cpu), .. }) => {
+                Some(format!("It exceeded its CPU time limit of {cpu:?}"))
+            }
+            // Allocation failures abort the process.
+            (
+                Some(libc::SIGABRT),
+                ResourceLimits {
+                    memory_bytes: Some(bytes),
+                    ..
+                },
+            ) => Some(format!(
+                "It probably exceeded its memory limit of {}",
+                format_memory_size(bytes)
+            )),
+            _ => None,
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn limit_explanation(&self, _exit_status: &std::process::ExitStatus) -> Option<String> {
+        None
+    }
+}
+
+fn send_command(commands: &Commands, command: &str) -> std::io::Result<()> {
+    use std::io::Write;
+    let mut commands = commands.lock().unwrap();
+    let Some(commands) = commands.as_mut() else {
+        return Err(std::io::ErrorKind::BrokenPipe.into());
+    };
+    writeln!(commands, "{command}")?;
+    commands.flush()
+}
+
+/// Reads output from the subprocess until it terminates. Lines of stdout that are printed after a
+/// cell completes and before the next starts, e.g. by threads that the cell started, are sent to
+/// `late_stdout_sender`, tagged with `generation` and the cell's build number. Everything else,
+/// including output that such threads print while a later cell is running, is sent to `sender`,
+/// since we can't tell which thread printed a line.
+/// If `acknowledgements` is set, the start of each cell is acknowledged by sending a command to it.
+fn read_output(
+    mut reader: OutputReader,
+    sender: crossbeam_channel::Sender<ReaderEvent>,
+    late_stdout_sender: crossbeam_channel::Sender<LateOutput>,
+    generation: u64,
+    acknowledgements: Option<Arc<Commands>>,
+) {
+    let mut running = None;
+    let mut last_completed = None;
+    loop {
+        let output = match reader.recv() {
+            Ok(Some(output)) => output,
+            Ok(None) => break,
+            Err(error) => {
+                let _ = sender.send(ReaderEvent::Failed(error));
+                return;
+            }
+        };
+        match &output {
+            ChildOutput::Control(message) => {
+                if let Some(build_num) = message
+                    .header
+                    .strip_prefix(runtime::EVCXR_EXECUTION_STARTED)
+                    .and_then(|build_num| build_num.trim().parse().ok())
+                {
+                    running = Some(build_num);
+                    // Errors are ignored, since they mean that the subprocess has terminated, which
+                    // we'll see soon enough.
+                    if let Some(commands) = &acknowledgements {
+                        let _ = send_command(commands, crate::control::ACKNOWLEDGE_COMMAND);
+                    }
+                    continue;
+                }
+                // Completion is also sent after things other than running cells, such as
+                // reporting variable details.
+                if message.header == runtime::EVCXR_EXECUTION_COMPLETE
+                    && let Some(build_num) = running.take()
+                {
+                    last_completed = Some(build_num);
+                }
+            }
+            ChildOutput::Stdout(line) => {
+                if let (None, Some(build_num)) = (running, last_completed) {
+                    // Errors are ignored, since it just means that the user of the library has
+                    // dropped the receiver.
+                    let _ = late_stdout_sender.send(LateOutput {
+                        generation,
+                        build_num,
+                        line: line.clone(),
+                    });
+                    continue;
+                }
+            }
+        }
+        if sender.send(ReaderEvent::Output(output)).is_err() {
+            // Our ChildProcess has been dropped.
+            return;
+        }
+    }
+    let _ = sender.send(ReaderEvent::Terminated(reader.remaining_stdout()));
+}
+
+/// Called between fork and exec to give the pipe ends in `child_fds` the fds that the subprocess
+/// expects. The fds that we're given were opened with close-on-exec set, which dup2 clears.
+#[cfg(unix)]
+fn move_child_fds(child_fds: &ChildFds) -> std::io::Result<()> {
+    use crate::control::COMMAND_FD;
+    use crate::control::CONTROL_FD;
+    let moves = [
+        (child_fds.control.load(Ordering::SeqCst), CONTROL_FD),
+        (child_fds.commands.load(Ordering::SeqCst), COMMAND_FD),
+    ];
+    // Each fd is first duplicated to one above all the targets, so that none gets replaced before
+    // it's been moved. The duplicates are closed on exec.
+    let mut sources = [-1; 2];
+    for (source, (fd, _)) in sources.iter_mut().zip(moves) {
+        if fd >= 0 {
+            // Safety: fcntl is async-signal-safe.
+            *source =
+                unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, CONTROL_FD.max(COMMAND_FD) + 1) };
+            if *source < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+    }
+    for (source, (_, target)) in sources.into_iter().zip(moves) {
+        // Safety: dup2 is async-signal-safe.
+        if source >= 0 && unsafe { libc::dup2(source, target) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
 impl Drop for ChildProcess {
     fn drop(&mut self) {
-        // Drop child_stdin before we wait. Our subprocess uses stdin being
-        // closed to know that it's time to terminate.
-        self.stdin.take();
+        // Drop our command channel before we wait. Our subprocess uses it
+        // being closed to know that it's time to terminate.
+        self.commands.lock().unwrap().take();
         if !self.process_disowned {
             // Wait for our subprocess to terminate. Otherwise we'll be left
             // with zombie processes.
@@ -192,3 +685,34 @@ impl Drop for ChildProcess {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::ResourceLimits;
+    use super::parse_memory_size;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_memory_size() {
+        assert_eq!(parse_memory_size("1024").unwrap(), 1024);
+        assert_eq!(parse_memory_size("512M").unwrap(), 512 << 20);
+        assert_eq!(parse_memory_size("4G").unwrap(), 4 << 30);
+        assert!(parse_memory_size("4GB").is_err());
+        assert!(parse_memory_size("G").is_err());
+        assert!(parse_memory_size("20000000T").is_err());
+    }
+
+    #[test]
+    fn test_update_limits() {
+        let limits = ResourceLimits::default()
+            .updated("memory=4G cpu=60s")
+            .unwrap();
+        assert_eq!(limits.memory_bytes, Some(4 << 30));
+        assert_eq!(limits.cpu, Some(Duration::from_secs(60)));
+        assert_eq!(limits.to_string(), "memory=4G cpu=60s");
+        let limits = limits.updated("cpu=off").unwrap();
+        assert_eq!(limits.to_string(), "memory=4G cpu=off");
+        assert!(limits.updated("disk=1G").is_err());
+        assert!(limits.updated("memory").is_err());
+    }
+}
@@ -35,6 +35,31 @@ impl Segment {
             sequence: None,
         }
     }
+
+    /// Returns a segment containing the code in `range`. If this is original user code, then the
+    /// returned segment's metadata refers to the corresponding part of the user's code, so that
+    /// errors can still be reported against it.
+    pub(crate) fn slice(&self, range: std::ops::Range<usize>) -> Segment {
+        let kind = match &self.kind {
+            CodeKind::OriginalUserCode(meta) => {
+                let before = &self.code[..range.start];
+                let column_offset = match before.rfind('\n') {
+                    Some(newline) => count_columns(&before[newline + 1..]),
+                    None => meta.column_offset + count_columns(before),
+                };
+                CodeKind::OriginalUserCode(UserCodeMetadata {
+                    start_byte: meta.start_byte + range.start,
+                    node_index: meta.node_index,
+                    start_line: meta.start_line + num_lines(before),
+                    column_offset,
+                })
+            }
+            kind => kind.clone(),
+        };
+        let mut segment = Segment::new(kind, self.code[range].to_owned());
+        segment.sequence = self.sequence;
+        segment
+    }
 }
 
 /// Information about the code the user supplied.
@@ -305,6 +330,24 @@ impl CodeBlock {
             .ok_or_else(|| anyhow!("Offset {} doesn't refer to user code", user_code_offset))
     }
 
+    /// Returns the offset in the output code at which `node_text` starts, where `node_text` is the
+    /// text of the node with index `node_index` in the user's original code. Returns None if the
+    /// node's text isn't present unmodified. Unlike offsets in the user's code, this is unambiguous
+    /// where one node ends on the same line as the next starts.
+    pub(crate) fn node_output_offset(&self, node_index: usize, node_text: &str) -> Option<usize> {
+        let mut bytes_seen = 0;
+        for segment in &self.segments {
+            if let CodeKind::OriginalUserCode(meta) = &segment.kind
+                && meta.node_index == node_index
+                && let Some(offset) = segment.code.find(node_text)
+            {
+                return Some(bytes_seen + offset);
+            }
+            bytes_seen += segment.code.len();
+        }
+        None
+    }
+
     pub(crate) fn output_offset_to_user_offset(&self, output_offset: usize) -> Result<usize> {
         let mut bytes_seen = 0;
         self.segments
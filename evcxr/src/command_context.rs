@@ -22,15 +22,28 @@ use crate::errors::SpannedMessage;
 use crate::errors::bail;
 use crate::eval_context::ContextState;
 use crate::eval_context::EvalCallbacks;
+use crate::eval_context::LossReason;
+use crate::eval_context::VariableDetails;
+use crate::eval_context::job_variable_name;
 use crate::rust_analyzer::Completion;
 use crate::rust_analyzer::Completions;
+use crate::session::ReactiveMode;
 use crate::toml_parse::ConfigToml;
 use anyhow::Result;
 use anyhow::anyhow;
 use once_cell::sync::Lazy;
+use ra_ap_syntax::AstNode;
+use ra_ap_syntax::SourceFile;
+use ra_ap_syntax::SyntaxKind;
+use ra_ap_syntax::ast;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::path::Path;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::SystemTime;
 
 /// A higher level interface to EvalContext. A bit closer to a Repl. Provides commands (start with
 /// ':') that alter context state or print information.
@@ -38,8 +51,27 @@ pub struct CommandContext {
     print_timings: bool,
     eval_context: EvalContext,
     last_errors: Vec<CompilationError>,
+    /// The name of the session that `eval_context` belongs to.
+    session_name: String,
+    /// Sessions other than the current one, keyed by name.
+    other_sessions: HashMap<String, EvalContext>,
+    /// Files added with `:watch`, which are rerun when they change.
+    watched_files: Vec<WatchedFile>,
+    /// Files that are part way through being run, so that a file can't run itself.
+    loading_files: Vec<PathBuf>,
+    /// The number of the cell most recently passed to `execute_with_callbacks`. Cells are counted
+    /// whether or not they succeed, so that numbers match those that the user sees.
+    execution_count: usize,
 }
 
+struct WatchedFile {
+    path: PathBuf,
+    /// When the file had been modified as of when we last ran it.
+    modified: Option<SystemTime>,
+}
+
+const DEFAULT_SESSION_NAME: &str = "default";
+
 impl CommandContext {
     pub fn new() -> Result<(CommandContext, EvalContextOutputs), Error> {
         let (eval_context, eval_context_outputs) = EvalContext::new()?;
@@ -52,6 +84,11 @@ impl CommandContext {
             print_timings: false,
             eval_context,
             last_errors: Vec::new(),
+            session_name: DEFAULT_SESSION_NAME.to_owned(),
+            other_sessions: HashMap::new(),
+            watched_files: Vec::new(),
+            loading_files: Vec::new(),
+            execution_count: 0,
         }
     }
 
@@ -93,11 +130,26 @@ impl CommandContext {
         self.eval_context.defined_item_names()
     }
 
+    /// Returns details of all variables, including a preview of their values.
+    pub fn variable_details(&mut self) -> Result<Vec<VariableDetails>, Error> {
+        self.eval_context.variable_details()
+    }
+
+    /// Sets the number of the next cell to be executed. Cells are numbered consecutively from 1 by
+    /// default, but frontends that show their own numbers for cells should call this before each
+    /// cell, so that we refer to cells by the same numbers, e.g. when reporting that a cell lost a
+    /// variable.
+    pub fn set_execution_count(&mut self, execution_count: usize) {
+        self.execution_count = execution_count.saturating_sub(1);
+    }
+
     pub fn execute_with_callbacks(
         &mut self,
         to_run: &str,
         callbacks: &mut EvalCallbacks,
     ) -> Result<EvalOutputs, Error> {
+        self.execution_count += 1;
+        self.eval_context.set_cell_number(self.execution_count);
         let mut state = self.eval_context.state();
         state.clear_non_debug_relevant_fields();
         let mut guard = CrashGuard::new(|| {
@@ -113,9 +165,131 @@ Panic detected. Here's some useful information if you're filing a bug report.
 </STATE>"#
             );
         });
+        let mut outputs = self.rerun_changed_watched_files(callbacks);
         let result = self.execute_with_callbacks_internal(to_run, callbacks);
         guard.disarm();
-        result
+        outputs.merge(result?);
+        Ok(outputs)
+    }
+
+    /// Returns `code` with macros expanded, without running it.
+    fn expand(&mut self, code: &str) -> Result<EvalOutputs, Error> {
+        let (user_code, code_info) = CodeBlock::from_original_user_code(code);
+        let (non_command_code, state, errors) = self.prepare_for_analysis(user_code)?;
+        if !errors.is_empty() {
+            return Err(Error::CompilationErrors(errors));
+        }
+        if non_command_code.is_empty() {
+            bail!("Please supply code to expand");
+        }
+        let expanded = self
+            .eval_context
+            .expand(code, non_command_code, state, &code_info)?;
+        if expanded.unexpanded.is_empty() {
+            return text_output(expanded.code);
+        }
+        text_output(format!(
+            "{}\n// Couldn't expand: {}",
+            expanded.code,
+            expanded.unexpanded.join(", ")
+        ))
+    }
+
+    /// Runs the contents of the file at `path` as if it had been typed into a cell.
+    fn load_file(
+        &mut self,
+        path: &Path,
+        callbacks: &mut EvalCallbacks,
+    ) -> Result<EvalOutputs, Error> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|error| Error::from(format!("Couldn't read {}: {error}", path.display())))?;
+        let canonical_path = path.canonicalize().unwrap_or_else(|_| path.to_owned());
+        if self.loading_files.contains(&canonical_path) {
+            bail!("{} is already being loaded", path.display());
+        }
+        self.loading_files.push(canonical_path);
+        let result = self.execute_with_callbacks_internal(&contents, callbacks);
+        self.loading_files.pop();
+        result.map_err(|error| match error {
+            Error::CompilationErrors(errors) => Error::CompilationErrors(
+                errors
+                    .into_iter()
+                    // Errors from files that this file loaded already say where they are.
+                    .map(|error| match error.source_file() {
+                        Some(_) => error,
+                        None => error.with_source_file(path),
+                    })
+                    .collect(),
+            ),
+            other => other,
+        })
+    }
+
+    /// Starts watching the file at `path`, then runs it.
+    fn watch_file(
+        &mut self,
+        path: &Path,
+        callbacks: &mut EvalCallbacks,
+    ) -> Result<EvalOutputs, Error> {
+        let modified = file_modified(path);
+        match self
+            .watched_files
+            .iter_mut()
+            .find(|watched| watched.path == path)
+        {
+            Some(watched) => watched.modified = modified,
+            None => self.watched_files.push(WatchedFile {
+                path: path.to_owned(),
+                modified,
+            }),
+        }
+        let mut outputs = self.load_file(path, callbacks)?;
+        outputs.merge(text_output(format!("Watching {}", path.display()))?);
+        Ok(outputs)
+    }
+
+    /// Reruns each watched file that has changed since it was last run. Returns the outputs of
+    /// the files, with a note of which were rerun.
+    fn rerun_changed_watched_files(&mut self, callbacks: &mut EvalCallbacks) -> EvalOutputs {
+        let mut outputs = EvalOutputs::new();
+        let mut message = String::new();
+        // Files can change what's watched, so we don't hold a reference while running them.
+        let mut index = 0;
+        while let Some(watched) = self.watched_files.get_mut(index) {
+            index += 1;
+            let modified = file_modified(&watched.path);
+            if modified == watched.modified {
+                continue;
+            }
+            // We record the modification time even if running the file fails, so that we don't
+            // try again until it's changed again.
+            watched.modified = modified;
+            let path = watched.path.clone();
+            match self.load_file(&path, callbacks) {
+                Ok(mut file_outputs) => {
+                    match file_outputs.content_by_mime_type.remove("text/plain") {
+                        Some(text) => message.push_str(&format!(
+                            "Reran {}: {}\n",
+                            path.display(),
+                            text.trim_end()
+                        )),
+                        None => message.push_str(&format!("Reran {}\n", path.display())),
+                    }
+                    outputs.merge(file_outputs);
+                }
+                Err(error) => {
+                    message.push_str(&format!("Rerunning {} failed: {error}\n", path.display()))
+                }
+            }
+        }
+        if !message.is_empty() {
+            outputs
+                .content_by_mime_type
+                .entry("text/plain".to_owned())
+                .or_default()
+                .push_str(&message);
+        }
+        outputs
     }
 
     fn execute_with_callbacks_internal(
@@ -124,9 +298,41 @@ Panic detected. Here's some useful information if you're filing a bug report.
         callbacks: &mut EvalCallbacks,
     ) -> Result<EvalOutputs, Error> {
         use std::time::Instant;
+        if let Some((_, code)) = split_command_prefix(to_run, ":expand") {
+            return self.expand(code);
+        }
         let mut eval_outputs = EvalOutputs::new();
         let start = Instant::now();
         let mut state = self.eval_context.state();
+        let num_cells = self.eval_context.num_cells();
+        let spawn_code;
+        let mut spawned_job = None;
+        let to_run = match split_command_prefix(to_run, ":spawn") {
+            Some((leading, job_code)) => {
+                let body_start = spawned_body_start(job_code)?;
+                let (hoisted, body) = job_code.split_at(body_start);
+                let id = state.add_job(body);
+                spawned_job = Some(id);
+                // The job gets clones of the variables that it uses, so that they stay in the
+                // session rather than being moved into the thread for good.
+                let variables = self.variables_and_types().map(|(name, _)| name).collect();
+                let clones: String = spawned_captures(body, &variables)
+                    .iter()
+                    .map(|name| {
+                        format!("#[allow(unused_mut)] let mut {name} = Clone::clone(&{name}); ")
+                    })
+                    .collect();
+                // Commands and items stay outside of the thread, so that they're available to
+                // later cells. We keep the user's code on the same lines so that line numbers in
+                // errors match.
+                spawn_code = format!(
+                    "{leading}{hoisted}let {} = {{ {clones}std::thread::spawn(move || {{{body}\n}}) }};",
+                    job_variable_name(id)
+                );
+                spawn_code.as_str()
+            }
+            None => to_run,
+        };
         let mut non_command_code = CodeBlock::new();
         let (user_code, code_info) = CodeBlock::from_original_user_code(to_run);
         for segment in user_code.segments {
@@ -137,9 +343,13 @@ Panic detected. Here's some useful information if you're filing a bug report.
                         &segment,
                         &mut state,
                         &command.args,
+                        callbacks,
                     )?);
                 }
                 CodeKind::ShellCommand(shell_command) => {
+                    if state.sandbox() {
+                        bail!("Shell commands aren't available when sandboxed");
+                    }
                     eval_outputs.merge(self.execute_shell_command(shell_command)?);
                 }
                 _ => {
@@ -154,6 +364,12 @@ Panic detected. Here's some useful information if you're filing a bug report.
         match result {
             Ok(m) => {
                 eval_outputs.merge(m);
+                if let Some(id) = spawned_job {
+                    eval_outputs.merge(text_output(format!("Started job {id}"))?);
+                }
+                if self.eval_context.num_cells() > num_cells {
+                    self.update_stale_cells(&mut eval_outputs);
+                }
                 if self.print_timings {
                     eval_outputs.timing = Some(duration);
                 }
@@ -167,6 +383,49 @@ Panic detected. Here's some useful information if you're filing a bug report.
         }
     }
 
+    /// Lists or reruns cells that are stale as a result of the cell that was just run redefining
+    /// items that they used, depending on the reactive mode.
+    fn update_stale_cells(&mut self, eval_outputs: &mut EvalOutputs) {
+        let mode = self.eval_context.reactive_mode();
+        if mode == ReactiveMode::Off {
+            return;
+        }
+        let stale = self.eval_context.stale_cells();
+        if stale.is_empty() {
+            return;
+        }
+        let mut message = String::new();
+        if mode == ReactiveMode::List {
+            let cell_numbers: Vec<String> =
+                stale.iter().map(|index| (index + 1).to_string()).collect();
+            message.push_str(&format!("Stale cells: {}\n", cell_numbers.join(", ")));
+        } else {
+            for (index, result) in self.eval_context.rerun_cells(&stale) {
+                match result {
+                    Ok(outputs) => match outputs.get("text/plain") {
+                        Some(text) => message.push_str(&format!(
+                            "Reran cell {}: {}\n",
+                            index + 1,
+                            text.trim_end()
+                        )),
+                        None => message.push_str(&format!("Reran cell {}\n", index + 1)),
+                    },
+                    Err(error) => {
+                        message.push_str(&format!("Rerunning cell {} failed: {error}\n", index + 1))
+                    }
+                }
+            }
+        }
+        let text = eval_outputs
+            .content_by_mime_type
+            .entry("text/plain".to_owned())
+            .or_default();
+        if !text.is_empty() && !text.ends_with('\n') {
+            text.push('\n');
+        }
+        text.push_str(&message);
+    }
+
     fn execute_shell_command(
         &mut self,
         shell_command: &ShellCommand,
@@ -244,10 +503,10 @@ Panic detected. Here's some useful information if you're filing a bug report.
         let mut errors = Vec::new();
         for segment in user_code.segments {
             if let CodeKind::Command(command) = &segment.kind {
-                if let Err(error) =
-                    self.process_command(command, &segment, &mut state, &command.args, true)
+                if let Err(command_errors) =
+                    self.process_command(command, &segment, &mut state, &command.args, None)
                 {
-                    errors.push(*error);
+                    errors.extend(command_errors);
                 }
             } else {
                 non_command_code = non_command_code.with_segment(segment);
@@ -309,25 +568,48 @@ Panic detected. Here's some useful information if you're filing a bug report.
         segment: &Segment,
         state: &mut ContextState,
         args: &Option<String>,
+        callbacks: &mut EvalCallbacks,
     ) -> Result<EvalOutputs, Error> {
-        self.process_command(command, segment, state, args, false)
-            .map_err(|err| Error::CompilationErrors(vec![*err]))
+        self.process_command(command, segment, state, args, Some(callbacks))
+            .map_err(Error::CompilationErrors)
     }
 
+    /// Runs a command. If `callbacks` is None, then we're preparing for analysis. Errors are
+    /// reported as spanning the command, except for compilation errors in files that the command
+    /// loaded, which are returned as they are.
     fn process_command(
         &mut self,
         command_call: &CommandCall,
         segment: &Segment,
         state: &mut ContextState,
         args: &Option<String>,
-        analysis_mode: bool,
-    ) -> Result<EvalOutputs, Box<CompilationError>> {
+        callbacks: Option<&mut EvalCallbacks>,
+    ) -> Result<EvalOutputs, Vec<CompilationError>> {
         if let Some(command) = Self::commands_by_name().get(command_call.command.as_str()) {
-            let result = match &command.analysis_callback {
-                Some(analysis_callback) if analysis_mode => (analysis_callback)(self, state, args),
-                _ => (command.callback)(self, state, args),
+            let result = match (&command.analysis_callback, callbacks) {
+                (Some(analysis_callback), None) => (analysis_callback)(self, state, args),
+                _ if command.escapes_sandbox && state.sandbox() => Err(Error::Message(format!(
+                    "{} isn't available when sandboxed",
+                    command.name
+                ))),
+                (_, callbacks) => match &command.callback {
+                    CommandCallback::Plain(callback) => callback(self, state, args),
+                    CommandCallback::RunsCode(callback) => match callbacks {
+                        Some(callbacks) => callback(self, state, args, callbacks),
+                        None => callback(self, state, args, &mut EvalCallbacks::default()),
+                    },
+                },
             };
             result.map_err(|error| {
+                let error = match error {
+                    // Errors in files that the command loaded say where they are.
+                    Error::CompilationErrors(errors)
+                        if errors.iter().all(|error| error.source_file().is_some()) =>
+                    {
+                        return errors;
+                    }
+                    error => error,
+                };
                 // Span from the start of the arguments to the end of the arguments, or if no
                 // arguments are found, span the command. We look for the first non-space character
                 // after a space is found.
@@ -347,17 +629,17 @@ Panic detected. Here's some useful information if you're filing a bug report.
                     .unwrap_or(0);
                 let start_column = code_block::count_columns(&segment.code[..start_byte]) + 1;
                 let end_column = code_block::count_columns(&segment.code);
-                Box::new(CompilationError::from_segment_span(
+                vec![CompilationError::from_segment_span(
                     segment,
                     SpannedMessage::from_segment_span(
                         segment,
                         Span::from_command(command_call, start_column, end_column),
                     ),
                     error.to_string(),
-                ))
+                )]
             })
         } else {
-            Err(Box::new(CompilationError::from_segment_span(
+            Err(vec![CompilationError::from_segment_span(
                 segment,
                 SpannedMessage::from_segment_span(
                     segment,
@@ -368,7 +650,7 @@ Panic detected. Here's some useful information if you're filing a bug report.
                     ),
                 ),
                 format!("Unrecognised command {}", command_call.command),
-            )))
+            )])
         }
     }
 
@@ -404,19 +686,75 @@ Panic detected. Here's some useful information if you're filing a bug report.
                 },
             )
             .disable_in_analysis(),
+            AvailableCommand::runs_code(
+                ":load",
+                "Run a file as if its contents had been typed into a cell",
+                |ctx, state, args, callbacks| {
+                    let Some(path) = args else {
+                        bail!("Please supply a path");
+                    };
+                    let result = ctx.load_file(Path::new(path.trim()), callbacks);
+                    *state = ctx.eval_context.state();
+                    result
+                },
+            )
+            .disable_in_analysis()
+            .disable_when_sandboxed(),
+            AvailableCommand::runs_code(
+                ":watch",
+                "Run a file, then run it again whenever it changes (path/off)",
+                |ctx, state, args, callbacks| match args.as_deref().map(str::trim) {
+                    None => {
+                        if ctx.watched_files.is_empty() {
+                            text_output("No files are being watched")
+                        } else {
+                            let paths: Vec<String> = ctx
+                                .watched_files
+                                .iter()
+                                .map(|watched| watched.path.display().to_string())
+                                .collect();
+                            text_output(format!("Watching: {}", paths.join(", ")))
+                        }
+                    }
+                    Some("off") => {
+                        ctx.watched_files.clear();
+                        text_output("Stopped watching files")
+                    }
+                    Some(path) => {
+                        let result = ctx.watch_file(Path::new(path), callbacks);
+                        *state = ctx.eval_context.state();
+                        result
+                    }
+                },
+            )
+            .disable_in_analysis()
+            .disable_when_sandboxed(),
             AvailableCommand::new(":version", "Print Evcxr version", |_ctx, _state, _args| {
                 text_output(env!("CARGO_PKG_VERSION"))
             }),
             AvailableCommand::new(
                 ":vars",
-                "List bound variables and their types",
-                |ctx, _state, _args| {
+                "List bound variables and their types. Pass --values to show previews",
+                |ctx, _state, args| {
+                    if args.as_deref().map(str::trim) == Some("--values") {
+                        let details = ctx.variable_details()?;
+                        return Ok(EvalOutputs::text_html(
+                            variable_details_as_text(&details),
+                            variable_details_as_html(&details),
+                        ));
+                    }
                     Ok(EvalOutputs::text_html(
                         ctx.vars_as_text(),
                         ctx.vars_as_html(),
                     ))
                 },
             ),
+            AvailableCommand::new(
+                ":session",
+                "Create, switch between or list sessions (new <name>, switch <name>, list)",
+                |ctx, state, args| ctx.session(state, args),
+            )
+            .disable_in_analysis(),
             AvailableCommand::new(
                 ":type",
                 "Show variable type",
@@ -443,6 +781,155 @@ Panic detected. Here's some useful information if you're filing a bug report.
                     ))
                 },
             ),
+            AvailableCommand::new(
+                ":spawn",
+                "Run the rest of the cell on a background thread, with clones of the variables that it uses. Must come first in the cell",
+                |_ctx, _state, _args| bail!(":spawn must come first in the cell"),
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":expand",
+                "Show the rest of the cell with macros expanded, without running it. Must come first in the cell",
+                |_ctx, _state, _args| bail!(":expand must come first in the cell"),
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":jobs",
+                "List background jobs started by :spawn",
+                |ctx, state, _args| ctx.jobs_as_text(state),
+            ),
+            AvailableCommand::new(
+                ":wait",
+                "Wait for a background job to finish and show its result",
+                |ctx, state, args| {
+                    let Some(id) = args.as_deref().map(str::trim) else {
+                        bail!("Please supply a job id");
+                    };
+                    let id = id.parse().map_err(|_| anyhow!("Invalid job id `{}`", id))?;
+                    let result = ctx.wait_for_job(id);
+                    *state = ctx.eval_context.state();
+                    result
+                },
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":timeout",
+                "Set how long a cell may run before it's killed, e.g. 30s (off to disable)",
+                |_ctx, state, args| {
+                    if let Some(arg) = args {
+                        state.set_timeout(crate::eval_context::parse_duration_limit(arg)?);
+                    }
+                    text_output(match state.timeout() {
+                        Some(timeout) => format!("Timeout: {timeout:?}"),
+                        None => "Timeout: off".to_owned(),
+                    })
+                },
+            ),
+            AvailableCommand::new(
+                ":limits",
+                "Limit memory and CPU time used by the subprocess, e.g. memory=4G cpu=60s",
+                |ctx, state, args| {
+                    let Some(args) = args else {
+                        return text_output(format!("Limits: {}", state.limits()));
+                    };
+                    let limits = state.limits().updated(args)?;
+                    let restored = ctx.eval_context.set_limits(limits)?;
+                    *state = ctx.eval_context.state();
+                    let mut out = format!("Limits: {limits}. Subprocess restarted");
+                    if !restored.is_empty() {
+                        out.push_str(&format!(". Restored from checkpoint: {}", restored.join(", ")));
+                    }
+                    text_output(out)
+                },
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":sandbox",
+                "Restrict the subprocess to writing in its working directory, without network access (on)",
+                |ctx, state, args| {
+                    match args.as_deref().map(str::trim) {
+                        None => {}
+                        Some("on" | "1") if !state.sandbox() => {
+                            let restored = ctx.eval_context.enable_sandbox()?;
+                            *state = ctx.eval_context.state();
+                            // Rerunning watched files would mean reading them from the host.
+                            ctx.watched_files.clear();
+                            if !restored.is_empty() {
+                                return text_output(format!(
+                                    "Sandbox: on. Restored from checkpoint: {}",
+                                    restored.join(", ")
+                                ));
+                            }
+                        }
+                        Some("off" | "0") if state.sandbox() => {
+                            bail!("The sandbox can't be turned off once enabled")
+                        }
+                        Some("on" | "1" | "off" | "0") => {}
+                        Some(_) => bail!("Please supply on"),
+                    }
+                    text_output(format!(
+                        "Sandbox: {}",
+                        if state.sandbox() { "on" } else { "off" }
+                    ))
+                },
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":checkpoint_vars",
+                "Save variables that support serde so that they survive restarts (on/off)",
+                |_ctx, state, args| {
+                    if let Some(arg) = args {
+                        match arg.as_str() {
+                            "on" | "1" => state.set_checkpoint_vars(true)?,
+                            "off" | "0" => state.set_checkpoint_vars(false)?,
+                            _ => bail!("Please supply on or off"),
+                        }
+                    }
+                    text_output(format!(
+                        "Checkpoint vars: {}",
+                        if state.checkpoint_vars() { "on" } else { "off" }
+                    ))
+                },
+            ),
+            AvailableCommand::new(
+                ":migrate_vars",
+                "Convert variables whose types are redefined via serde or a From impl (on/off)",
+                |_ctx, state, args| {
+                    if let Some(arg) = args {
+                        match arg.as_str() {
+                            "on" | "1" => state.set_migrate_vars(true)?,
+                            "off" | "0" => state.set_migrate_vars(false)?,
+                            _ => bail!("Please supply on or off"),
+                        }
+                    }
+                    text_output(format!(
+                        "Migrate vars: {}",
+                        if state.migrate_vars() { "on" } else { "off" }
+                    ))
+                },
+            ),
+            AvailableCommand::new(
+                ":reactive",
+                "When an item is redefined, list (list) or rerun (on) cells that used it (on/list/off)",
+                |_ctx, state, args| {
+                    if let Some(arg) = args {
+                        match arg.as_str() {
+                            "on" | "1" => state.set_reactive_mode(ReactiveMode::Rerun),
+                            "list" => state.set_reactive_mode(ReactiveMode::List),
+                            "off" | "0" => state.set_reactive_mode(ReactiveMode::Off),
+                            _ => bail!("Please supply on, list or off"),
+                        }
+                    }
+                    text_output(format!(
+                        "Reactive: {}",
+                        match state.reactive_mode() {
+                            ReactiveMode::Off => "off",
+                            ReactiveMode::List => "list",
+                            ReactiveMode::Rerun => "on",
+                        }
+                    ))
+                },
+            ),
             AvailableCommand::new(
                 ":clear",
                 "Clear all state, keeping compilation cache",
@@ -457,12 +944,145 @@ Panic detected. Here's some useful information if you're filing a bug report.
                 *state = ctx.eval_context.cleared_state();
                 Ok(EvalOutputs::default())
             }),
+            AvailableCommand::new(
+                ":save_session",
+                "Save items, dependencies, config and history to a file",
+                |ctx, state, args| {
+                    let Some(path) = args else {
+                        bail!("Please supply a path");
+                    };
+                    state.save_session(ctx.eval_context.cells(), Path::new(path))?;
+                    text_output(format!("Session saved to {path}"))
+                },
+            )
+            .disable_in_analysis()
+            .disable_when_sandboxed(),
+            AvailableCommand::new(
+                ":export_crate",
+                "Write the session history as a runnable crate to a directory",
+                |ctx, state, args| {
+                    let Some(dir) = args else {
+                        bail!("Please supply a directory");
+                    };
+                    state.export_crate(ctx.eval_context.cells(), Path::new(dir))?;
+                    text_output(format!("Crate written to {dir}"))
+                },
+            )
+            .disable_in_analysis()
+            .disable_when_sandboxed(),
+            AvailableCommand::new(
+                ":export_test",
+                "Write the session history as a test in tests/{name}.rs of a crate",
+                |ctx, state, args| {
+                    let Some(args) = args else {
+                        bail!("Please supply a test name");
+                    };
+                    let (name, crate_dir) = match args.split_once(' ') {
+                        Some((name, crate_dir)) => (name, PathBuf::from(crate_dir.trim())),
+                        None => (args.as_str(), enclosing_crate_dir()?),
+                    };
+                    state.export_test(ctx.eval_context.cells(), &crate_dir, name)?;
+                    text_output(format!(
+                        "Test written to {}",
+                        crate_dir.join("tests").join(format!("{name}.rs")).display()
+                    ))
+                },
+            )
+            .disable_in_analysis()
+            .disable_when_sandboxed(),
+            AvailableCommand::new(
+                ":load_session",
+                "Replace all state with that from a saved session",
+                |ctx, state, args| {
+                    let Some(path) = args else {
+                        bail!("Please supply a path");
+                    };
+                    let failures = ctx.eval_context.load_session(Path::new(path))?;
+                    *state = ctx.eval_context.state();
+                    let mut out = format!("Session loaded from {path}");
+                    for (index, error) in failures {
+                        out.push_str(&format!("\nCell {} couldn't be replayed: {error}", index + 1));
+                    }
+                    text_output(out)
+                },
+            )
+            .disable_in_analysis()
+            .disable_when_sandboxed(),
+            AvailableCommand::new(
+                ":undo",
+                "Undo items, imports and variables defined by the last successful cell",
+                |ctx, state, _args| {
+                    let lost = ctx.eval_context.undo()?;
+                    *state = ctx.eval_context.state();
+                    if lost.is_empty() {
+                        text_output("Undid last cell")
+                    } else {
+                        text_output(format!(
+                            "Undid last cell. Variables that it moved or redefined can't be restored: {}",
+                            lost.join(", ")
+                        ))
+                    }
+                },
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":forget",
+                "Remove an item or variable, along with items and variables that depend on it",
+                |ctx, state, args| {
+                    let Some(name) = args else {
+                        bail!("Please supply the name of an item or variable");
+                    };
+                    let name = name.trim();
+                    let forgotten = ctx.eval_context.forget(name)?;
+                    *state = ctx.eval_context.state();
+                    let variables: Vec<&str> = forgotten
+                        .variables
+                        .iter()
+                        .map(String::as_str)
+                        .filter(|variable| *variable != name)
+                        .collect();
+                    let items = forgotten.dependent_items.join(", ");
+                    text_output(match (items.is_empty(), variables.is_empty()) {
+                        (true, true) => format!("Forgot {name}"),
+                        (true, false) => format!(
+                            "Forgot {name} and variables of that type: {}",
+                            variables.join(", ")
+                        ),
+                        (false, true) => format!("Forgot {name} and items that use it: {items}"),
+                        (false, false) => format!(
+                            "Forgot {name}, items that use it: {items} and variables of those \
+                            types: {}",
+                            variables.join(", ")
+                        ),
+                    })
+                },
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":unuse",
+                "Remove an import. e.g. :unuse std::collections::HashMap",
+                |_ctx, state, args| {
+                    let Some(path) = args else {
+                        bail!("Please supply the path of an import");
+                    };
+                    state.unuse(path)?;
+                    text_output(format!("Removed import {}", path.trim()))
+                },
+            ),
             AvailableCommand::new(
                 ":restart",
                 "Restart child process",
-                |ctx, _state, _args| {
-                    ctx.eval_context.restart_child_process()?;
-                    text_output("Child process restarted")
+                |ctx, state, _args| {
+                    let restored = ctx.eval_context.restart_child_process(LossReason::Restarted)?;
+                    *state = ctx.eval_context.state();
+                    if restored.is_empty() {
+                        text_output("Child process restarted")
+                    } else {
+                        text_output(format!(
+                            "Child process restarted. Restored from checkpoint: {}",
+                            restored.join(", ")
+                        ))
+                    }
                 },
             ),
             AvailableCommand::new(
@@ -537,7 +1157,8 @@ Panic detected. Here's some useful information if you're filing a bug report.
                     }
                     text_output(format!("Toolchain: {}", state.toolchain()))
                 },
-            ),
+            )
+            .disable_when_sandboxed(),
             AvailableCommand::new(
                 ":offline",
                 "Set offline mode when invoking cargo (0/1)",
@@ -589,7 +1210,8 @@ Panic detected. Here's some useful information if you're filing a bug report.
                         text_output("sccache: false")
                     }
                 },
-            ),
+            )
+            .disable_when_sandboxed(),
             AvailableCommand::new(
                 ":cache",
                 "Set cache size in MiB, or 0 to disable.",
@@ -620,7 +1242,8 @@ Panic detected. Here's some useful information if you're filing a bug report.
                     }
                     text_output(format!("linker: {}", state.linker()))
                 },
-            ),
+            )
+            .disable_when_sandboxed(),
             AvailableCommand::new(
                 ":codegen_backend",
                 "Set/print the codegen backend. Requires nightly",
@@ -630,7 +1253,8 @@ Panic detected. Here's some useful information if you're filing a bug report.
                     }
                     text_output(format!("codegen backend: {}", state.codegen_backend()))
                 },
-            ),
+            )
+            .disable_when_sandboxed(),
             AvailableCommand::new(
                 ":explain",
                 "Print explanation of last error",
@@ -661,7 +1285,8 @@ Panic detected. Here's some useful information if you're filing a bug report.
                         }
                     bail!("Please supply key=value");
                 },
-            ),
+            )
+            .disable_when_sandboxed(),
             AvailableCommand::new(
                 ":env",
                 "Set an environment variable (key=value)",
@@ -685,7 +1310,8 @@ Panic detected. Here's some useful information if you're filing a bug report.
                         }
                     bail!("Please supply key=value");
                 },
-            ),
+            )
+            .disable_when_sandboxed(),
             AvailableCommand::new(
                 ":last_error_json",
                 "Print the last compilation error as JSON (for debugging)",
@@ -752,6 +1378,98 @@ Panic detected. Here's some useful information if you're filing a bug report.
         out
     }
 
+    fn session(
+        &mut self,
+        state: &mut ContextState,
+        args: &Option<String>,
+    ) -> Result<EvalOutputs, Error> {
+        let args = args.as_deref().unwrap_or("").trim();
+        let (subcommand, name) = args.split_once(' ').unwrap_or((args, ""));
+        let name = name.trim();
+        match subcommand {
+            "" => text_output(format!("Session: {}", self.session_name)),
+            "list" => {
+                let mut names: Vec<&str> = self.other_sessions.keys().map(String::as_str).collect();
+                names.push(&self.session_name);
+                names.sort();
+                let lines: Vec<String> = names
+                    .into_iter()
+                    .map(|name| {
+                        let marker = if name == self.session_name { "*" } else { " " };
+                        format!("{marker} {name}")
+                    })
+                    .collect();
+                text_output(lines.join("\n"))
+            }
+            "new" => {
+                if name.is_empty()
+                    || !name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+                {
+                    bail!("Session names may only contain letters, digits, '_' and '-'");
+                }
+                if name == self.session_name || self.other_sessions.contains_key(name) {
+                    bail!("Session `{}` already exists", name);
+                }
+                let session = self.eval_context.new_session(name)?;
+                self.other_sessions.insert(name.to_owned(), session);
+                self.switch_session(name)?;
+                *state = self.eval_context.state();
+                text_output(format!("Created session {name}"))
+            }
+            "switch" => {
+                if name != self.session_name {
+                    self.switch_session(name)?;
+                    *state = self.eval_context.state();
+                }
+                text_output(format!("Switched to session {name}"))
+            }
+            _ => bail!("Usage: :session [new <name> | switch <name> | list]"),
+        }
+    }
+
+    fn jobs_as_text(&mut self, state: &ContextState) -> Result<EvalOutputs, Error> {
+        let jobs = state.jobs();
+        if jobs.is_empty() {
+            return text_output("No jobs");
+        }
+        let details = self.variable_details()?;
+        let mut lines = Vec::new();
+        for (id, code) in jobs {
+            let status = details
+                .iter()
+                .find(|detail| detail.name == job_variable_name(id))
+                .and_then(|detail| detail.preview.as_deref())
+                .unwrap_or("unknown");
+            let mut line = format!("Job {id}: {status}");
+            if let Some(code) = code.and_then(|code| code.lines().next()) {
+                line.push_str(&format!(" - {code}"));
+            }
+            lines.push(line);
+        }
+        text_output(lines.join("\n"))
+    }
+
+    /// Waits for the background job with the supplied id to complete, then returns its result.
+    fn wait_for_job(&mut self, id: usize) -> Result<EvalOutputs, Error> {
+        let mut state = self.eval_context.state();
+        let code = state.take_job(id)?;
+        self.eval_context.eval_with_state(&code, state)
+    }
+
+    fn switch_session(&mut self, name: &str) -> Result<(), Error> {
+        let Some(mut session) = self.other_sessions.remove(name) else {
+            bail!("No session named `{}`", name);
+        };
+        self.eval_context.swap_process_handles(&mut session);
+        session.set_cell_number(self.execution_count);
+        let previous = std::mem::replace(&mut self.eval_context, session);
+        let previous_name = std::mem::replace(&mut self.session_name, name.to_owned());
+        self.other_sessions.insert(previous_name, previous);
+        Ok(())
+    }
+
     fn var_type(&self, args: &Option<String>) -> Result<EvalOutputs, Error> {
         let args = if let Some(x) = args {
             x.trim()
@@ -849,12 +1567,32 @@ type CallbackFn = dyn Fn(&mut CommandContext, &mut ContextState, &Option<String>
     + Sync
     + Send;
 
+/// Like `CallbackFn`, but also given the callbacks of the cell, for commands that run code.
+type RunsCodeCallbackFn = dyn Fn(
+        &mut CommandContext,
+        &mut ContextState,
+        &Option<String>,
+        &mut EvalCallbacks,
+    ) -> Result<EvalOutputs, Error>
+    + 'static
+    + Sync
+    + Send;
+
+enum CommandCallback {
+    Plain(Box<CallbackFn>),
+    RunsCode(Box<RunsCodeCallbackFn>),
+}
+
 struct AvailableCommand {
     name: &'static str,
     short_description: &'static str,
-    callback: Box<CallbackFn>,
+    callback: CommandCallback,
     /// If `Some`, this callback will be run when preparing for analysis instead of `callback`.
     analysis_callback: Option<Box<CallbackFn>>,
+    /// Whether the command would let sandboxed code escape the sandbox, since commands run in our
+    /// process rather than the subprocess. e.g. it reads or writes files on the host, or results in
+    /// the host running programs that sandboxed code could have written.
+    escapes_sandbox: bool,
 }
 
 impl AvailableCommand {
@@ -873,8 +1611,32 @@ impl AvailableCommand {
         AvailableCommand {
             name,
             short_description,
-            callback: Box::new(callback),
+            callback: CommandCallback::Plain(Box::new(callback)),
+            analysis_callback: None,
+            escapes_sandbox: false,
+        }
+    }
+
+    /// Returns a command that runs code, so needs the callbacks of the cell.
+    fn runs_code(
+        name: &'static str,
+        short_description: &'static str,
+        callback: impl Fn(
+            &mut CommandContext,
+            &mut ContextState,
+            &Option<String>,
+            &mut EvalCallbacks,
+        ) -> Result<EvalOutputs, Error>
+        + 'static
+        + Sync
+        + Send,
+    ) -> AvailableCommand {
+        AvailableCommand {
+            name,
+            short_description,
+            callback: CommandCallback::RunsCode(Box::new(callback)),
             analysis_callback: None,
+            escapes_sandbox: false,
         }
     }
 
@@ -893,6 +1655,11 @@ impl AvailableCommand {
         self
     }
 
+    fn disable_when_sandboxed(mut self) -> Self {
+        self.escapes_sandbox = true;
+        self
+    }
+
     fn disable_in_analysis(self) -> Self {
         self.with_analysis_callback(|_ctx, _state, _args| Ok(EvalOutputs::default()))
     }
@@ -909,6 +1676,160 @@ fn html_escape(input: &str, out: &mut String) {
     }
 }
 
+fn variable_details_as_text(details: &[VariableDetails]) -> String {
+    let mut out = String::new();
+    for detail in details {
+        out.push_str(&format!("{}: {}", detail.name, detail.type_name));
+        if let Some(preview) = &detail.preview {
+            out.push_str(&format!(" = {preview}"));
+        }
+        let mut notes = Vec::new();
+        if let Some(size) = detail.size {
+            notes.push(format!("{size} bytes"));
+        }
+        if let Some(cell) = detail.defining_cell {
+            notes.push(format!("cell {cell}"));
+        }
+        if !notes.is_empty() {
+            out.push_str(&format!(" ({})", notes.join(", ")));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+fn variable_details_as_html(details: &[VariableDetails]) -> String {
+    let mut out = String::new();
+    out.push_str(
+        "<table><tr><th>Variable</th><th>Type</th><th>Value</th><th>Size</th><th>Cell</th></tr>",
+    );
+    for detail in details {
+        out.push_str("<tr><td>");
+        html_escape(&detail.name, &mut out);
+        out.push_str("</td><td>");
+        html_escape(&detail.type_name, &mut out);
+        out.push_str("</td><td>");
+        html_escape(detail.preview.as_deref().unwrap_or_default(), &mut out);
+        out.push_str("</td><td>");
+        if let Some(size) = detail.size {
+            out.push_str(&size.to_string());
+        }
+        out.push_str("</td><td>");
+        if let Some(cell) = detail.defining_cell {
+            out.push_str(&cell.to_string());
+        }
+        out.push_str("</td></tr>");
+    }
+    out.push_str("</table>");
+    out
+}
+
+/// If `code` starts with `command`, e.g. `:spawn`, returns any whitespace prior to it and the code
+/// that follows it.
+fn split_command_prefix<'a>(code: &'a str, command: &str) -> Option<(&'a str, &'a str)> {
+    let trimmed = code.trim_start();
+    let rest = trimmed.strip_prefix(command)?;
+    if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    Some((&code[..code.len() - trimmed.len()], rest))
+}
+
+/// Returns the byte offset within `job_code`, the code following `:spawn`, at which the code to run
+/// in the background starts. Any commands, items and use statements before it are left for the
+/// session. Items that follow it would be scoped to the background thread, so are rejected.
+fn spawned_body_start(job_code: &str) -> Result<usize, Error> {
+    let (code_block, code_info) = CodeBlock::from_original_user_code(job_code);
+    let mut body_start = None;
+    for segment in &code_block.segments {
+        match &segment.kind {
+            CodeKind::ShellCommand(_) => {
+                bail!("Shell commands can't be run in the background");
+            }
+            CodeKind::OriginalUserCode(meta) => {
+                let is_item = ast::Item::cast(code_info.nodes[meta.node_index].clone())
+                    .is_some_and(|item| !matches!(item, ast::Item::MacroCall(_)));
+                match (is_item, body_start) {
+                    (true, Some(_)) => {
+                        bail!(
+                            "Items and use statements must come before the code to run in the background"
+                        );
+                    }
+                    (false, None) => body_start = Some(meta.start_byte),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    match body_start {
+        Some(body_start) => Ok(body_start),
+        None => bail!("Please supply code to run in the background"),
+    }
+}
+
+/// Returns the names of the session's `variables` that `body`, the code for a background job, uses.
+/// This errs on the side of including variables, e.g. ones that the job defines for itself.
+fn spawned_captures(body: &str, variables: &HashSet<&str>) -> Vec<String> {
+    let parsed = SourceFile::parse(
+        &format!("fn f() {{{body}\n}}"),
+        crate::rust_analyzer::EDITION,
+    );
+    let mut captures = BTreeSet::new();
+    for token in parsed
+        .syntax_node()
+        .descendants_with_tokens()
+        .filter_map(|element| element.into_token())
+    {
+        match token.kind() {
+            SyntaxKind::IDENT if variables.contains(token.text()) => {
+                // Fields, methods and paths like `a::b` can't refer to variables.
+                let previous = std::iter::successors(token.prev_token(), |t| t.prev_token())
+                    .find(|t| !t.kind().is_trivia());
+                if !previous
+                    .is_some_and(|t| matches!(t.kind(), SyntaxKind::DOT | SyntaxKind::COLON2))
+                {
+                    captures.insert(token.text().to_owned());
+                }
+            }
+            // Variables used by format strings, e.g. `println!("{x}")`.
+            SyntaxKind::STRING => {
+                for variable in variables {
+                    if token.text().contains(&format!("{{{variable}}}"))
+                        || token.text().contains(&format!("{{{variable}:"))
+                    {
+                        captures.insert((*variable).to_owned());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    captures.into_iter().collect()
+}
+
+/// Returns when the file at `path` was last modified, or None if that can't be determined, e.g.
+/// because the file doesn't exist.
+fn file_modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).ok()?.modified().ok()
+}
+
+/// Returns the closest directory to the current directory, including itself, that contains a
+/// Cargo.toml.
+fn enclosing_crate_dir() -> Result<PathBuf, Error> {
+    let current_dir = std::env::current_dir()?;
+    match current_dir
+        .ancestors()
+        .find(|dir| dir.join("Cargo.toml").exists())
+    {
+        Some(dir) => Ok(dir.to_owned()),
+        None => bail!(
+            "'{}' isn't inside a crate. Please supply the crate directory after the test name",
+            current_dir.display()
+        ),
+    }
+}
+
 fn text_output<T: Into<String>>(text: T) -> Result<EvalOutputs, Error> {
     let mut outputs = EvalOutputs::new();
     let mut content = text.into();
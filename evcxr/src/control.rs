@@ -0,0 +1,520 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE
+// or https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Control messages sent from the runtime subprocess to evcxr, e.g. to say that execution of a cell
+//! has completed or to send content to be displayed. On Unix, these are sent as length-prefixed
+//! frames over a dedicated pipe, which leaves stdout carrying only output from the user's code, so
+//! nothing that the user's code prints is treated as a control message. Elsewhere, each message is
+//! written to stdout as a line, so lines that look like control messages, e.g.
+//! `EVCXR_BEGIN_CONTENT`, are treated as such.
+//!
+//! Commands sent from evcxr to the runtime subprocess are lines of text. On Unix, these are also
+//! sent via a dedicated pipe, which leaves stdin for the user's code to read.
+//!
+//! A frame consists of a header and a payload, each preceded by its length as a little-endian u32.
+//! The header is the same as the line that would be written to stdout, e.g.
+//! `EVCXR_BEGIN_CONTENT text/html`. The payload is only used for content and may be binary, in which
+//! case the header has the mime type followed by `bytes`, e.g. `EVCXR_BEGIN_CONTENT image/png bytes`.
+//! Binary content sent via stdout is base64 encoded and the `bytes` removed, since stdout is read as
+//! lines of text.
+
+use std::collections::VecDeque;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+/// Set in the environment of the runtime subprocess to the file descriptor of the pipe to which it
+/// should write control messages.
+pub(crate) const CONTROL_FD_ENV: &str = "EVCXR_CONTROL_FD";
+
+/// The file descriptor that the control pipe is given in the runtime subprocess.
+#[cfg(unix)]
+pub(crate) const CONTROL_FD: std::os::fd::RawFd = 3;
+
+/// Set in the environment of the runtime subprocess to the file descriptor of the pipe from which
+/// it should read commands. If not set, commands are read from stdin.
+pub(crate) const COMMAND_FD_ENV: &str = "EVCXR_COMMAND_FD";
+
+/// The file descriptor that the command pipe is given in the runtime subprocess.
+#[cfg(unix)]
+pub(crate) const COMMAND_FD: std::os::fd::RawFd = 4;
+
+/// Sent via the command pipe once evcxr has read `EVCXR_EXECUTION_STARTED`. The runtime subprocess
+/// waits for it before running the user's code, so that anything the code prints is read after the
+/// message.
+pub(crate) const ACKNOWLEDGE_COMMAND: &str = "EVCXR_ACKNOWLEDGE";
+
+const BEGIN_CONTENT: &str = "EVCXR_BEGIN_CONTENT ";
+const END_CONTENT: &str = "EVCXR_END_CONTENT";
+
+/// Follows the mime type in the header of content whose payload is binary.
+const BYTES_ATTRIBUTE: &str = "bytes";
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct ControlMessage {
+    pub(crate) header: String,
+    pub(crate) payload: Vec<u8>,
+}
+
+impl ControlMessage {
+    fn new(header: String) -> ControlMessage {
+        ControlMessage {
+            header,
+            payload: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum ChildOutput {
+    Stdout(String),
+    Control(ControlMessage),
+}
+
+/// Used by the runtime subprocess to send control messages.
+pub(crate) enum ControlWriter {
+    #[cfg(unix)]
+    Pipe(std::fs::File),
+    Stdout,
+}
+
+impl ControlWriter {
+    /// Returns a writer for the control pipe if our parent gave us one, otherwise one that writes
+    /// to stdout.
+    pub(crate) fn from_env() -> ControlWriter {
+        #[cfg(unix)]
+        if let Ok(fd) = std::env::var(CONTROL_FD_ENV) {
+            use std::os::fd::FromRawFd;
+            if let Ok(fd) = fd.parse::<std::os::fd::RawFd>() {
+                // Processes that the user's code starts shouldn't inherit the pipe, since that
+                // would stop us noticing when we terminate.
+                unsafe {
+                    libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+                }
+                // Safety: The fd was set up by our parent for our exclusive use.
+                return ControlWriter::Pipe(unsafe { std::fs::File::from_raw_fd(fd) });
+            }
+        }
+        ControlWriter::Stdout
+    }
+
+    pub(crate) fn send(&mut self, header: &[u8], payload: &[u8]) -> io::Result<()> {
+        match self {
+            #[cfg(unix)]
+            ControlWriter::Pipe(pipe) => {
+                let mut frame = Vec::with_capacity(header.len() + payload.len() + 8);
+                for part in [header, payload] {
+                    let len = u32::try_from(part.len())
+                        .map_err(|_| io::Error::other("Control message too large"))?;
+                    frame.extend_from_slice(&len.to_le_bytes());
+                    frame.extend_from_slice(part);
+                }
+                pipe.write_all(&frame)
+            }
+            ControlWriter::Stdout => {
+                let header = String::from_utf8_lossy(header);
+                let stdout = io::stdout();
+                let mut stdout = stdout.lock();
+                if let Some(header) = strip_bytes_attribute(&header) {
+                    use base64::Engine as _;
+                    let encoded = base64::engine::general_purpose::STANDARD.encode(payload);
+                    writeln!(stdout, "{header}\n{encoded}\n{END_CONTENT}")?;
+                    return stdout.flush();
+                }
+                writeln!(stdout, "{header}")?;
+                if header.starts_with(BEGIN_CONTENT) {
+                    stdout.write_all(payload)?;
+                    stdout.write_all(b"\n")?;
+                    stdout.write_all(END_CONTENT.as_bytes())?;
+                    stdout.write_all(b"\n")?;
+                }
+                stdout.flush()
+            }
+        }
+    }
+}
+
+/// If `header` is for content whose payload is binary, returns it with the attribute that says so
+/// removed.
+pub(crate) fn strip_bytes_attribute(header: &str) -> Option<String> {
+    let rest = header.strip_prefix(BEGIN_CONTENT)?;
+    let mut parts = rest.splitn(3, ' ');
+    let mime_type = parts.next()?;
+    if parts.next()? != BYTES_ATTRIBUTE {
+        return None;
+    }
+    Some(match parts.next() {
+        Some(attributes) => format!("{BEGIN_CONTENT}{mime_type} {attributes}"),
+        None => format!("{BEGIN_CONTENT}{mime_type}"),
+    })
+}
+
+fn read_frame(input: &mut impl Read) -> io::Result<ControlMessage> {
+    fn read_part(input: &mut impl Read) -> io::Result<Vec<u8>> {
+        let mut len = [0; 4];
+        input.read_exact(&mut len)?;
+        let mut part = vec![0; u32::from_le_bytes(len) as usize];
+        input.read_exact(&mut part)?;
+        Ok(part)
+    }
+    Ok(ControlMessage {
+        header: String::from_utf8_lossy(&read_part(input)?).into_owned(),
+        payload: read_part(input)?,
+    })
+}
+
+/// Reads stdout and, if present, the control pipe of the runtime subprocess. Control messages are
+/// returned in order relative to the stdout that was written before them.
+pub(crate) struct OutputReader {
+    stdout: std::process::ChildStdout,
+    stdout_open: bool,
+    #[cfg(unix)]
+    control: Option<io::PipeReader>,
+    parser: StdoutParser,
+    pending: VecDeque<ChildOutput>,
+}
+
+impl OutputReader {
+    pub(crate) fn new(
+        stdout: std::process::ChildStdout,
+        control: Option<io::PipeReader>,
+    ) -> OutputReader {
+        OutputReader {
+            stdout,
+            stdout_open: true,
+            parser: StdoutParser {
+                legacy_control: control.is_none(),
+                ..StdoutParser::default()
+            },
+            #[cfg(unix)]
+            control,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Returns the next line of stdout or control message. Returns None if the subprocess has
+    /// terminated.
+    pub(crate) fn recv(&mut self) -> io::Result<Option<ChildOutput>> {
+        loop {
+            if let Some(output) = self.pending.pop_front() {
+                return Ok(Some(output));
+            }
+            #[cfg(unix)]
+            if self.control.is_some() {
+                if !self.recv_from_pipes()? {
+                    return Ok(None);
+                }
+                continue;
+            }
+            if !self.read_stdout()? {
+                self.parser.flush(&mut self.pending);
+                if self.pending.is_empty() {
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    /// Reads whatever remains on stdout. Used once the subprocess has terminated.
+    pub(crate) fn remaining_stdout(&mut self) -> String {
+        while let Ok(true) = self.read_stdout() {}
+        self.parser.flush(&mut self.pending);
+        let mut content = String::new();
+        for output in self.pending.drain(..) {
+            match output {
+                ChildOutput::Stdout(line) => content.push_str(&line),
+                ChildOutput::Control(message) => content.push_str(&message.header),
+            }
+            content.push('\n');
+        }
+        content
+    }
+
+    /// Reads a chunk of stdout, returning false if stdout is closed.
+    fn read_stdout(&mut self) -> io::Result<bool> {
+        if !self.stdout_open {
+            return Ok(false);
+        }
+        let mut buffer = [0; 8192];
+        let bytes_read = match self.stdout.read(&mut buffer) {
+            Ok(n) => n,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => return Ok(true),
+            Err(error) => return Err(error),
+        };
+        if bytes_read == 0 {
+            self.stdout_open = false;
+            return Ok(false);
+        }
+        self.parser.push(&buffer[..bytes_read], &mut self.pending);
+        Ok(true)
+    }
+
+    /// Waits until there's output on either stdout or the control pipe, then reads some of it.
+    /// Returns false if the control pipe is closed.
+    #[cfg(unix)]
+    fn recv_from_pipes(&mut self) -> io::Result<bool> {
+        use std::os::fd::AsRawFd;
+        let Some(control) = self.control.as_mut() else {
+            return Ok(false);
+        };
+        let mut fds = [
+            libc::pollfd {
+                fd: control.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                // Negative fds are ignored by poll.
+                fd: if self.stdout_open {
+                    self.stdout.as_raw_fd()
+                } else {
+                    -1
+                },
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        if poll(&mut fds, -1)? == 0 {
+            return Ok(true);
+        }
+        if fds[1].revents != 0 {
+            self.read_stdout()?;
+            return Ok(true);
+        }
+        // Anything written to stdout before the control message will already be in the pipe, so
+        // we read it first in order to preserve ordering.
+        self.drain_stdout()?;
+        let Some(control) = self.control.as_mut() else {
+            return Ok(false);
+        };
+        match read_frame(control) {
+            Ok(mut message) => {
+                if message.header == crate::runtime::EVCXR_EXECUTION_COMPLETE {
+                    self.parser.flush(&mut self.pending);
+                } else if message.header == crate::user_stdin::STDIN_READ {
+                    message.payload = self.parser.take_partial_line().into_bytes();
+                }
+                self.pending.push_back(ChildOutput::Control(message));
+                Ok(true)
+            }
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => {
+                self.control = None;
+                Ok(false)
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Reads from stdout until there's nothing more immediately available.
+    #[cfg(unix)]
+    fn drain_stdout(&mut self) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+        while self.stdout_open {
+            let mut fds = [libc::pollfd {
+                fd: self.stdout.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            }];
+            if poll(&mut fds, 0)? == 0 || !self.read_stdout()? {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn poll(fds: &mut [libc::pollfd], timeout_ms: libc::c_int) -> io::Result<usize> {
+    loop {
+        // Safety: `fds` is a valid slice of pollfd structs.
+        let result = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout_ms) };
+        if result >= 0 {
+            return Ok(result as usize);
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+}
+
+/// Splits stdout into lines. If `legacy_control` is set, which it is when there's no control pipe,
+/// then lines that start with `EVCXR_` are treated as control messages and blocks of content are
+/// picked out.
+#[derive(Default)]
+struct StdoutParser {
+    legacy_control: bool,
+    partial_line: Vec<u8>,
+    /// The header and content so far of a block of content that we're part way through.
+    content: Option<ControlMessage>,
+}
+
+impl StdoutParser {
+    fn push(&mut self, bytes: &[u8], out: &mut VecDeque<ChildOutput>) {
+        let mut rest = bytes;
+        while let Some(newline) = rest.iter().position(|b| *b == b'\n') {
+            self.partial_line.extend_from_slice(&rest[..newline]);
+            rest = &rest[newline + 1..];
+            if self.partial_line.last() == Some(&b'\r') {
+                self.partial_line.pop();
+            }
+            let line = String::from_utf8_lossy(&self.partial_line).into_owned();
+            self.partial_line.clear();
+            self.line(line, out);
+        }
+        self.partial_line.extend_from_slice(rest);
+    }
+
+    /// Removes and returns any partial line, provided that we're not part way through a block of
+    /// content.
+    fn take_partial_line(&mut self) -> String {
+        if self.content.is_some() {
+            return String::new();
+        }
+        let line = String::from_utf8_lossy(&self.partial_line).into_owned();
+        self.partial_line.clear();
+        line
+    }
+
+    /// Outputs any partial line or incomplete block of content as ordinary stdout.
+    fn flush(&mut self, out: &mut VecDeque<ChildOutput>) {
+        if !self.partial_line.is_empty() {
+            let line = String::from_utf8_lossy(&self.partial_line).into_owned();
+            self.partial_line.clear();
+            self.line(line, out);
+        }
+        if let Some(content) = self.content.take() {
+            out.push_back(ChildOutput::Stdout(content.header));
+            out.extend(
+                String::from_utf8_lossy(&content.payload)
+                    .lines()
+                    .map(|l| ChildOutput::Stdout(l.to_owned())),
+            );
+        }
+    }
+
+    fn line(&mut self, line: String, out: &mut VecDeque<ChildOutput>) {
+        if !self.legacy_control {
+            out.push_back(ChildOutput::Stdout(line));
+            return;
+        }
+        if let Some(content) = self.content.as_mut() {
+            if line == END_CONTENT {
+                out.push_back(ChildOutput::Control(self.content.take().unwrap()));
+                return;
+            }
+            if !line.starts_with("EVCXR_") {
+                if !content.payload.is_empty() {
+                    content.payload.push(b'\n');
+                }
+                content.payload.extend_from_slice(line.as_bytes());
+                return;
+            }
+            // A control message, most likely a panic notification, interrupted the content.
+            out.push_back(ChildOutput::Control(self.content.take().unwrap()));
+        }
+        if line.starts_with(BEGIN_CONTENT) {
+            self.content = Some(ControlMessage::new(line));
+        } else if line.starts_with("EVCXR_") {
+            out.push_back(ChildOutput::Control(ControlMessage::new(line)));
+        } else {
+            out.push_back(ChildOutput::Stdout(line));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ChildOutput;
+    use super::ControlMessage;
+    use super::StdoutParser;
+    use super::strip_bytes_attribute;
+    use std::collections::VecDeque;
+
+    fn stdout(line: &str) -> ChildOutput {
+        ChildOutput::Stdout(line.to_owned())
+    }
+
+    fn control(header: &str, payload: &str) -> ChildOutput {
+        ChildOutput::Control(ControlMessage {
+            header: header.to_owned(),
+            payload: payload.as_bytes().to_owned(),
+        })
+    }
+
+    #[test]
+    fn test_parse_stdout() {
+        let mut parser = StdoutParser::default();
+        let mut out = VecDeque::new();
+        parser.push(b"a\r\nEVCXR_BEGIN_CONTENT text/html\n<b>", &mut out);
+        parser.push(
+            b"x</b>\n<i>y</i>\nEVCXR_END_CONTENT\nEVCXR_RESULT\npartial",
+            &mut out,
+        );
+        parser.flush(&mut out);
+        assert_eq!(
+            Vec::from(out),
+            vec![
+                stdout("a"),
+                stdout("EVCXR_BEGIN_CONTENT text/html"),
+                stdout("<b>x</b>"),
+                stdout("<i>y</i>"),
+                stdout("EVCXR_END_CONTENT"),
+                stdout("EVCXR_RESULT"),
+                stdout("partial"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_stdout_with_legacy_control() {
+        let mut parser = StdoutParser {
+            legacy_control: true,
+            ..StdoutParser::default()
+        };
+        let mut out = VecDeque::new();
+        parser.push(b"a\nEVCXR_BEGIN_CONTENT text/html\n<b>", &mut out);
+        parser.push(b"x</b>\nEVCXR_END_CONTENT\n", &mut out);
+        parser.push(b"EVCXR_BEGIN_CONTENT text/plain\n1\n", &mut out);
+        parser.push(
+            b"EVCXR_PANIC_NOTIFICATION\nEVCXR_BEGIN_CONTENT text/plain\n2",
+            &mut out,
+        );
+        parser.flush(&mut out);
+        assert_eq!(
+            Vec::from(out),
+            vec![
+                stdout("a"),
+                control("EVCXR_BEGIN_CONTENT text/html", "<b>x</b>"),
+                control("EVCXR_BEGIN_CONTENT text/plain", "1"),
+                control("EVCXR_PANIC_NOTIFICATION", ""),
+                stdout("EVCXR_BEGIN_CONTENT text/plain"),
+                stdout("2"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_strip_bytes_attribute() {
+        assert_eq!(
+            strip_bytes_attribute("EVCXR_BEGIN_CONTENT image/png bytes").as_deref(),
+            Some("EVCXR_BEGIN_CONTENT image/png")
+        );
+        assert_eq!(
+            strip_bytes_attribute("EVCXR_BEGIN_CONTENT image/png bytes display_id=a b").as_deref(),
+            Some("EVCXR_BEGIN_CONTENT image/png display_id=a b")
+        );
+        assert_eq!(
+            strip_bytes_attribute("EVCXR_BEGIN_CONTENT text/plain display_id=bytes"),
+            None
+        );
+        assert_eq!(
+            strip_bytes_attribute("EVCXR_BEGIN_CONTENT text/plain"),
+            None
+        );
+    }
+}
@@ -23,6 +23,9 @@ use std::fmt;
 use std::fmt::Write as _;
 use std::io;
 use std::ops::Range;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Debug, Clone)]
 pub struct CompilationError {
@@ -32,6 +35,9 @@ pub struct CompilationError {
     spanned_messages: Vec<SpannedMessage>,
     spanned_helps: Vec<SpannedMessage>,
     level: String,
+    /// The file that the code with the error was loaded from, if it wasn't in the cell. Spans are
+    /// then relative to the file.
+    source_file: Option<PathBuf>,
 }
 
 pub enum Theme {
@@ -62,7 +68,8 @@ impl CompilationError {
         theme: Theme,
     ) -> Option<Report<'_, (String, Range<usize>)>> {
         let error = self;
-        if !source.is_ascii() {
+        // Spans of errors in loaded files don't relate to `source`.
+        if !source.is_ascii() || error.source_file.is_some() {
             return None;
         }
         let mut builder = Report::build(ReportKind::Error, (file_name.clone(), 0..source.len()))
@@ -223,6 +230,7 @@ impl CompilationError {
             level: json["level"].as_str().unwrap_or("").to_owned(),
             json,
             code_origins: code_origins.into_iter().cloned().collect(),
+            source_file: None,
         })
     }
 
@@ -251,9 +259,46 @@ impl CompilationError {
             json: Value::Null,
             code_origins: vec![segment.kind.clone()],
             level: "error".to_owned(),
+            source_file: None,
         }
     }
 
+    /// Returns this error with its message replaced. Help from the compiler is removed, since it's
+    /// unlikely to be relevant to the new message.
+    pub(crate) fn with_replacement_message(mut self, message: String) -> CompilationError {
+        self.message = message;
+        self.spanned_helps.clear();
+        if let Value::Object(json) = &mut self.json {
+            json.remove("children");
+        }
+        self
+    }
+
+    /// Returns this error, recording that the code it's in was loaded from `path`. The message is
+    /// prefixed with where in the file the error is, since it can't be shown in the cell.
+    pub(crate) fn with_source_file(mut self, path: &Path) -> CompilationError {
+        self.message = match self
+            .primary_spanned_message()
+            .and_then(|message| message.span.as_ref())
+        {
+            Some(span) => format!(
+                "{}:{}:{}: {}",
+                path.display(),
+                span.start_line,
+                span.start_column,
+                self.message
+            ),
+            None => format!("{}: {}", path.display(), self.message),
+        };
+        self.source_file = Some(path.to_owned());
+        self
+    }
+
+    /// Returns the file that the code with the error was loaded from, if it wasn't in the cell.
+    pub fn source_file(&self) -> Option<&Path> {
+        self.source_file.as_deref()
+    }
+
     /// Returns whether this error originated in code supplied by the user.
     pub fn is_from_user_code(&self) -> bool {
         self.code_origins.iter().any(CodeKind::is_user_supplied)
@@ -563,6 +608,14 @@ pub enum Error {
     TypeRedefinedVariablesLost(Vec<String>),
     Message(String),
     SubprocessTerminated(String),
+    /// Execution of a cell took longer than the configured timeout, so the subprocess was killed.
+    Timeout {
+        /// The position the cell would have had in the history (starting at 1).
+        cell: usize,
+        timeout: Duration,
+        /// Variables that were lost when the subprocess was restarted.
+        lost_variables: Vec<String>,
+    },
 }
 
 impl std::error::Error for Error {}
@@ -585,6 +638,16 @@ impl fmt::Display for Error {
             Error::Message(message) | Error::SubprocessTerminated(message) => {
                 write!(f, "{message}")?
             }
+            Error::Timeout {
+                cell,
+                timeout,
+                lost_variables,
+            } => {
+                write!(f, "Cell {cell} timed out after {timeout:?}")?;
+                if !lost_variables.is_empty() {
+                    write!(f, ". Variables lost: {}", lost_variables.join(", "))?;
+                }
+            }
         }
         Ok(())
     }
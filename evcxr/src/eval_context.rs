@@ -7,10 +7,13 @@
 
 use crate::cargo_metadata;
 use crate::child_process::ChildProcess;
+use crate::child_process::ResourceLimits;
 use crate::code_block::CodeBlock;
 use crate::code_block::CodeKind;
 use crate::code_block::Segment;
 use crate::code_block::UserCodeInfo;
+use crate::control::ChildOutput;
+use crate::control::strip_bytes_attribute;
 use crate::crate_config::ExternalCrate;
 use crate::errors::CompilationError;
 use crate::errors::Error;
@@ -26,8 +29,14 @@ use crate::rust_analyzer::Completions;
 use crate::rust_analyzer::RustAnalyzer;
 use crate::rust_analyzer::TypeName;
 use crate::rust_analyzer::VariableInfo;
+use crate::session::Cell;
+use crate::session::CellNames;
+use crate::session::ReactiveMode;
+use crate::session::SessionDependency;
+use crate::session::SessionFile;
 use crate::toml_parse;
 use crate::use_trees::Import;
+use crate::user_stdin;
 use anyhow::Result;
 use once_cell::sync::Lazy;
 use ra_ap_ide::TextRange;
@@ -36,6 +45,7 @@ use ra_ap_syntax::SyntaxKind;
 use ra_ap_syntax::SyntaxNode;
 use ra_ap_syntax::ast;
 use regex::Regex;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::ffi::OsString;
@@ -44,6 +54,8 @@ use std::path::PathBuf;
 use std::process::Command;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
 use std::time::Duration;
 use std::time::Instant;
 
@@ -57,9 +69,21 @@ pub struct EvalContext {
     _tmpdir: Option<tempfile::TempDir>,
     module: Module,
     committed_state: ContextState,
+    /// States that were committed prior to each successful evaluation, most recent last, together
+    /// with the number of cells in the history at the time. Used by `undo`.
+    undo_states: Vec<(ContextState, usize)>,
+    /// Cells that have been successfully executed, oldest first. Kept here rather than in
+    /// `ContextState`, since that gets cloned for each evaluation and each undo state.
+    cells: Vec<Cell>,
     stdout_sender: crossbeam_channel::Sender<String>,
+    /// Kept so that additional sessions can send their stderr to the same place.
+    stderr_sender: crossbeam_channel::Sender<String>,
+    late_stdout_sender: crossbeam_channel::Sender<LateOutput>,
     analyzer: RustAnalyzer,
     initial_config: Config,
+    /// The number by which the user knows the cell that's running, or that ran most recently. Used
+    /// when telling the user which cell lost a variable.
+    cell_number: usize,
 }
 
 #[derive(Clone, Debug)]
@@ -101,6 +125,24 @@ pub(crate) struct Config {
     pub(crate) target: String,
     pub(crate) allow_static_linking: bool,
     pub(crate) build_envs: HashMap<String, String>,
+    /// Whether to write variables that support serde to disk, so that they can be restored if the
+    /// subprocess is restarted.
+    pub(crate) checkpoint_vars: bool,
+    /// Whether to convert the values of variables whose types are redefined, rather than losing
+    /// them.
+    pub(crate) migrate_vars: bool,
+    /// The target directory of the first session, if this config is for another session. Sharing
+    /// it means that dependencies don't need to be built again.
+    pub(crate) shared_target_dir: Option<PathBuf>,
+    /// What to do with earlier cells when a cell redefines an item that they used.
+    pub(crate) reactive: ReactiveMode,
+    /// How long user code may run before the subprocess is killed.
+    pub(crate) timeout: Option<Duration>,
+    /// Limits on resources used by the subprocess.
+    pub(crate) limits: ResourceLimits,
+    /// Whether the subprocess should be restricted to writing within `sandbox_dirs` and be
+    /// prevented from accessing the network.
+    pub(crate) sandbox: bool,
     subprocess_path: PathBuf,
 }
 
@@ -148,6 +190,13 @@ impl Config {
             subprocess_path,
             codegen_backend: None,
             build_envs: Default::default(),
+            checkpoint_vars: false,
+            migrate_vars: false,
+            shared_target_dir: None,
+            reactive: ReactiveMode::Off,
+            timeout: None,
+            limits: ResourceLimits::default(),
+            sandbox: false,
         })
     }
 
@@ -203,9 +252,9 @@ impl Config {
         command
             .arg(command_name)
             .current_dir(self.crate_dir())
-            .env("CARGO_TARGET_DIR", "target")
-            // Ignore global build.build-dir, since we rely on stuff being under "target".
-            .env("CARGO_BUILD_BUILD_DIR", "target")
+            .env("CARGO_TARGET_DIR", self.common_target_dir())
+            // Ignore global build.build-dir, since we rely on stuff being under our target directory.
+            .env("CARGO_BUILD_BUILD_DIR", self.common_target_dir())
             .env("RUSTC", &self.rustc_path)
             .env("RUSTFLAGS", rustflags.join(" "))
             .envs(&self.build_envs)
@@ -248,6 +297,21 @@ impl Config {
         self.tmpdir.join("src")
     }
 
+    pub(crate) fn checkpoint_dir(&self) -> PathBuf {
+        self.tmpdir.join("checkpoints")
+    }
+
+    /// Returns the directories that a sandboxed subprocess may write to, or an empty list if the
+    /// subprocess isn't sandboxed. The first is the subprocess's working directory. The crate
+    /// directory isn't included, since code written there would be run unsandboxed by cargo.
+    pub(crate) fn sandbox_dirs(&self) -> Vec<PathBuf> {
+        if self.sandbox {
+            vec![self.tmpdir.join("sandbox"), self.checkpoint_dir()]
+        } else {
+            Vec::new()
+        }
+    }
+
     pub(crate) fn deps_dir(&self) -> PathBuf {
         self.target_dir().join("debug").join("deps")
     }
@@ -257,7 +321,9 @@ impl Config {
     }
 
     pub(crate) fn common_target_dir(&self) -> PathBuf {
-        self.tmpdir.join("target")
+        self.shared_target_dir
+            .clone()
+            .unwrap_or_else(|| self.tmpdir.join("target"))
     }
 }
 
@@ -282,25 +348,6 @@ static ERROR_FORMATS: &[ErrorFormat] = &[
     },
 ];
 
-const SEND_TEXT_PLAIN_DEF: &str = stringify!(
-    fn evcxr_send_text_plain(text: &str) {
-        use std::io::Write;
-        use std::io::{self};
-        fn try_send_text(text: &str) -> io::Result<()> {
-            let stdout = io::stdout();
-            let mut output = stdout.lock();
-            output.write_all(b"EVCXR_BEGIN_CONTENT text/plain\n")?;
-            output.write_all(text.as_bytes())?;
-            output.write_all(b"\nEVCXR_END_CONTENT\n")?;
-            Ok(())
-        }
-        if let Err(error) = try_send_text(text) {
-            eprintln!("Failed to send content to parent: {:?}", error);
-            std::process::exit(1);
-        }
-    }
-);
-
 const GET_TYPE_NAME_DEF: &str = stringify!(
     /// Shorten a type name. Convert "core::option::Option<alloc::string::String>" into "Option<String>".
     pub fn evcxr_shorten_type(t: &str) -> String {
@@ -332,11 +379,181 @@ const GET_TYPE_NAME_DEF: &str = stringify!(
 
 const PANIC_NOTIFICATION: &str = "EVCXR_PANIC_NOTIFICATION";
 
+// Printed after the final expression of a cell has been evaluated, but before it's displayed. Any
+// content emitted after this is the result of the cell rather than a separate display.
+const RESULT_NOTIFICATION: &str = "EVCXR_RESULT";
+
+// Sent by `evcxr_runtime::display` before and after content that forms a single display, with each
+// mime type being an alternative representation of the same thing.
+const DISPLAY_START: &str = "EVCXR_BEGIN_DISPLAY";
+const DISPLAY_END: &str = "EVCXR_END_DISPLAY";
+
+// The maximum number of evaluations that can be undone.
+const MAX_UNDO_STATES: usize = 50;
+
+// Prefix of the names of variables that hold the join handles of background jobs.
+const JOB_VARIABLE_PREFIX: &str = "evcxr_job_";
+
+/// Returns an error unless `dep_config` is for a dependency from crates.io. When sandboxed, other
+/// dependencies could be in files written by sandboxed code, which building them would run on the
+/// host, e.g. build scripts.
+fn check_registry_dep(dep: &str, dep_config: &str) -> Result<(), Error> {
+    const ALLOWED_KEYS: &[&str] = &[
+        "version",
+        "features",
+        "default-features",
+        "default_features",
+        "package",
+    ];
+    let Ok(table) = format!("dep = {dep_config}").parse::<toml::Table>() else {
+        bail!(
+            "Invalid configuration for dependency {}: {}",
+            dep,
+            dep_config
+        );
+    };
+    match &table["dep"] {
+        toml::Value::String(_) => Ok(()),
+        toml::Value::Table(config) => {
+            if let Some(key) = config
+                .keys()
+                .find(|key| !ALLOWED_KEYS.contains(&key.as_str()))
+            {
+                bail!(
+                    "Only dependencies from crates.io are available when sandboxed, but {} sets `{}`",
+                    dep,
+                    key
+                );
+            }
+            Ok(())
+        }
+        _ => bail!(
+            "Invalid configuration for dependency {}: {}",
+            dep,
+            dep_config
+        ),
+    }
+}
+
+/// Returns the name of the variable that holds the join handle for the job with the supplied id.
+pub(crate) fn job_variable_name(id: usize) -> String {
+    format!("{JOB_VARIABLE_PREFIX}{id}")
+}
+
+// The dependency added when async mode is enabled, which is also what `run` gets executed with.
+const TOKIO_DEP: (&str, &str) = (
+    "tokio",
+    "{version=\"1.34.0\", features=[\"rt\", \"rt-multi-thread\"]}",
+);
+
+// Dependencies added when checkpointing or migration of variables is enabled.
+const SERDE_DEPS: &[(&str, &str)] = &[
+    ("serde", "{ version = \"1\", features = [\"derive\"] }"),
+    ("serde_json", "\"1\""),
+];
+
+// Writes a variable to the checkpoint directory if its type supports serde, otherwise removes any
+// previous checkpoint of a variable with the same name. Uses autoref-based specialization to pick
+// between the two, which works since the types of stored variables are always concrete.
+// `EVCXR_CHECKPOINT_DIR` is replaced with the checkpoint directory.
+const CHECKPOINT_DEF: &str = r#"
+struct EvcxrCheckpoint<'a, T>(&'a T, &'static str);
+impl<T> EvcxrCheckpoint<'_, T> {
+    fn path(&self) -> std::path::PathBuf {
+        std::path::Path::new(EVCXR_CHECKPOINT_DIR).join(format!("{}.json", self.1))
+    }
+}
+trait EvcxrCheckpointSerde {
+    fn evcxr_checkpoint(&self);
+}
+impl<T: serde::Serialize + serde::de::DeserializeOwned> EvcxrCheckpointSerde
+    for EvcxrCheckpoint<'_, T>
+{
+    fn evcxr_checkpoint(&self) {
+        let path = self.path();
+        let tmp_path = path.with_extension("tmp");
+        if let Ok(json) = serde_json::to_string(self.0)
+            && std::fs::create_dir_all(path.parent().unwrap()).is_ok()
+            && std::fs::write(&tmp_path, json).is_ok()
+        {
+            let _ = std::fs::rename(&tmp_path, &path);
+        } else {
+            let _ = std::fs::remove_file(&path);
+        }
+    }
+}
+trait EvcxrCheckpointFallback {
+    fn evcxr_checkpoint(&self);
+}
+impl<T> EvcxrCheckpointFallback for &EvcxrCheckpoint<'_, T> {
+    fn evcxr_checkpoint(&self) {
+        let _ = std::fs::remove_file(self.path());
+    }
+}
+"#;
+
+// Converts variables to and from JSON when their types support serde, so that a variable can be
+// migrated if its type is redefined. Like `EvcxrCheckpoint`, this uses autoref-based
+// specialization. Generated code calls `(&EvcxrMigration::<T>(PhantomData)).evcxr_to_json(value)`
+// and `.evcxr_from_json(json)`, which return None if `T` doesn't support serde.
+const MIGRATION_DEF: &str = r#"
+struct EvcxrMigration<T>(std::marker::PhantomData<T>);
+trait EvcxrMigrationSerialize<T> {
+    fn evcxr_to_json(&self, value: &T) -> Option<String>;
+}
+impl<T: serde::Serialize> EvcxrMigrationSerialize<T> for EvcxrMigration<T> {
+    fn evcxr_to_json(&self, value: &T) -> Option<String> {
+        serde_json::to_string(value).ok()
+    }
+}
+trait EvcxrMigrationSerializeFallback<T> {
+    fn evcxr_to_json(&self, value: &T) -> Option<String>;
+}
+impl<T> EvcxrMigrationSerializeFallback<T> for &EvcxrMigration<T> {
+    fn evcxr_to_json(&self, _value: &T) -> Option<String> {
+        None
+    }
+}
+trait EvcxrMigrationDeserialize<T> {
+    fn evcxr_from_json(&self, json: &str) -> Option<T>;
+}
+impl<T: serde::de::DeserializeOwned> EvcxrMigrationDeserialize<T> for EvcxrMigration<T> {
+    fn evcxr_from_json(&self, json: &str) -> Option<T> {
+        serde_json::from_str(json).ok()
+    }
+}
+trait EvcxrMigrationDeserializeFallback<T> {
+    fn evcxr_from_json(&self, json: &str) -> Option<T>;
+}
+impl<T> EvcxrMigrationDeserializeFallback<T> for &EvcxrMigration<T> {
+    fn evcxr_from_json(&self, _json: &str) -> Option<T> {
+        None
+    }
+}
+"#;
+
 // Outputs from an EvalContext. This is a separate struct since users may want
 // destructure this and pass its components to separate threads.
 pub struct EvalContextOutputs {
     pub stdout: crossbeam_channel::Receiver<String>,
     pub stderr: crossbeam_channel::Receiver<String>,
+    /// Lines of stdout that were printed while no cell was running, attributed to the cell that
+    /// completed last. See `LateOutput`.
+    pub late_stdout: crossbeam_channel::Receiver<LateOutput>,
+}
+
+/// A line of stdout that was printed after a cell completed and before the next one started, e.g.
+/// by a thread that the cell started. Stdout doesn't say which thread printed a line, so the line
+/// is attributed to the cell that completed last, which might not be the one that started the
+/// thread. Lines printed while a cell is running are always treated as that cell's output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LateOutput {
+    /// Together with `build_num`, identifies the cell. Matches `EvalOutputs::generation` for the
+    /// cell.
+    pub generation: u64,
+    /// Matches `EvalOutputs::build_num` for the cell.
+    pub build_num: i32,
+    pub line: String,
 }
 
 #[non_exhaustive]
@@ -348,16 +565,23 @@ pub struct InputRequest {
 
 pub struct EvalCallbacks<'a> {
     pub input_reader: &'a dyn Fn(InputRequest) -> String,
+    /// Called with each piece of rich content that the user's code displays, as soon as it
+    /// arrives. By the time this is called, any stdout that preceded the content will have been
+    /// sent.
+    pub display: &'a dyn Fn(&DisplayBundle),
 }
 
 fn default_input_reader(_: InputRequest) -> String {
     String::new()
 }
 
+fn default_display(_: &DisplayBundle) {}
+
 impl Default for EvalCallbacks<'_> {
     fn default() -> Self {
         EvalCallbacks {
             input_reader: &default_input_reader,
+            display: &default_display,
         }
     }
 }
@@ -429,41 +653,109 @@ impl EvalContext {
             create_initial_config(tmpdir_path, subprocess_command.get_program().into())?;
         parsed_config.update_config(&mut initial_config)?;
         Self::apply_platform_specific_vars(&initial_config, &mut subprocess_command);
+        crate::sandbox::configure_command(&mut subprocess_command, &initial_config.sandbox_dirs())?;
 
         let (stdout_sender, stdout_receiver) = crossbeam_channel::unbounded();
         let (stderr_sender, stderr_receiver) = crossbeam_channel::unbounded();
-        let child_process = ChildProcess::new(subprocess_command, stderr_sender)?;
+        let (late_stdout_sender, late_stdout_receiver) = crossbeam_channel::unbounded();
+        let child_process = ChildProcess::new(
+            subprocess_command,
+            stderr_sender.clone(),
+            late_stdout_sender.clone(),
+            initial_config.limits,
+        )?;
         let initial_state = ContextState::new(initial_config.clone());
-        let mut context = EvalContext {
+        let context = EvalContext {
             _tmpdir: opt_tmpdir,
             committed_state: initial_state,
+            undo_states: Vec::new(),
+            cells: Vec::new(),
             module,
             child_process,
             stdout_sender,
+            stderr_sender,
+            late_stdout_sender,
             analyzer,
             initial_config,
+            cell_number: 0,
         };
         let outputs = EvalContextOutputs {
             stdout: stdout_receiver,
             stderr: stderr_receiver,
+            late_stdout: late_stdout_receiver,
         };
-        if context.committed_state.linker() == "lld" && context.eval("42").is_err() {
-            context.committed_state.set_linker("system".to_owned());
-        } else {
-            // We need to eval something anyway, otherwise rust-analyzer crashes when trying to get
-            // completions. Not 100% sure. Just writing Cargo.toml isn't sufficient.
-            if let Err(error) = context.eval("42") {
-                drop(context);
+        match context.warm_up() {
+            Ok(context) => Ok((context, outputs)),
+            Err(error) => {
                 let mut stderr = String::new();
                 while let Ok(line) = outputs.stderr.recv() {
                     stderr.push_str(&line);
                     stderr.push('\n');
                 }
-                return Err(format!("{stderr}{error}").into());
+                Err(format!("{stderr}{error}").into())
             }
         }
-        context.initial_config = context.committed_state.config.clone();
-        Ok((context, outputs))
+    }
+
+    /// Evaluates some code before the context is handed to the user. Drops the context if this
+    /// fails.
+    fn warm_up(mut self) -> Result<EvalContext, Error> {
+        if self.committed_state.linker() == "lld" && self.eval("42").is_err() {
+            self.committed_state.set_linker("system".to_owned());
+        } else {
+            // We need to eval something anyway, otherwise rust-analyzer crashes when trying to get
+            // completions. Not 100% sure. Just writing Cargo.toml isn't sufficient.
+            self.eval("42")?;
+        }
+        // The code we evaluated above wasn't from the user, so shouldn't be part of the history.
+        self.cells.clear();
+        self.undo_states.clear();
+        self.initial_config = self.committed_state.config.clone();
+        Ok(self)
+    }
+
+    /// Creates a new context with its own state and subprocess. Its output goes to the same place
+    /// as ours and it reuses dependencies that we've already built. It starts with our current
+    /// settings, such as limits, timeout, build environment variables and sandboxing, but not our
+    /// dependencies.
+    pub(crate) fn new_session(&self, name: &str) -> Result<EvalContext, Error> {
+        let config = &self.committed_state.config;
+        let mut initial_config = config.clone();
+        initial_config.shared_target_dir = Some(config.common_target_dir());
+        initial_config.tmpdir = self.initial_config.tmpdir.join("sessions").join(name);
+        std::fs::create_dir_all(&initial_config.tmpdir)?;
+        let mut subprocess_command = Command::new(&initial_config.subprocess_path);
+        Self::apply_platform_specific_vars(&initial_config, &mut subprocess_command);
+        crate::sandbox::configure_command(&mut subprocess_command, &initial_config.sandbox_dirs())?;
+        let child_process = ChildProcess::new(
+            subprocess_command,
+            self.stderr_sender.clone(),
+            self.late_stdout_sender.clone(),
+            initial_config.limits,
+        )?;
+        let context = EvalContext {
+            _tmpdir: None,
+            committed_state: ContextState::new(initial_config.clone()),
+            undo_states: Vec::new(),
+            cells: Vec::new(),
+            module: Module::for_session(name),
+            child_process,
+            stdout_sender: self.stdout_sender.clone(),
+            stderr_sender: self.stderr_sender.clone(),
+            late_stdout_sender: self.late_stdout_sender.clone(),
+            analyzer: RustAnalyzer::new(&initial_config.tmpdir)?,
+            initial_config,
+            cell_number: 0,
+        };
+        context.warm_up()
+    }
+
+    /// Makes handles previously returned by `process_handle` refer to `other`'s subprocess
+    /// instead of ours and vice versa. Used when switching sessions so that interrupting affects
+    /// the current session.
+    pub(crate) fn swap_process_handles(&mut self, other: &mut EvalContext) {
+        self.child_process
+            .swap_process_handles(&mut other.child_process);
     }
 
     /// Returns a new context state, suitable for passing to `eval` after
@@ -521,9 +813,31 @@ impl EvalContext {
 
         let mut outputs =
             match self.run_statements(code_out, code_info, &mut state, &mut phases, callbacks) {
-                error @ Err(Error::SubprocessTerminated(_)) => {
-                    self.restart_child_process()?;
-                    return error;
+                Err(Error::SubprocessTerminated(message)) => {
+                    let restored = self.restart_child_process(LossReason::SubprocessTerminated)?;
+                    if restored.is_empty() {
+                        return Err(Error::SubprocessTerminated(message));
+                    }
+                    return Err(Error::SubprocessTerminated(format!(
+                        "{message}\nRestored from checkpoint: {}",
+                        restored.join(", ")
+                    )));
+                }
+                Err(Error::Timeout { cell, timeout, .. }) => {
+                    let mut lost_variables: Vec<String> = self
+                        .committed_state
+                        .variable_states
+                        .keys()
+                        .cloned()
+                        .collect();
+                    let restored = self.restart_child_process(LossReason::Timeout)?;
+                    lost_variables.retain(|name| !restored.contains(name));
+                    lost_variables.sort();
+                    return Err(Error::Timeout {
+                        cell,
+                        timeout,
+                        lost_variables,
+                    });
                 }
                 Err(Error::CompilationErrors(errors)) => {
                     let mut errors = state.apply_custom_errors(errors, &user_code, code_info);
@@ -540,6 +854,13 @@ impl EvalContext {
 
         // Once, we reach here, our code has successfully executed, so we
         // conclude that variable changes are now applied.
+        let cell = state.take_cell(&user_code, &outputs);
+        if self.undo_states.len() == MAX_UNDO_STATES {
+            self.undo_states.remove(0);
+        }
+        self.undo_states
+            .push((self.committed_state.clone(), self.cells.len()));
+        self.cells.extend(cell);
         self.commit_state(state);
 
         phases.phase_complete("Execution");
@@ -587,6 +908,53 @@ impl EvalContext {
         Ok(completions)
     }
 
+    /// Returns the Rust code from `code` with each macro invocation replaced by its expansion.
+    /// `user_code` and `code_info` are the result of parsing `code`.
+    pub(crate) fn expand(
+        &mut self,
+        code: &str,
+        user_code: CodeBlock,
+        mut state: ContextState,
+        code_info: &UserCodeInfo,
+    ) -> Result<crate::expand::Expanded> {
+        state.config.display_final_expression = false;
+        state.config.expand_use_statements = false;
+        let applied_code = state.apply(user_code.clone(), &code_info.nodes)?;
+        let analysis_code = state.analysis_code(applied_code);
+        self.analyzer.set_source(analysis_code.code_string())?;
+        let analyzer = &self.analyzer;
+        let module = &self.module;
+        // Only run rustc if there are derives to expand, since it's much slower than rust-analyzer.
+        let mut derived_impls = None;
+        let expanded = crate::expand::expand_macros(
+            code,
+            &user_code,
+            &code_info.nodes,
+            |node_index, offset| {
+                let node_text = code_info.nodes[node_index].text().to_string();
+                let position = analysis_code.node_output_offset(node_index, &node_text)? + offset;
+                analyzer.expand_macro(position).ok().flatten()
+            },
+            |path| {
+                let impls = derived_impls.get_or_insert_with(|| {
+                    module
+                        .expanded_code(&analysis_code, &state.config)
+                        .map(|expanded| {
+                            crate::expand::derived_impls(&expanded, "evcxr_analysis_wrapper")
+                        })
+                });
+                match impls {
+                    Ok(impls) => impls.remove(path).unwrap_or_default(),
+                    Err(_) => Vec::new(),
+                }
+            },
+        );
+        if let Some(Err(error)) = derived_impls {
+            anyhow::bail!("Couldn't expand derives: {error}");
+        }
+        Ok(expanded)
+    }
+
     pub fn hover(&mut self, code: &str, state: &mut ContextState) -> Result<(String, String)> {
         let (modified_code, hover_offset) = if code == "let" {
             (String::from("let _ = 1;"), 0)
@@ -645,6 +1013,62 @@ impl EvalContext {
             .map(|(v, t)| (v.as_str(), t.type_name.as_str()))
     }
 
+    /// Returns details of all variables, sorted by name. Previews and sizes are obtained from the
+    /// subprocess without compiling anything.
+    pub fn variable_details(&mut self) -> Result<Vec<VariableDetails>, Error> {
+        let mut reported = HashMap::new();
+        if !self.committed_state.variable_states.is_empty() {
+            self.child_process.send(runtime::VARIABLE_DETAILS)?;
+            loop {
+                let message = match self.child_process.recv()? {
+                    ChildOutput::Stdout(line) => {
+                        let _ = self.stdout_sender.send(line);
+                        continue;
+                    }
+                    ChildOutput::Control(message) => message,
+                };
+                if message.header == runtime::EVCXR_EXECUTION_COMPLETE {
+                    break;
+                }
+                if let Some(detail) = message
+                    .header
+                    .strip_prefix(evcxr_internal_runtime::VARIABLE_DETAIL)
+                {
+                    let mut parts = detail.splitn(3, ' ');
+                    if let (Some(name), Some(Ok(size))) =
+                        (parts.next(), parts.next().map(str::parse::<usize>))
+                    {
+                        reported.insert(name.to_owned(), (size, parts.next().map(str::to_owned)));
+                    }
+                }
+            }
+        }
+        let mut details: Vec<VariableDetails> = self
+            .committed_state
+            .variable_states
+            .iter()
+            .map(|(name, state)| {
+                let (size, preview) = match reported.remove(name) {
+                    Some((size, preview)) => (Some(size), preview),
+                    None => (None, None),
+                };
+                VariableDetails {
+                    name: name.clone(),
+                    type_name: state.type_name.clone(),
+                    preview,
+                    size,
+                    defining_cell: self
+                        .cells
+                        .iter()
+                        .rposition(|cell| cell.defined_variables.contains(name))
+                        .map(|index| index + 1),
+                }
+            })
+            .collect();
+        details.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(details)
+    }
+
     pub fn defined_item_names(&self) -> impl Iterator<Item = &str> {
         self.committed_state
             .items_by_name
@@ -657,28 +1081,376 @@ impl EvalContext {
     // compiled. Config is preserved.
     pub fn clear(&mut self) -> Result<(), Error> {
         self.committed_state = self.cleared_state();
-        self.restart_child_process()
+        self.undo_states.clear();
+        self.cells.clear();
+        self.restart_child_process(LossReason::Restarted)?;
+        Ok(())
     }
 
     /// Returns the state that would result from clearing. Config is preserved. Nothing is done to
     /// the subprocess.
     pub(crate) fn cleared_state(&self) -> ContextState {
-        ContextState::new(self.committed_state.config.clone())
+        let mut state = ContextState::new(self.committed_state.config.clone());
+        if state.config.checkpoint_vars || state.config.migrate_vars {
+            // Dependencies are cleared, but since checkpointing and migration are config, they
+            // stay enabled, so we need to put back the dependencies that they need.
+            for (name, config) in SERDE_DEPS {
+                if let Ok(krate) = ExternalCrate::new((*name).to_owned(), (*config).to_owned()) {
+                    state.external_deps.insert((*name).to_owned(), krate);
+                }
+            }
+        }
+        state
     }
 
     pub fn reset_config(&mut self) {
+        let sandbox = self.committed_state.config.sandbox;
         self.committed_state.config = self.initial_config.clone();
+        // Once enabled, the sandbox stays enabled.
+        self.committed_state.config.sandbox |= sandbox;
+    }
+
+    /// Replaces all state with that from a session previously written by `save_session`. Items,
+    /// dependencies and configuration are restored directly, then the statements and expressions
+    /// from each cell are run again, since they may have defined or changed variables. Returns the
+    /// (zero-based) indices of cells that couldn't be replayed together with the error that
+    /// occurred.
+    pub(crate) fn load_session(&mut self, path: &Path) -> Result<Vec<(usize, Error)>, Error> {
+        let session = SessionFile::read(path)?;
+        self.clear()?;
+        let mut state = self.cleared_state();
+        state.apply_session_file(&session)?;
+        // Build the restored items without running any user code.
+        self.eval_with_state("", state)?;
+        let mut failures = Vec::new();
+        for (index, cell) in session.cells.iter().enumerate() {
+            let statements = cell.statements();
+            if statements.trim().is_empty() {
+                continue;
+            }
+            if let Err(error) = self.eval(&statements) {
+                failures.push((index, error));
+            }
+        }
+        // Replaying cells records them again, but only in their reduced form, so we put back the
+        // history as it was when saved.
+        self.cells = session.cells;
+        self.undo_states.clear();
+        Ok(failures)
+    }
+
+    /// Restores items, imports, attributes and dependencies to how they were prior to the most
+    /// recent successful evaluation. Variables defined by that evaluation are dropped. Returns the
+    /// names of variables that existed beforehand, but which can't be restored because that
+    /// evaluation moved or redefined them.
+    pub(crate) fn undo(&mut self) -> Result<Vec<String>, Error> {
+        let Some((mut previous, num_cells)) = self.undo_states.pop() else {
+            bail!("Nothing to undo");
+        };
+        let current_variables = &self.committed_state.variable_states;
+        let mut to_drop: Vec<String> = current_variables
+            .iter()
+            .filter(|(_, state)| state.move_state == VariableMoveState::New)
+            .map(|(name, _)| name.clone())
+            .collect();
+        to_drop.sort();
+        let mut lost: Vec<String> = previous
+            .variable_states
+            .keys()
+            .filter(|name| {
+                current_variables
+                    .get(*name)
+                    .is_none_or(|state| state.move_state == VariableMoveState::New)
+            })
+            .cloned()
+            .collect();
+        lost.sort();
+        if let Err(error) = self.drop_variables(&to_drop) {
+            self.undo_states.push((previous, num_cells));
+            return Err(error);
+        }
+        for name in &lost {
+            previous.variable_states.remove(name);
+        }
+        previous
+            .stored_variable_states
+            .clone_from(&previous.variable_states);
+        // Configuration changes aren't something that we undo.
+        previous.config = self.committed_state.config.clone();
+        self.committed_state = previous;
+        self.cells.truncate(num_cells);
+        Ok(lost)
+    }
+
+    /// Sets limits on the resources that the subprocess may use. The subprocess is restarted so that
+    /// they take effect. Returns the names of variables restored from checkpoints.
+    pub(crate) fn set_limits(&mut self, limits: ResourceLimits) -> Result<Vec<String>, Error> {
+        self.committed_state.config.limits = limits;
+        self.restart_child_process(LossReason::Restarted)
+    }
+
+    /// Sandboxes the subprocess, which is restarted so that this takes effect. Returns the names of
+    /// variables restored from checkpoints. Fails without changing anything if sandboxing isn't
+    /// supported.
+    pub(crate) fn enable_sandbox(&mut self) -> Result<Vec<String>, Error> {
+        let mut config = self.committed_state.config.clone();
+        config.sandbox = true;
+        self.child_process.check_sandbox(&config.sandbox_dirs())?;
+        self.committed_state.config.sandbox = true;
+        self.restart_child_process(LossReason::Restarted)
+    }
+
+    /// Sets the number by which the user knows the cell that's about to run.
+    pub(crate) fn set_cell_number(&mut self, cell_number: usize) {
+        self.cell_number = cell_number;
+    }
+
+    /// Returns the cells in the history, oldest first.
+    pub(crate) fn cells(&self) -> &[Cell] {
+        &self.cells
+    }
+
+    /// Returns the number of cells in the history.
+    pub(crate) fn num_cells(&self) -> usize {
+        self.cells.len()
+    }
+
+    pub(crate) fn reactive_mode(&self) -> ReactiveMode {
+        self.committed_state.config.reactive
+    }
+
+    /// Returns the indices of cells that ran against an earlier definition of an item that the most
+    /// recent cell redefined.
+    pub(crate) fn stale_cells(&self) -> Vec<usize> {
+        crate::session::stale_cells(&self.cells)
+    }
+
+    /// Runs the statements and expressions from the cells with the supplied indices again, stopping
+    /// at the first failure. Items defined by those cells aren't rerun, since that would put back
+    /// old definitions. The history is left unchanged.
+    pub(crate) fn rerun_cells(
+        &mut self,
+        indices: &[usize],
+    ) -> Vec<(usize, Result<EvalOutputs, Error>)> {
+        let num_cells = self.cells.len();
+        let undo_depth = self.undo_states.len();
+        let mut results = Vec::new();
+        for index in indices {
+            let result = self.eval(&self.cells[*index].statements());
+            let failed = result.is_err();
+            results.push((*index, result));
+            if failed {
+                break;
+            }
+        }
+        self.cells.truncate(num_cells);
+        self.undo_states.truncate(undo_depth);
+        results
+    }
+
+    /// Removes the item and/or variable named `name`. Variables whose types refer to a removed item
+    /// are also removed, since they could no longer be loaded. Returns the names of all removed
+    /// variables.
+    pub(crate) fn forget(&mut self, name: &str) -> Result<Forgotten, Error> {
+        let state = &self.committed_state;
+        let mut items = Vec::new();
+        if state.items_by_name.contains_key(name) {
+            items.push(name.to_owned());
+            // Items whose definitions refer to a forgotten item would no longer compile, so are
+            // forgotten too. The first segment of an item's block is its definition. Later
+            // segments are unnamed items, such as impls, that followed it.
+            let mut index = 0;
+            while let Some(forgotten) = items.get(index).cloned() {
+                let mut dependents: Vec<String> = state
+                    .items_by_name
+                    .iter()
+                    .filter(|(other, block)| {
+                        !items.contains(other)
+                            && block.segments.first().is_some_and(|definition| {
+                                crate::item::refers_to(&definition.code, &forgotten)
+                            })
+                    })
+                    .map(|(other, _)| other.clone())
+                    .collect();
+                dependents.sort();
+                items.extend(dependents);
+                index += 1;
+            }
+        }
+        let mut variables: Vec<String> = state
+            .variable_states
+            .iter()
+            .filter(|(variable, variable_state)| {
+                *variable == name
+                    || items.iter().any(|item| {
+                        crate::item::refers_to(
+                            &format!("type T = {};", variable_state.type_name),
+                            item,
+                        )
+                    })
+            })
+            .map(|(variable, _)| variable.clone())
+            .collect();
+        if items.is_empty() && variables.is_empty() {
+            bail!("No item or variable named `{}`", name);
+        }
+        variables.sort();
+        // Variables need to be dropped while their types still exist.
+        self.drop_variables(&variables)?;
+        let state = &mut self.committed_state;
+        for item in &items {
+            state.items_by_name.remove(item);
+        }
+        // Items without a name, such as impls, can't be forgotten directly, so are only forgotten
+        // along with what they refer to.
+        let refers_to_forgotten =
+            |code: &str| items.iter().any(|item| crate::item::refers_to(code, item));
+        for block in state.items_by_name.values_mut() {
+            block
+                .segments
+                .retain(|segment| !refers_to_forgotten(&segment.code));
+        }
+        state
+            .unnamed_items
+            .retain(|block| !refers_to_forgotten(&block.code_string()));
+        Ok(Forgotten {
+            dependent_items: items.into_iter().skip(1).collect(),
+            variables,
+        })
+    }
+
+    /// Removes the specified variables, including from the subprocess.
+    fn drop_variables(&mut self, names: &[String]) -> Result<(), Error> {
+        if names.is_empty() {
+            return Ok(());
+        }
+        let code: String = names
+            .iter()
+            .map(|name| format!("drop({name});\n"))
+            .collect();
+        let num_cells = self.cells.len();
+        let undo_depth = self.undo_states.len();
+        let result = self.eval(&code);
+        // The code we ran isn't something the user wrote, so shouldn't be in the history, or be
+        // something that can be undone.
+        self.cells.truncate(num_cells);
+        self.undo_states.truncate(undo_depth);
+        result?;
+        // Variables of types that are Copy won't have been moved by the call to `drop`.
+        for name in names {
+            self.committed_state.variable_states.remove(name);
+            self.committed_state.stored_variable_states.remove(name);
+        }
+        Ok(())
     }
 
     pub fn process_handle(&self) -> Arc<Mutex<std::process::Child>> {
         self.child_process.process_handle()
     }
 
-    pub(crate) fn restart_child_process(&mut self) -> Result<(), Error> {
+    /// Restarts the subprocess, which loses all variables. If checkpointing of variables is
+    /// enabled, then any variables that were checkpointed are then restored. Returns the names of
+    /// restored variables. `reason` is recorded for each variable that's lost.
+    pub(crate) fn restart_child_process(
+        &mut self,
+        reason: LossReason,
+    ) -> Result<Vec<String>, Error> {
+        let checkpointed = if self.committed_state.config.checkpoint_vars {
+            let checkpoint_dir = self.committed_state.config.checkpoint_dir();
+            let mut checkpointed: Vec<(String, VariableState)> = self
+                .committed_state
+                .stored_variable_states
+                .iter()
+                .filter(|(name, _)| checkpoint_dir.join(format!("{name}.json")).exists())
+                .map(|(name, state)| (name.clone(), state.clone()))
+                .collect();
+            checkpointed.sort_by(|a, b| a.0.cmp(&b.0));
+            checkpointed
+        } else {
+            Vec::new()
+        };
+        let cell = match reason {
+            LossReason::Restarted => None,
+            _ => Some(self.cell_number),
+        };
+        let lost: Vec<String> = self
+            .committed_state
+            .variable_states
+            .keys()
+            .cloned()
+            .collect();
+        for name in &lost {
+            self.committed_state
+                .record_lost_variable(name, cell, reason);
+        }
         self.committed_state.variable_states.clear();
         self.committed_state.stored_variable_states.clear();
+        self.child_process
+            .set_limits(self.committed_state.config.limits)?;
+        self.child_process
+            .set_sandbox_dirs(&self.committed_state.config.sandbox_dirs())?;
         self.child_process = self.child_process.restart()?;
-        Ok(())
+        Ok(self.restore_checkpointed_variables(&checkpointed))
+    }
+
+    /// Attempts to load each of the supplied variables from its checkpoint. Returns the names of
+    /// those that were successfully restored.
+    fn restore_checkpointed_variables(
+        &mut self,
+        variables: &[(String, VariableState)],
+    ) -> Vec<String> {
+        if variables.is_empty() {
+            return Vec::new();
+        }
+        let checkpoint_dir = self.committed_state.config.checkpoint_dir();
+        // We move checkpoints out of the way before loading them. If loading crashes the
+        // subprocess, we then won't try to load them again after the resulting restart. Those that
+        // load successfully get checkpointed again once they're stored.
+        for (name, _) in variables {
+            let _ = std::fs::rename(
+                checkpoint_dir.join(format!("{name}.json")),
+                checkpoint_dir.join(format!("{name}.restoring")),
+            );
+        }
+        let restore_code = |(name, state): &(String, VariableState)| {
+            format!(
+                "let {}{name}: {} = serde_json::from_str(&std::fs::read_to_string({:?}).unwrap()).unwrap();\n",
+                if state.is_mut { "mut " } else { "" },
+                state.type_name,
+                checkpoint_dir.join(format!("{name}.restoring")),
+            )
+        };
+        let num_cells = self.cells.len();
+        let undo_depth = self.undo_states.len();
+        let mut restored = Vec::new();
+        let all_code: String = variables.iter().map(restore_code).collect();
+        match self.eval(&all_code) {
+            Ok(_) => {
+                restored = variables.iter().map(|(name, _)| name.clone()).collect();
+            }
+            // If the subprocess died, then there's no point trying again.
+            Err(Error::SubprocessTerminated(_)) => {}
+            Err(_) => {
+                // Perhaps a type no longer supports serde. Fall back to restoring variables one at
+                // a time so that one bad variable doesn't stop the others.
+                for variable in variables {
+                    match self.eval(&restore_code(variable)) {
+                        Ok(_) => restored.push(variable.0.clone()),
+                        Err(Error::SubprocessTerminated(_)) => {
+                            // Anything we'd restored so far was lost with the subprocess.
+                            restored.clear();
+                            break;
+                        }
+                        Err(_) => {}
+                    }
+                }
+            }
+        }
+        // The code we ran isn't something the user wrote, so shouldn't be in the history, or be
+        // something that can be undone.
+        self.cells.truncate(num_cells);
+        self.undo_states.truncate(undo_depth);
+        restored
     }
 
     pub(crate) fn last_compile_dir(&self) -> &Path {
@@ -693,6 +1465,12 @@ impl EvalContext {
         state
             .stored_variable_states
             .clone_from(&state.variable_states);
+        let ContextState {
+            lost_variables,
+            variable_states,
+            ..
+        } = &mut state;
+        lost_variables.retain(|name, _| !variable_states.contains_key(name));
         state.commit_old_user_code();
         self.committed_state = state;
     }
@@ -782,11 +1560,18 @@ impl EvalContext {
                 }
 
                 Err(Error::TypeRedefinedVariablesLost(variables)) => {
+                    let cell = Some(self.cell_number);
                     for variable in &variables {
                         state.variable_states.remove(variable);
                         state.stored_variable_states.remove(variable);
+                        state.record_lost_variable(variable, cell, LossReason::TypeRedefined);
                         self.committed_state.variable_states.remove(variable);
                         self.committed_state.stored_variable_states.remove(variable);
+                        self.committed_state.record_lost_variable(
+                            variable,
+                            cell,
+                            LossReason::TypeRedefined,
+                        );
                     }
                     remaining_retries -= 1;
                 }
@@ -843,8 +1628,9 @@ impl EvalContext {
             if variable_name == "evcxr_variable_store" {
                 continue;
             }
-            let type_name = match type_name {
-                TypeName::Named(x) => x,
+            let (type_name, needs_boxing) = match type_name {
+                TypeName::Named(x) => (x, false),
+                TypeName::Boxed(x) => (x, true),
                 TypeName::Closure => bail!(
                     "The variable `{}` is a closure, which cannot be persisted.\n\
                      You can however persist closures if you box them. e.g.:\n\
@@ -862,7 +1648,7 @@ impl EvalContext {
             // For now, we need to look for and escape any reserved words. This should probably in
             // theory be done in rust analyzer in a less hacky way.
             let type_name = replace_reserved_words_in_type(&type_name);
-            state
+            let variable_state = state
                 .variable_states
                 .entry(variable_name)
                 .or_insert_with(|| VariableState {
@@ -870,8 +1656,10 @@ impl EvalContext {
                     is_mut: is_mutable,
                     move_state: VariableMoveState::New,
                     definition_span: None,
-                })
-                .type_name = type_name;
+                    needs_boxing: false,
+                });
+            variable_state.type_name = type_name;
+            variable_state.needs_boxing = needs_boxing;
         }
         Ok(())
     }
@@ -882,92 +1670,218 @@ impl EvalContext {
         so_file: &SoFile,
         callbacks: &mut EvalCallbacks,
     ) -> Result<EvalOutputs, Error> {
-        let mut output = EvalOutputs::new();
         // TODO: We should probably send an OsString not a String. Otherwise
         // things won't work if the path isn't UTF-8 - apparently that's a thing
         // on some platforms.
         let fn_name = state.current_user_fn_name();
+        let build_num = state.build_num;
+        let generation = self.child_process.generation();
         self.child_process.send(&format!(
-            "LOAD_AND_RUN {} {}",
+            "LOAD_AND_RUN {} {} {}",
             so_file.path.to_string_lossy(),
             fn_name,
+            build_num,
         ))?;
 
         state.build_num += 1;
 
+        let watchdog = state
+            .config
+            .timeout
+            .map(|timeout| Watchdog::start(timeout, self.child_process.process_handle()));
+        let result = self
+            .capture_output(state, callbacks, watchdog.as_ref())
+            .map(|mut output| {
+                output.build_num = Some(build_num);
+                output.generation = Some(generation);
+                output
+            });
+        if let Some(mut watchdog) = watchdog
+            && watchdog.stop()
+        {
+            return Err(Error::Timeout {
+                cell: self.cell_number,
+                timeout: watchdog.timeout,
+                lost_variables: Vec::new(),
+            });
+        }
+        result
+    }
+
+    /// Obtains input from the user and sends it to the subprocess for the user's code to read. Time
+    /// spent waiting for the user doesn't count towards `watchdog`'s timeout.
+    fn request_input(
+        &mut self,
+        request: InputRequest,
+        callbacks: &mut EvalCallbacks,
+        watchdog: Option<&Watchdog>,
+    ) -> Result<(), Error> {
+        // Anything that was printed before the request may provide context for it.
+        self.wait_for_stdout_to_be_consumed();
+        if let Some(watchdog) = watchdog {
+            watchdog.pause();
+        }
+        let input = (callbacks.input_reader)(request);
+        if let Some(watchdog) = watchdog {
+            watchdog.resume();
+        }
+        self.child_process.send_input(&input)
+    }
+
+    /// Processes output from the subprocess until the user code that it's running completes.
+    fn capture_output(
+        &mut self,
+        state: &mut ContextState,
+        callbacks: &mut EvalCallbacks,
+        watchdog: Option<&Watchdog>,
+    ) -> Result<EvalOutputs, Error> {
+        let mut output = EvalOutputs::new();
         let mut got_panic = false;
+        let mut got_result = false;
         let mut lost_variables = Vec::new();
-        static MIME_OUTPUT: Lazy<Regex> =
-            Lazy::new(|| Regex::new("EVCXR_BEGIN_CONTENT ([^ ]+)").unwrap());
+        // Content received since the start of a display that has yet to end.
+        let mut started_display: Option<DisplayBundle> = None;
+        static MIME_OUTPUT: Lazy<Regex> = Lazy::new(|| {
+            Regex::new("EVCXR_BEGIN_CONTENT ([^ ]+)(?: (display_id|update_display_id)=(.+))?")
+                .unwrap()
+        });
         loop {
-            let line = self.child_process.recv_line()?;
+            let (line, content) = match self.child_process.recv()? {
+                ChildOutput::Stdout(line) => {
+                    // Note, errors sending are ignored, since it just means the
+                    // user of the library has dropped the Receiver.
+                    let _ = self.stdout_sender.send(line);
+                    continue;
+                }
+                ChildOutput::Control(message) => (message.header, message.payload),
+            };
+            let (line, is_bytes) = match strip_bytes_attribute(&line) {
+                Some(header) => (header, true),
+                None => (line, false),
+            };
             if line == runtime::EVCXR_EXECUTION_COMPLETE {
                 break;
             }
             if line == PANIC_NOTIFICATION {
                 got_panic = true;
+            } else if line == RESULT_NOTIFICATION {
+                got_result = true;
+            } else if line == DISPLAY_START {
+                started_display = Some(DisplayBundle::default());
+            } else if line == DISPLAY_END {
+                if let Some(display) = started_display.take() {
+                    self.send_display(display, &mut output, callbacks);
+                }
             } else if line.starts_with(evcxr_input::GET_CMD) {
                 let is_password = line.starts_with(evcxr_input::GET_CMD_PASSWORD);
                 let prompt = line.split(':').nth(1).unwrap_or_default().to_owned();
-                self.child_process
-                    .send(&(callbacks.input_reader)(InputRequest {
+                // The subprocess won't also report the read that follows, so this is the only
+                // time we're asked.
+                self.request_input(
+                    InputRequest {
                         prompt,
                         is_password,
-                    }))?;
+                    },
+                    callbacks,
+                    watchdog,
+                )?;
+            } else if line == user_stdin::STDIN_READ {
+                self.request_input(
+                    InputRequest {
+                        prompt: String::from_utf8_lossy(&content).into_owned(),
+                        is_password: false,
+                    },
+                    callbacks,
+                    watchdog,
+                )?;
             } else if line == evcxr_internal_runtime::USER_ERROR_OCCURRED {
                 // A question mark operator in user code triggered an early
                 // return. Any newly defined variables won't have been stored.
-                state
-                    .variable_states
-                    .retain(|_variable_name, variable_state| {
-                        variable_state.move_state != VariableMoveState::New
-                    });
+                state.lose_new_variables(LossReason::EarlyReturn, self.cell_number);
             } else if let Some(variable_name) =
                 line.strip_prefix(evcxr_internal_runtime::VARIABLE_CHANGED_TYPE)
             {
                 lost_variables.push(variable_name.to_owned());
             } else if let Some(captures) = MIME_OUTPUT.captures(&line) {
                 let mime_type = captures[1].to_owned();
-                let mut content = String::new();
-                loop {
-                    let line = self.child_process.recv_line()?;
-                    if line == "EVCXR_END_CONTENT" {
-                        break;
+                let display_id = captures.get(3).map(|m| m.as_str().to_owned());
+                let update = captures.get(2).map(|m| m.as_str()) == Some("update_display_id");
+                let content = self.content_as_text(&mime_type, is_bytes, content);
+                // Content with a display ID can be updated later, so is always sent as a
+                // display, even if it's the result of the cell.
+                if got_result && display_id.is_none() {
+                    output.content_by_mime_type.insert(mime_type, content);
+                } else if let Some(display) = &mut started_display {
+                    display.content_by_mime_type.insert(mime_type, content);
+                    if display_id.is_some() {
+                        display.display_id = display_id;
+                        display.update = update;
                     }
-                    if line == PANIC_NOTIFICATION {
-                        got_panic = true;
-                        break;
-                    }
-                    if !content.is_empty() {
-                        content.push('\n');
-                    }
-                    content.push_str(&line);
+                } else {
+                    let display = DisplayBundle {
+                        content_by_mime_type: HashMap::from([(mime_type, content)]),
+                        display_id,
+                        update,
+                    };
+                    self.send_display(display, &mut output, callbacks);
                 }
-                output.content_by_mime_type.insert(mime_type, content);
-            } else {
-                // Note, errors sending are ignored, since it just means the
-                // user of the library has dropped the Receiver.
-                let _ = self.stdout_sender.send(line);
             }
         }
+        // e.g. if the code panicked part way through a display.
+        if let Some(display) = started_display {
+            self.send_display(display, &mut output, callbacks);
+        }
         if got_panic {
-            state
-                .variable_states
-                .retain(|_variable_name, variable_state| {
-                    variable_state.move_state != VariableMoveState::New
-                });
+            state.lose_new_variables(LossReason::Panic, self.cell_number);
         } else if !lost_variables.is_empty() {
             return Err(Error::TypeRedefinedVariablesLost(lost_variables));
         }
 
-        // Block until stdout_sender is empty with exponential backoff
+        self.wait_for_stdout_to_be_consumed();
+
+        Ok(output)
+    }
+
+    /// Returns content that the subprocess sent as text. Binary content of a binary mime type, e.g.
+    /// an image, is base64 encoded, which is what frontends expect. Text that isn't valid UTF-8 is
+    /// converted lossily, with a warning.
+    fn content_as_text(&self, mime_type: &str, is_bytes: bool, content: Vec<u8>) -> String {
+        use base64::Engine as _;
+        if is_bytes && !is_text_mime_type(mime_type) {
+            return base64::engine::general_purpose::STANDARD.encode(content);
+        }
+        String::from_utf8(content).unwrap_or_else(|error| {
+            let _ = self.stderr_sender.send(format!(
+                "Warning: Content of type {mime_type} wasn't valid UTF-8: {}",
+                error.utf8_error()
+            ));
+            String::from_utf8_lossy(error.as_bytes()).into_owned()
+        })
+    }
+
+    /// Passes `display` to the display callback as soon as any preceding stdout has been consumed,
+    /// then adds it to `output`. Displays without any content are dropped.
+    fn send_display(
+        &self,
+        display: DisplayBundle,
+        output: &mut EvalOutputs,
+        callbacks: &mut EvalCallbacks,
+    ) {
+        if display.content_by_mime_type.is_empty() {
+            return;
+        }
+        self.wait_for_stdout_to_be_consumed();
+        (callbacks.display)(&display);
+        output.displays.push(display);
+    }
+
+    /// Blocks until stdout_sender is empty with exponential backoff.
+    fn wait_for_stdout_to_be_consumed(&self) {
         let mut sleep_duration = Duration::from_millis(1);
         while !self.stdout_sender.is_empty() {
             std::thread::sleep(sleep_duration);
             sleep_duration = std::cmp::min(sleep_duration * 2, Duration::from_millis(100));
         }
-
-        Ok(output)
     }
 
     fn attempt_to_fix_error(
@@ -1010,16 +1924,14 @@ impl EvalContext {
                 }
                 CodeKind::WithFallback(fallback) => {
                     user_code.apply_fallback(fallback);
+                    state.final_expression_formatted = true;
                     fixed_errors.insert("Fallback");
                 }
                 CodeKind::OriginalUserCode(_) | CodeKind::OtherUserCode => {
                     if error.code() == Some("E0728") && !state.async_mode {
                         state.async_mode = true;
-                        if !state.external_deps.contains_key("tokio") {
-                            state.add_dep(
-                                "tokio",
-                                "{version=\"1.34.0\", features=[\"rt\", \"rt-multi-thread\"]}",
-                            )?;
+                        if !state.external_deps.contains_key(TOKIO_DEP.0) {
+                            state.add_dep(TOKIO_DEP.0, TOKIO_DEP.1)?;
                             // Rewrite Cargo.toml, since the dependency will probably have been
                             // validated in the process of being added, which will have overwritten
                             // Cargo.toml
@@ -1051,6 +1963,26 @@ impl EvalContext {
     }
 }
 
+/// Returns a module containing the code that's shared between the code that we compile and evcxr.
+fn internal_runtime_code() -> CodeBlock {
+    CodeBlock::new()
+        .generated("mod evcxr_internal_runtime {")
+        .generated(include_str!("evcxr_internal_runtime.rs"))
+        .generated("}")
+}
+
+/// Returns whether content of `mime_type` is text, as opposed to a binary format like image/png.
+fn is_text_mime_type(mime_type: &str) -> bool {
+    let essence = mime_type.split(';').next().unwrap_or_default().trim();
+    essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+        || matches!(
+            essence,
+            "application/json" | "application/javascript" | "application/xml"
+        )
+}
+
 fn non_persistable_type_error(variable_name: &str, actual_type: &str) -> Result<(), Error> {
     bail!(
         "The variable `{}` has type `{}` which cannot be persisted.\n\
@@ -1063,6 +1995,23 @@ fn non_persistable_type_error(variable_name: &str, actual_type: &str) -> Result<
     );
 }
 
+/// Returns a valid Cargo package name derived from `dir_name`, or an empty string if there's nothing
+/// usable.
+fn exported_crate_name(dir_name: &str) -> String {
+    let name: String = dir_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    name.trim_start_matches(|c: char| c.is_ascii_digit() || c == '-' || c == '_')
+        .to_owned()
+}
+
 fn fix_path() {
     // If cargo isn't on our path, see if it exists in the same directory as
     // our executable and if it does, add that directory to our PATH.
@@ -1092,6 +2041,48 @@ fn type_is_fully_specified(ty: &ast::Type) -> bool {
         .any(|n| n.kind() == SyntaxKind::INFER_TYPE)
 }
 
+/// If `impl_item` is of the form `impl From<Source> for Target`, returns `Source` and `Target`.
+fn from_impl_types(impl_item: &ast::Impl) -> Option<(String, String)> {
+    let ast::Type::PathType(trait_type) = impl_item.trait_()? else {
+        return None;
+    };
+    let segment = trait_type.path()?.segment()?;
+    if segment.name_ref()?.text() != "From" {
+        return None;
+    }
+    let mut args = ast::HasGenericArgs::generic_arg_list(&segment)?.generic_args();
+    let (Some(ast::GenericArg::TypeArg(source)), None) = (args.next(), args.next()) else {
+        return None;
+    };
+    Some((
+        source.ty()?.syntax().text().to_string(),
+        impl_item.self_ty()?.syntax().text().to_string(),
+    ))
+}
+
+/// What `EvalContext::forget` removed.
+#[derive(Debug)]
+pub(crate) struct Forgotten {
+    /// Items that referred to the forgotten item, directly or indirectly.
+    pub(crate) dependent_items: Vec<String>,
+    /// Variables whose types were forgotten, or the forgotten variable itself.
+    pub(crate) variables: Vec<String>,
+}
+
+/// Information about a variable, as returned by `CommandContext::variable_details`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VariableDetails {
+    pub name: String,
+    pub type_name: String,
+    /// The variable's `Debug` output, truncated. `None` if the type doesn't implement `Debug`.
+    pub preview: Option<String>,
+    /// The result of `std::mem::size_of_val` on the variable. `None` if the subprocess didn't
+    /// report the variable.
+    pub size: Option<usize>,
+    /// The (1-based) number of the cell in the history that defined the variable, if known.
+    pub defining_cell: Option<usize>,
+}
+
 #[derive(Debug)]
 pub struct PhaseDetails {
     pub name: String,
@@ -1121,19 +2112,43 @@ impl PhaseDetailsBuilder {
     }
 }
 
+/// A piece of rich content displayed by user code before the end of a cell, e.g. one of several
+/// images.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct DisplayBundle {
+    pub content_by_mime_type: HashMap<String, String>,
+    /// An ID that the user's code gave to the content, so that it can be updated later.
+    pub display_id: Option<String>,
+    /// Whether this content should replace earlier content with the same `display_id`.
+    pub update: bool,
+}
+
 #[derive(Default, Debug)]
 pub struct EvalOutputs {
+    /// The result of the cell, if it ended with an expression.
     pub content_by_mime_type: HashMap<String, String>,
+    /// Content displayed while the cell was running, in the order it was displayed.
+    pub displays: Vec<DisplayBundle>,
     pub timing: Option<Duration>,
     pub phases: Vec<PhaseDetails>,
+    /// The build number of the code that was run, if any. Output that the code prints after it
+    /// completes is sent to `EvalContextOutputs::late_stdout`, tagged with this and `generation`.
+    /// Build numbers start again when the context is cleared and aren't shared between sessions.
+    pub build_num: Option<i32>,
+    /// Identifies the subprocess that ran the code, if any. Differs between sessions and changes
+    /// each time the subprocess restarts, e.g. when the context is cleared.
+    pub generation: Option<u64>,
 }
 
 impl EvalOutputs {
     pub fn new() -> EvalOutputs {
         EvalOutputs {
             content_by_mime_type: HashMap::new(),
+            displays: Vec::new(),
             timing: None,
             phases: Vec::new(),
+            build_num: None,
+            generation: None,
         }
     }
 
@@ -1161,11 +2176,14 @@ impl EvalOutputs {
                 .or_default()
                 .push_str(&content);
         }
+        self.displays.append(&mut other.displays);
         self.timing = match (self.timing.take(), other.timing) {
             (Some(t1), Some(t2)) => Some(t1 + t2),
             (t1, t2) => t1.or(t2),
         };
         self.phases.append(&mut other.phases);
+        self.build_num = other.build_num.or(self.build_num);
+        self.generation = other.generation.or(self.generation);
     }
 }
 
@@ -1175,6 +2193,9 @@ struct VariableState {
     is_mut: bool,
     move_state: VariableMoveState,
     definition_span: Option<UserCodeSpan>,
+    /// Whether the variable's value needs to be boxed in order to be stored as `type_name`. This
+    /// is the case for closures and `impl Trait` values, but only in the cell that defines them.
+    needs_boxing: bool,
 }
 
 #[derive(Clone, Debug)]
@@ -1183,6 +2204,37 @@ struct UserCodeSpan {
     range: TextRange,
 }
 
+#[derive(Clone, Debug)]
+struct LostVariable {
+    /// The number of the cell in which the variable was lost, if it was lost while running a cell.
+    cell: Option<usize>,
+    reason: LossReason,
+}
+
+/// Why a variable was lost.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub(crate) enum LossReason {
+    Panic,
+    EarlyReturn,
+    TypeRedefined,
+    SubprocessTerminated,
+    Timeout,
+    Restarted,
+}
+
+impl LossReason {
+    fn description(self) -> &'static str {
+        match self {
+            LossReason::Panic => "the cell panicked",
+            LossReason::EarlyReturn => "the cell returned an error via `?`",
+            LossReason::TypeRedefined => "its type was redefined",
+            LossReason::SubprocessTerminated => "the subprocess terminated",
+            LossReason::Timeout => "the cell timed out",
+            LossReason::Restarted => "the subprocess was restarted",
+        }
+    }
+}
+
 #[derive(PartialEq, Eq, Debug, Copy, Clone)]
 enum VariableMoveState {
     New,
@@ -1221,10 +2273,25 @@ pub struct ContextState {
     /// code was executed. Doesn't include newly defined variables until after
     /// execution completes.
     stored_variable_states: HashMap<String, VariableState>,
+    /// Variables that were lost other than by being moved, so that we can explain what happened
+    /// to them if they're referred to. Removed once a variable of the same name is defined.
+    lost_variables: HashMap<String, LostVariable>,
+    /// For each `impl From<Source> for Type` in the code most recently passed to `apply`, maps the
+    /// name of the type to the name of the source type. Used to migrate variables whose type the
+    /// code redefines.
+    migration_sources: HashMap<String, String>,
     attributes: HashMap<String, CodeBlock>,
     async_mode: bool,
     allow_question_mark: bool,
     build_num: i32,
+    /// Names defined and referenced by the code most recently passed to `apply`.
+    cell_names: CellNames,
+    /// Whether the final expression of the code most recently passed to `apply` is displayed by
+    /// formatting it, since it didn't have an `evcxr_display` method.
+    final_expression_formatted: bool,
+    /// The code run by each background job, keyed by job id. May include jobs whose handles have
+    /// since been lost.
+    jobs: BTreeMap<usize, String>,
     pub(crate) config: Config,
 }
 
@@ -1237,10 +2304,15 @@ impl ContextState {
             extern_crate_stmts: HashMap::new(),
             variable_states: HashMap::new(),
             stored_variable_states: HashMap::new(),
+            lost_variables: HashMap::new(),
+            migration_sources: HashMap::new(),
             attributes: HashMap::new(),
             async_mode: false,
             allow_question_mark: false,
             build_num: 0,
+            cell_names: CellNames::default(),
+            final_expression_formatted: false,
+            jobs: BTreeMap::new(),
             config,
         }
     }
@@ -1318,20 +2390,80 @@ impl ContextState {
         };
     }
 
-    pub fn codegen_backend(&mut self) -> &str {
-        self.config.codegen_backend.as_deref().unwrap_or("default")
+    pub fn codegen_backend(&mut self) -> &str {
+        self.config.codegen_backend.as_deref().unwrap_or("default")
+    }
+
+    pub fn preserve_vars_on_panic(&self) -> bool {
+        self.config.preserve_vars_on_panic
+    }
+
+    pub fn offline_mode(&self) -> bool {
+        self.config.offline_mode
+    }
+
+    pub fn set_preserve_vars_on_panic(&mut self, value: bool) {
+        self.config.preserve_vars_on_panic = value;
+    }
+
+    pub(crate) fn reactive_mode(&self) -> ReactiveMode {
+        self.config.reactive
+    }
+
+    pub fn sandbox(&self) -> bool {
+        self.config.sandbox
+    }
+
+    pub(crate) fn limits(&self) -> ResourceLimits {
+        self.config.limits
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.config.timeout
+    }
+
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.config.timeout = timeout;
     }
 
-    pub fn preserve_vars_on_panic(&self) -> bool {
-        self.config.preserve_vars_on_panic
+    pub(crate) fn set_reactive_mode(&mut self, mode: ReactiveMode) {
+        self.config.reactive = mode;
     }
 
-    pub fn offline_mode(&self) -> bool {
-        self.config.offline_mode
+    pub fn checkpoint_vars(&self) -> bool {
+        self.config.checkpoint_vars
     }
 
-    pub fn set_preserve_vars_on_panic(&mut self, value: bool) {
-        self.config.preserve_vars_on_panic = value;
+    /// Sets whether variables should be checkpointed. Enabling adds the dependencies needed to
+    /// serialize variables.
+    pub fn set_checkpoint_vars(&mut self, value: bool) -> Result<(), Error> {
+        if value {
+            for (name, config) in SERDE_DEPS {
+                if !self.external_deps.contains_key(*name) {
+                    self.add_dep(name, config)?;
+                }
+            }
+        }
+        self.config.checkpoint_vars = value;
+        Ok(())
+    }
+
+    pub fn migrate_vars(&self) -> bool {
+        self.config.migrate_vars
+    }
+
+    /// Sets whether variables should be migrated when their types are redefined. Enabling adds the
+    /// dependencies needed to serialize variables.
+    pub fn set_migrate_vars(&mut self, value: bool) -> Result<(), Error> {
+        if value {
+            for (name, config) in SERDE_DEPS {
+                if !self.external_deps.contains_key(*name) {
+                    self.add_dep(name, config)?;
+                }
+            }
+        }
+        self.config.migrate_vars = value;
+        Ok(())
     }
 
     pub fn debug_mode(&self) -> bool {
@@ -1342,6 +2474,12 @@ impl ContextState {
         self.config.debug_mode = debug_mode;
     }
 
+    /// Returns the edition that code in this session is compiled with. This is always the edition
+    /// that rust-analyzer parses code with.
+    pub(crate) fn edition(&self) -> ra_ap_ide::Edition {
+        crate::rust_analyzer::EDITION
+    }
+
     pub fn opt_level(&self) -> &str {
         &self.config.opt_level
     }
@@ -1399,6 +2537,9 @@ impl ContextState {
         {
             return Ok(());
         }
+        if self.sandbox() {
+            check_registry_dep(dep, dep_config)?;
+        }
         let external = ExternalCrate::new(dep.to_owned(), dep_config.to_owned())?;
         crate::cargo_metadata::validate_dep(&external.name, &external.config, &self.config)?;
         self.external_deps.insert(dep.to_owned(), external);
@@ -1407,10 +2548,383 @@ impl ContextState {
 
     /// Adds a crate dependency at the specified local path
     pub fn add_local_dep(&mut self, dep: &str) -> Result<(), Error> {
+        if self.sandbox() {
+            bail!("Only dependencies from crates.io are available when sandboxed");
+        }
         let name = cargo_metadata::parse_crate_name(dep)?;
         self.add_dep(&name, &format!("{{ path = \"{dep}\" }}"))
     }
 
+    /// Writes everything needed to recreate this state, with history `cells`, to `path`. See
+    /// `EvalContext::load_session`.
+    pub(crate) fn save_session(&self, cells: &[Cell], path: &Path) -> Result<(), Error> {
+        self.to_session_file(cells).write(path)
+    }
+
+    /// Writes a binary crate to `dir` that, when run, executes all of `cells` in order.
+    pub(crate) fn export_crate(&self, cells: &[Cell], dir: &Path) -> Result<(), Error> {
+        let cargo_toml_path = dir.join("Cargo.toml");
+        if cargo_toml_path.exists() {
+            bail!("'{}' already exists", cargo_toml_path.display());
+        }
+        let src_dir = dir.join("src");
+        if let Err(error) = std::fs::create_dir_all(&src_dir) {
+            bail!("Error creating '{}': {}", src_dir.display(), error);
+        }
+        let crate_name = dir
+            .file_name()
+            .map(|name| exported_crate_name(&name.to_string_lossy()))
+            .unwrap_or_default();
+        let crate_name = if crate_name.is_empty() {
+            "evcxr_export".to_owned()
+        } else {
+            crate_name
+        };
+        let cargo_toml = format!(
+            r#"[package]
+name = "{}"
+version = "0.1.0"
+edition = "{}"
+
+[dependencies]
+{}"#,
+            crate_name,
+            self.edition(),
+            self.exported_cargo_deps()
+        );
+        std::fs::write(&cargo_toml_path, cargo_toml)?;
+        std::fs::write(src_dir.join("main.rs"), self.exported_main_code(cells))?;
+        Ok(())
+    }
+
+    /// Writes a file named `tests/{name}.rs` in the crate at `crate_dir` containing a test that
+    /// runs all of `cells` in order, asserting that each final expression displays the same as it
+    /// did when it was evaluated.
+    pub(crate) fn export_test(
+        &self,
+        cells: &[Cell],
+        crate_dir: &Path,
+        name: &str,
+    ) -> Result<(), Error> {
+        if name.is_empty()
+            || name.starts_with(|c: char| c.is_ascii_digit())
+            || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            bail!("Test name must be a valid identifier, got '{}'", name);
+        }
+        if !crate_dir.join("Cargo.toml").exists() {
+            bail!("'{}' doesn't contain a Cargo.toml", crate_dir.display());
+        }
+        let tests_dir = crate_dir.join("tests");
+        let tests_dir = tests_dir.as_path();
+        let path = tests_dir.join(format!("{name}.rs"));
+        if path.exists() {
+            bail!("'{}' already exists", path.display());
+        }
+        if let Err(error) = std::fs::create_dir_all(tests_dir) {
+            bail!("Error creating '{}': {}", tests_dir.display(), error);
+        }
+        let mut code = String::new();
+        let deps = self.exported_cargo_deps();
+        if !deps.is_empty() {
+            code.push_str("// Requires the following dev-dependencies:\n");
+            for line in deps.lines() {
+                code.push_str(&format!("// {line}\n"));
+            }
+        }
+        let run = if self.async_mode {
+            "tokio::runtime::Runtime::new().unwrap().block_on(run())"
+        } else {
+            "run()"
+        };
+        code.push_str(
+            &self.exported_code(
+                cells,
+                Cell::test_statements,
+                CodeBlock::new()
+                    .generated("#[test]")
+                    .generated(format!("fn {name}() {{"))
+                    // EvcxrUserCodeError can't implement Debug, since its From impl would then
+                    // conflict with the blanket one. That impl has already printed the error.
+                    .generated(format!(
+                        "{run}.map_err(|_| \"the session's code returned an error\").unwrap();"
+                    ))
+                    .generated("}"),
+            ),
+        );
+        std::fs::write(&path, code)?;
+        Ok(())
+    }
+
+    fn exported_main_code(&self, cells: &[Cell]) -> String {
+        let run = if self.async_mode {
+            "tokio::runtime::Runtime::new().unwrap().block_on(run())"
+        } else {
+            "run()"
+        };
+        self.exported_code(
+            cells,
+            Cell::standalone_statements,
+            CodeBlock::new()
+                .generated("fn main() {")
+                .generated(format!("if {run}.is_err() {{"))
+                .generated("std::process::exit(1);")
+                .generated("}")
+                .generated("}"),
+        )
+    }
+
+    /// Returns code for use outside of Evcxr containing all our items and a function `run` that
+    /// executes `cells`, with each cell converted by `cell_code`. `entry_point` is expected to call
+    /// `run`.
+    fn exported_code(
+        &self,
+        cells: &[Cell],
+        cell_code: fn(&Cell, &str) -> String,
+        entry_point: CodeBlock,
+    ) -> String {
+        let mut attributes: Vec<_> = self
+            .attributes
+            .values()
+            .map(CodeBlock::code_string)
+            .collect();
+        attributes.sort();
+        let mut items: Vec<_> = self.items_by_name.iter().collect();
+        items.sort_by(|a, b| a.0.cmp(b.0));
+        let mut code = CodeBlock::new()
+            .generated("#![allow(unused_imports, unused_mut, dead_code)]")
+            .generated(attributes.join("\n"))
+            .add_all(self.get_imports());
+        for (_, item) in items {
+            code = code.add_all(item.clone());
+        }
+        for item in &self.unnamed_items {
+            code = code.add_all(item.clone());
+        }
+        code = code.add_all(self.error_trait_code(true));
+        code = code.generated(if self.async_mode {
+            "async fn run() -> Result<(), EvcxrUserCodeError> {"
+        } else {
+            "fn run() -> Result<(), EvcxrUserCodeError> {"
+        });
+        for (index, cell) in cells.iter().enumerate() {
+            code = code
+                .generated(format!("// Cell {}", index + 1))
+                .other_user_code(cell_code(cell, &self.config.output_format));
+        }
+        code = code.generated("Ok(())").generated("}").add_all(entry_point);
+        code.code_string()
+    }
+
+    fn to_session_file(&self, cells: &[Cell]) -> SessionFile {
+        SessionFile {
+            version: SessionFile::current_version(),
+            dependencies: self
+                .external_deps
+                .iter()
+                .map(|(key, krate)| {
+                    (
+                        key.clone(),
+                        SessionDependency {
+                            name: krate.name.clone(),
+                            config: krate.config.clone(),
+                        },
+                    )
+                })
+                .collect(),
+            extern_crate_stmts: self
+                .extern_crate_stmts
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            items: self
+                .items_by_name
+                .iter()
+                .map(|(name, block)| (name.clone(), block.code_string()))
+                .collect(),
+            unnamed_items: self
+                .unnamed_items
+                .iter()
+                .map(CodeBlock::code_string)
+                .collect(),
+            attributes: self
+                .attributes
+                .iter()
+                .map(|(key, block)| (key.clone(), block.code_string()))
+                .collect(),
+            async_mode: self.async_mode,
+            allow_question_mark: self.allow_question_mark,
+            error_format: self.error_format().to_owned(),
+            output_format: self.config.output_format.clone(),
+            display_types: self.config.display_types,
+            opt_level: self.config.opt_level.clone(),
+            preserve_vars_on_panic: self.config.preserve_vars_on_panic,
+            checkpoint_vars: self.config.checkpoint_vars,
+            migrate_vars: self.config.migrate_vars,
+            toolchain: self.config.toolchain.clone(),
+            build_envs: self
+                .config
+                .build_envs
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect::<BTreeMap<_, _>>(),
+            cells: cells.to_vec(),
+        }
+    }
+
+    /// Applies everything from `session` except for variables, which can only be restored by
+    /// running code.
+    fn apply_session_file(&mut self, session: &SessionFile) -> Result<(), Error> {
+        for (key, dep) in &session.dependencies {
+            self.external_deps.insert(
+                key.clone(),
+                ExternalCrate::new(dep.name.clone(), dep.config.clone())?,
+            );
+        }
+        self.extern_crate_stmts = session.extern_crate_stmts.clone().into_iter().collect();
+        self.items_by_name = session
+            .items
+            .iter()
+            .map(|(name, code)| (name.clone(), CodeBlock::new().other_user_code(code.clone())))
+            .collect();
+        self.unnamed_items = session
+            .unnamed_items
+            .iter()
+            .map(|code| CodeBlock::new().other_user_code(code.clone()))
+            .collect();
+        self.attributes = session
+            .attributes
+            .iter()
+            .map(|(key, code)| (key.clone(), CodeBlock::new().other_user_code(code.clone())))
+            .collect();
+        self.async_mode = session.async_mode;
+        self.allow_question_mark = session.allow_question_mark;
+        self.set_error_format(&session.error_format)?;
+        self.config.output_format.clone_from(&session.output_format);
+        self.config.display_types = session.display_types;
+        self.set_opt_level(&session.opt_level)?;
+        self.config.preserve_vars_on_panic = session.preserve_vars_on_panic;
+        self.config.checkpoint_vars = session.checkpoint_vars;
+        self.config.migrate_vars = session.migrate_vars;
+        if !session.toolchain.is_empty() {
+            self.set_toolchain(&session.toolchain)?;
+        }
+        for (key, value) in &session.build_envs {
+            self.set_build_env(key, value);
+        }
+        Ok(())
+    }
+
+    /// Removes the imports that the use statement `use_code` would add. The leading `use` and
+    /// trailing semicolon are optional.
+    pub fn unuse(&mut self, use_code: &str) -> Result<(), Error> {
+        let path = use_code.trim().trim_end_matches(';');
+        let path = path.strip_prefix("use ").unwrap_or(path).trim();
+        let imports = crate::use_trees::use_tree_names(&format!("use {path};"));
+        if imports.is_empty() {
+            bail!("Invalid use statement `{}`", use_code);
+        }
+        let mut removed = false;
+        for import in imports {
+            match import {
+                Import::Named { name, code } => {
+                    if self
+                        .items_by_name
+                        .get(&name)
+                        .is_some_and(|block| block.code_string().trim() == code)
+                    {
+                        self.items_by_name.remove(&name);
+                        removed = true;
+                    }
+                }
+                Import::Unnamed(code) => {
+                    let num_before = self.unnamed_items.len();
+                    self.unnamed_items
+                        .retain(|block| block.code_string().trim() != code);
+                    removed |= self.unnamed_items.len() != num_before;
+                }
+            }
+        }
+        if !removed {
+            bail!("No matching use statement for `{}`", path);
+        }
+        Ok(())
+    }
+
+    /// Records that a background job is about to be started to run `code`, returning its id. Ids of
+    /// jobs whose handles no longer exist are reused.
+    pub(crate) fn add_job(&mut self, code: &str) -> usize {
+        self.jobs
+            .retain(|id, _| self.variable_states.contains_key(&job_variable_name(*id)));
+        let mut id = 1;
+        while self.variable_states.contains_key(&job_variable_name(id)) {
+            id += 1;
+        }
+        self.jobs.insert(id, code.trim().to_owned());
+        id
+    }
+
+    /// Returns the ids of jobs that haven't been waited for, in order, together with their code if
+    /// known.
+    pub(crate) fn jobs(&self) -> Vec<(usize, Option<&str>)> {
+        let mut jobs: Vec<(usize, Option<&str>)> = self
+            .variable_states
+            .keys()
+            .filter_map(|name| name.strip_prefix(JOB_VARIABLE_PREFIX)?.parse().ok())
+            .map(|id| (id, self.jobs.get(&id).map(String::as_str)))
+            .collect();
+        jobs.sort();
+        jobs
+    }
+
+    /// Stops tracking the job with the supplied id. Returns code that waits for the job to complete
+    /// and evaluates to its result. If the job panicked, the panic is resumed.
+    pub(crate) fn take_job(&mut self, id: usize) -> Result<String, Error> {
+        let variable_name = job_variable_name(id);
+        if !self.variable_states.contains_key(&variable_name) {
+            bail!("No job with id {}", id);
+        }
+        self.jobs.remove(&id);
+        Ok(format!(
+            "{variable_name}.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic))"
+        ))
+    }
+
+    /// Returns the record of `user_code` having been successfully executed and having produced
+    /// `outputs`, or None if it didn't contain any Rust code.
+    fn take_cell(&mut self, user_code: &CodeBlock, outputs: &EvalOutputs) -> Option<Cell> {
+        let code: String = user_code
+            .segments
+            .iter()
+            .filter(|segment| matches!(segment.kind, CodeKind::OriginalUserCode(_)))
+            .map(|segment| segment.code.as_str())
+            .collect();
+        if code.trim().is_empty() {
+            return None;
+        }
+        let mut defined_variables: Vec<String> = self
+            .variable_states
+            .iter()
+            .filter(|(_, state)| state.move_state == VariableMoveState::New)
+            .map(|(name, _)| name.clone())
+            .collect();
+        defined_variables.sort();
+        // When types are being displayed, the output includes the type name, so isn't something we
+        // could reproduce outside of Evcxr.
+        let output = if self.config.display_types {
+            None
+        } else {
+            outputs.get("text/plain").map(str::to_owned)
+        };
+        Some(Cell {
+            code,
+            defined_variables,
+            output,
+            evcxr_display: !self.final_expression_formatted,
+            names: std::mem::take(&mut self.cell_names),
+        })
+    }
+
     /// Clears fields that aren't useful for inclusion in bug reports and which might give away
     /// things like usernames.
     pub(crate) fn clear_non_debug_relevant_fields(&mut self) {
@@ -1436,12 +2950,50 @@ impl ContextState {
             .collect()
     }
 
+    fn record_lost_variable(&mut self, name: &str, cell: Option<usize>, reason: LossReason) {
+        self.lost_variables
+            .insert(name.to_owned(), LostVariable { cell, reason });
+    }
+
+    /// Removes variables defined by the cell that's running, which is numbered `cell`, recording
+    /// that they were lost.
+    fn lose_new_variables(&mut self, reason: LossReason, cell: usize) {
+        let cell = Some(cell);
+        let lost: Vec<String> = self
+            .variable_states
+            .iter()
+            .filter(|(_, state)| state.move_state == VariableMoveState::New)
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in lost {
+            self.variable_states.remove(&name);
+            self.record_lost_variable(&name, cell, reason);
+        }
+    }
+
     /// Customizes errors based on their origins.
     fn customize_error(
         &self,
         error: CompilationError,
         user_code: &CodeBlock,
     ) -> Option<CompilationError> {
+        if error.code() == Some("E0425")
+            && let Some(name) = error
+                .message()
+                .strip_prefix("cannot find value `")
+                .and_then(|rest| rest.split_once('`'))
+                .map(|(name, _)| name)
+            && let Some(lost) = self.lost_variables.get(name)
+        {
+            let message = match lost.cell {
+                Some(cell) => format!(
+                    "`{name}` was lost in cell {cell} because {}",
+                    lost.reason.description()
+                ),
+                None => format!("`{name}` was lost because {}", lost.reason.description()),
+            };
+            return Some(error.with_replacement_message(message));
+        }
         for origin in &error.code_origins {
             if let CodeKind::PackVariable { variable_name } = origin
                 && let Some(variable_state) = self.variable_states.get(variable_name)
@@ -1514,6 +3066,16 @@ impl ContextState {
             .join("")
     }
 
+    /// Returns our dependencies formatted for the Cargo.toml of an exported crate. In async mode,
+    /// this includes tokio if it wasn't added explicitly, since exported code uses it to run.
+    fn exported_cargo_deps(&self) -> String {
+        let mut deps = self.format_cargo_deps();
+        if self.async_mode && !self.external_deps.contains_key(TOKIO_DEP.0) {
+            deps.push_str(&format!("{} = {}\n", TOKIO_DEP.0, TOKIO_DEP.1));
+        }
+        deps
+    }
+
     fn compilation_mode(&self) -> CompilationMode {
         if self.config.preserve_vars_on_panic {
             CompilationMode::RunAndCatchPanics
@@ -1529,6 +3091,7 @@ impl ContextState {
             .add_all(self.attributes_code())
             .add_all(self.items_code())
             .add_all(self.error_trait_code(true))
+            .add_all(internal_runtime_code())
             .generated("fn evcxr_variable_store<T: 'static>(_: T) {}")
             .generated("#[allow(unused_variables)]")
             .generated("async fn evcxr_analysis_wrapper(");
@@ -1566,7 +3129,8 @@ impl ContextState {
         let mut code = CodeBlock::new()
             .generated("#![allow(unused_imports, unused_mut, dead_code)]")
             .add_all(self.attributes_code())
-            .add_all(self.items_code());
+            .add_all(self.items_code())
+            .add_all(internal_runtime_code());
         let has_user_code = !user_code.is_empty();
         if has_user_code {
             code = code.add_all(self.wrap_user_code(user_code, compilation_mode));
@@ -1616,7 +3180,7 @@ impl ContextState {
             if for_analysis {
                 ""
             } else {
-                "println!(\"{}\", evcxr_internal_runtime::USER_ERROR_OCCURRED);"
+                "evcxr_internal_runtime::send_control(evcxr_internal_runtime::USER_ERROR_OCCURRED, \"\");"
             }
         ))
     }
@@ -1636,9 +3200,23 @@ impl ContextState {
         }
         if needs_variable_store {
             code = code
-                .generated("mod evcxr_internal_runtime {")
-                .generated(include_str!("evcxr_internal_runtime.rs"))
-                .generated("}");
+                .generated("#[unsafe(no_mangle)]")
+                .generated(format!(
+                    "pub extern \"C\" fn {}(store: *const evcxr_internal_runtime::VariableStore) {{",
+                    runtime::VARIABLE_DETAILS_FN
+                ))
+                .generated("if let Some(store) = unsafe { store.as_ref() } {")
+                .generated("store.send_variable_details();")
+                .generated("}}");
+            if self.config.checkpoint_vars {
+                code = code.generated(CHECKPOINT_DEF.replace(
+                    "EVCXR_CHECKPOINT_DIR",
+                    &format!("{:?}", self.config.checkpoint_dir()),
+                ));
+            }
+            if self.config.migrate_vars {
+                code = code.generated(MIGRATION_DEF);
+            }
         }
         code = code.generated("#[unsafe(no_mangle)]").generated(format!(
             "pub extern \"C\" fn {}(",
@@ -1697,14 +3275,18 @@ impl ContextState {
                     .generated("})) { ")
                     .generated("  Ok(_) => {}")
                     .generated("  Err(_) => {")
-                    .generated(format!("    println!(\"{PANIC_NOTIFICATION}\");"))
+                    .generated(format!(
+                        "    evcxr_internal_runtime::send_control(\"{PANIC_NOTIFICATION}\", \"\");"
+                    ))
                     .generated("}}");
             } else {
                 code = code
                     .generated("if std::panic::catch_unwind(||{")
                     .add_all(user_code)
                     .generated("}).is_err() {")
-                    .generated(format!("    println!(\"{PANIC_NOTIFICATION}\");"))
+                    .generated(format!(
+                        "    evcxr_internal_runtime::send_control(\"{PANIC_NOTIFICATION}\", \"\");"
+                    ))
                     .generated("}");
             }
         } else {
@@ -1713,6 +3295,8 @@ impl ContextState {
         if needs_variable_store {
             code = code.add_all(self.store_variable_statements(VariableMoveState::Available));
         }
+        // Anything printed without a trailing newline would otherwise stay in our buffer.
+        code = code.generated("{ use std::io::Write; let _ = std::io::stdout().flush(); }");
         code = code.generated("evcxr_variable_store");
         code.generated("}")
     }
@@ -1721,13 +3305,56 @@ impl ContextState {
         let mut statements = CodeBlock::new();
         for (var_name, var_state) in &self.variable_states {
             if var_state.move_state == move_state {
+                let checkpoint = if self.config.checkpoint_vars
+                    && self.checkpoint_may_be_stale(var_name, var_state)
+                {
+                    format!(
+                        "(&EvcxrCheckpoint(&{var_name}, stringify!({var_name}))).evcxr_checkpoint();"
+                    )
+                } else {
+                    String::new()
+                };
+                let serializer = if self.config.migrate_vars {
+                    format!(
+                        "evcxr_variable_store.set_serializer(stringify!({var_name}), |value| {{
+                            let value = value.downcast_ref::<{type_name}>()?;
+                            (&EvcxrMigration::<{type_name}>(std::marker::PhantomData)).evcxr_to_json(value)
+                        }});",
+                        type_name = var_state.type_name
+                    )
+                } else {
+                    String::new()
+                };
+                let value = if var_state.needs_boxing && move_state == VariableMoveState::New {
+                    format!("Box::new({var_name})")
+                } else {
+                    var_name.clone()
+                };
                 statements.pack_variable(
                     var_name.clone(),
                     format!(
                         // Note, we use stringify instead of quoting ourselves since it results in
                         // better errors if the user forgets to close a double-quote in their code.
-                        "evcxr_variable_store.put_variable::<{}>(stringify!({var_name}), {var_name});",
-                        var_state.type_name
+                        // Describers are called from an extern "C" function, where a panic, e.g.
+                        // from the user's Debug implementation, would abort the subprocess.
+                        "{checkpoint}evcxr_variable_store.put_variable::<{type_name}>(stringify!({var_name}), {value});
+                        evcxr_variable_store.set_describer(stringify!({var_name}), |value| {{
+                            use crate::evcxr_internal_runtime::DescribeDebug as _;
+                            use crate::evcxr_internal_runtime::DescribeFallback as _;
+                            let Some(value) = value.downcast_ref::<{type_name}>() else {{
+                                return (0, None);
+                            }};
+                            let preview = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {{
+                                {preview}
+                            }}));
+                            (std::mem::size_of_val(value), preview.ok().flatten())
+                        }});{serializer}",
+                        type_name = var_state.type_name,
+                        preview = if var_name.starts_with(JOB_VARIABLE_PREFIX) {
+                            "Some(if value.is_finished() { \"finished\" } else { \"running\" }.to_owned())"
+                        } else {
+                            "(&crate::evcxr_internal_runtime::Describe(value)).evcxr_preview()"
+                        }
                     ),
                 );
             }
@@ -1735,12 +3362,43 @@ impl ContextState {
         statements
     }
 
+    /// Returns whether the checkpoint of a variable may be out of date once the current cell has run.
+    /// Other than in the cell that defines it, a variable can only change in cells that refer to it,
+    /// whether or not it's `mut`, since it may have interior mutability. Rewriting checkpoints of
+    /// other variables would make each cell cost more the more variables there are.
+    fn checkpoint_may_be_stale(&self, var_name: &str, var_state: &VariableState) -> bool {
+        var_state.move_state == VariableMoveState::New
+            || self.cell_names.references.contains(var_name)
+            // e.g. if checkpointing was only just enabled.
+            || !self
+                .config
+                .checkpoint_dir()
+                .join(format!("{var_name}.json"))
+                .exists()
+    }
+
     fn check_variable_statements(&self) -> CodeBlock {
         let mut statements = CodeBlock::new().generated("{let mut vars_ok = true;");
         for (var_name, var_state) in &self.stored_variable_states {
+            let type_name = &var_state.type_name;
+            if !self.config.migrate_vars {
+                statements = statements.generated(format!(
+                    "vars_ok &= evcxr_variable_store.check_variable::<{type_name}>(stringify!({var_name}));"
+                ));
+                continue;
+            }
+            // If the user supplied a conversion from another type, we try deserializing as that
+            // type first, since that's presumably the shape of the old type.
+            let mut deserialize = format!(
+                "(&EvcxrMigration::<{type_name}>(std::marker::PhantomData)).evcxr_from_json(json)"
+            );
+            if let Some(source) = self.migration_sources.get(type_name) {
+                deserialize = format!(
+                    "(&EvcxrMigration::<{source}>(std::marker::PhantomData)).evcxr_from_json(json).map(<{type_name} as From<{source}>>::from).or_else(|| {deserialize})"
+                );
+            }
             statements = statements.generated(format!(
-                "vars_ok &= evcxr_variable_store.check_variable::<{}>(stringify!({var_name}));",
-                var_state.type_name
+                "vars_ok &= evcxr_variable_store.migrate_variable::<{type_name}>(stringify!({var_name}), |json| {deserialize});"
             ));
         }
         statements.generated("if !vars_ok {return evcxr_variable_store;}}")
@@ -1795,6 +3453,9 @@ impl ContextState {
             variable_state.move_state = VariableMoveState::Available;
         }
 
+        self.cell_names = CellNames::default();
+        self.final_expression_formatted = !self.config.display_final_expression;
+        self.migration_sources.clear();
         let mut code_out = CodeBlock::new();
         let mut previous_item_name = None;
         let num_statements = user_code.segments.len();
@@ -1805,7 +3466,20 @@ impl ContextState {
                 code_out = code_out.with_segment(segment);
                 continue;
             };
-            if let Some(let_stmt) = ast::LetStmt::cast(node.clone()) {
+            if let Some(name) = crate::statics::static_mut_name(node) {
+                bail!(
+                    "`static mut {}` isn't supported, since its value wouldn't persist between cells. \
+                    Use a static with interior mutability instead, e.g. an atomic or a Mutex.",
+                    name
+                );
+            }
+            self.record_cell_names(node, previous_item_name.as_deref());
+            if let Some(statics) = crate::statics::session_statics(node, &segment, self.build_num) {
+                for (name, code) in statics {
+                    self.items_by_name.insert(name.clone(), code);
+                    previous_item_name = Some(name);
+                }
+            } else if let Some(let_stmt) = ast::LetStmt::cast(node.clone()) {
                 if let Some(pat) = let_stmt.pat() {
                     self.record_new_locals(pat, let_stmt.ty(), &segment, node.text_range());
                     code_out = code_out.with_segment(segment);
@@ -1821,30 +3495,30 @@ impl ContextState {
                         code_out = code_out.code_with_fallback(
                             // First we try calling .evcxr_display().
                             CodeBlock::new()
-                                .generated("(")
+                                .generated("match &(")
                                 .with_segment(segment.clone())
-                                .generated(").evcxr_display();")
+                                .generated(format!(
+                                    ") {{ evcxr_result => {{ evcxr_internal_runtime::send_control(\"{RESULT_NOTIFICATION}\", \"\"); evcxr_result.evcxr_display(); }} }}"
+                                ))
                                 .code_string(),
                             // If that fails, we try debug format.
                             if self.config.display_types {
                                 CodeBlock::new()
-                                .generated(SEND_TEXT_PLAIN_DEF)
                                 .generated(GET_TYPE_NAME_DEF)
                                 .generated("{ let r = &(")
                                 .with_segment(segment)
                                 .generated(format!(
-                                    "); evcxr_send_text_plain(&format!(\": {{}} = {}\", evcxr_get_type_name(r), r)); }};",
+                                    "); evcxr_internal_runtime::send_control(\"{RESULT_NOTIFICATION}\", \"\"); evcxr_internal_runtime::send_control(\"EVCXR_BEGIN_CONTENT text/plain\", &format!(\": {{}} = {}\", evcxr_get_type_name(r), r)); }};",
                                     self.config.output_format
                                 ))
                             } else {
                                 CodeBlock::new()
-                                .generated(SEND_TEXT_PLAIN_DEF)
+                                .generated("match &(\n")
+                                .with_segment(segment)
                                 .generated(format!(
-                                    "evcxr_send_text_plain(&format!(\"{}\",&(\n",
+                                    ") {{ r => {{ evcxr_internal_runtime::send_control(\"{RESULT_NOTIFICATION}\", \"\"); evcxr_internal_runtime::send_control(\"EVCXR_BEGIN_CONTENT text/plain\", &format!(\"{}\", r)); }} }}",
                                     self.config.output_format
                                 ))
-                                .with_segment(segment)
-                                .generated(")));")
                                 },
                             );
                     } else {
@@ -1931,6 +3605,11 @@ impl ContextState {
                         }
                     }
                     item => {
+                        if let ast::Item::Impl(impl_item) = &item
+                            && let Some((source, target)) = from_impl_types(impl_item)
+                        {
+                            self.migration_sources.insert(target, source);
+                        }
                         let item_block = CodeBlock::new().with_segment(segment);
                         if let Some(item_name) = item::item_name(&item) {
                             *self.items_by_name.entry(item_name.to_owned()).or_default() =
@@ -1957,6 +3636,34 @@ impl ContextState {
         Ok(code_out)
     }
 
+    /// Records the names that `node` defines and refers to. `previous_item_name` is the item that
+    /// unnamed items such as impls get associated with.
+    fn record_cell_names(&mut self, node: &SyntaxNode, previous_item_name: Option<&str>) {
+        let Some(item) = ast::Item::cast(node.clone()) else {
+            if let Some(names) = crate::statics::thread_local_names(node) {
+                for name in names {
+                    self.cell_names.add_item(&name, node);
+                }
+            } else if !ast::Attr::can_cast(node.kind()) {
+                self.cell_names.add_statement(node);
+            }
+            return;
+        };
+        if let ast::Item::Use(use_stmt) = &item {
+            if let Some(use_tree) = use_stmt.use_tree() {
+                crate::use_trees::use_tree_names_do(&use_tree, &mut |import| {
+                    if let Import::Named { name, .. } = import {
+                        self.cell_names.add_item(&name, node);
+                    }
+                });
+            }
+        } else if let Some(name) = item::item_name(&item) {
+            self.cell_names.add_item(&name, node);
+        } else if let (ast::Item::Impl(_), Some(name)) = (&item, previous_item_name) {
+            self.cell_names.add_item(name, node);
+        }
+    }
+
     fn dependency_lib_names(&self) -> Result<Vec<String>> {
         cargo_metadata::get_library_names(&self.config)
     }
@@ -2020,6 +3727,7 @@ impl ContextState {
                     // All new locals will initially be defined only inside our catch_unwind
                     // block.
                     move_state: VariableMoveState::New,
+                    needs_boxing: false,
                     definition_span: segment.sequence.map(|segment_index| {
                         let range = name.syntax().text_range() - let_stmt_range.start();
                         UserCodeSpan {
@@ -2157,6 +3865,120 @@ fn std_lib_path(rustc: &Path) -> Result<PathBuf> {
     anyhow::bail!("No libstd found in {libdir}");
 }
 
+/// Kills the subprocess if it's still running user code once a timeout elapses. Time during which
+/// the watchdog is paused doesn't count.
+struct Watchdog {
+    timeout: Duration,
+    control: Option<crossbeam_channel::Sender<WatchdogMessage>>,
+    thread: Option<std::thread::JoinHandle<()>>,
+    fired: Arc<AtomicBool>,
+}
+
+enum WatchdogMessage {
+    Pause,
+    Resume,
+}
+
+impl Watchdog {
+    fn start(timeout: Duration, process: Arc<Mutex<std::process::Child>>) -> Watchdog {
+        let (control_sender, control_receiver) = crossbeam_channel::unbounded();
+        let fired = Arc::new(AtomicBool::new(false));
+        let thread = std::thread::spawn({
+            let fired = Arc::clone(&fired);
+            move || {
+                let mut remaining = timeout;
+                loop {
+                    let started = Instant::now();
+                    match control_receiver.recv_timeout(remaining) {
+                        Ok(message) => {
+                            remaining = remaining.saturating_sub(started.elapsed());
+                            if let WatchdogMessage::Pause = message {
+                                // Wait until we're resumed, or stopped.
+                                loop {
+                                    match control_receiver.recv() {
+                                        Ok(WatchdogMessage::Resume) => break,
+                                        Ok(WatchdogMessage::Pause) => {}
+                                        Err(_) => return,
+                                    }
+                                }
+                            }
+                        }
+                        Err(crossbeam_channel::RecvTimeoutError::Timeout) => {
+                            fired.store(true, Ordering::SeqCst);
+                            let _ = process.lock().unwrap().kill();
+                            return;
+                        }
+                        Err(crossbeam_channel::RecvTimeoutError::Disconnected) => return,
+                    }
+                }
+            }
+        });
+        Watchdog {
+            timeout,
+            control: Some(control_sender),
+            thread: Some(thread),
+            fired,
+        }
+    }
+
+    /// Stops the timeout from elapsing until `resume` is called.
+    fn pause(&self) {
+        if let Some(control) = &self.control {
+            let _ = control.send(WatchdogMessage::Pause);
+        }
+    }
+
+    fn resume(&self) {
+        if let Some(control) = &self.control {
+            let _ = control.send(WatchdogMessage::Resume);
+        }
+    }
+
+    /// Stops the watchdog. Returns whether it killed the subprocess.
+    fn stop(&mut self) -> bool {
+        self.control.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        self.fired.load(Ordering::SeqCst)
+    }
+}
+
+/// Parses durations like "500ms", "30s", "5m" or "1h". A number without a unit is taken to be
+/// seconds.
+pub(crate) fn parse_duration(text: &str) -> Result<Duration, Error> {
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let Ok(number) = number.parse::<f64>() else {
+        bail!("Invalid duration `{}`", text);
+    };
+    let seconds = match unit.trim() {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 60.0 * 60.0,
+        _ => bail!(
+            "Invalid duration `{}`. Supported units are ms, s, m and h",
+            text
+        ),
+    };
+    Duration::try_from_secs_f64(seconds)
+        .map_err(|error| Error::Message(format!("Invalid duration `{text}`: {error}")))
+}
+
+/// Parses a duration that limits something. Returns None if there should be no limit, which is
+/// specified as `off` or a duration of zero.
+pub(crate) fn parse_duration_limit(text: &str) -> Result<Option<Duration>, Error> {
+    if text.trim() == "off" {
+        return Ok(None);
+    }
+    let duration = parse_duration(text)?;
+    Ok((!duration.is_zero()).then_some(duration))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -2210,4 +4032,136 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_check_registry_dep() {
+        assert!(check_registry_dep("regex", "\"1.0\"").is_ok());
+        assert!(
+            check_registry_dep(
+                "serde",
+                "{ version = \"1\", features = [\"derive\"], default-features = false }"
+            )
+            .is_ok()
+        );
+        assert!(check_registry_dep("evil", "{ path = \"/tmp/evil\" }").is_err());
+        assert!(check_registry_dep("evil", "{ version = \"1\", registry = \"evil\" }").is_err());
+        assert!(check_registry_dep("evil", "{ git = \"file:///tmp/evil\" }").is_err());
+        assert!(check_registry_dep("evil", "not toml").is_err());
+    }
+
+    #[test]
+    fn test_export_crate_cargo_toml() {
+        let mut state = create_state();
+        state.async_mode = true;
+        let tempdir = tempfile::tempdir().unwrap();
+        let crate_dir = tempdir.path().join("exported");
+        state.export_crate(&[], &crate_dir).unwrap();
+        let cargo_toml = std::fs::read_to_string(crate_dir.join("Cargo.toml")).unwrap();
+        assert!(cargo_toml.contains(&format!("edition = \"{}\"", state.edition())));
+        assert!(cargo_toml.contains(&format!("{} = {}", TOKIO_DEP.0, TOKIO_DEP.1)));
+        let main = std::fs::read_to_string(crate_dir.join("src").join("main.rs")).unwrap();
+        assert!(main.contains("async fn run()"));
+    }
+
+    #[test]
+    fn test_export_test() {
+        let mut state = create_state();
+        let (user_code, code_info) = CodeBlock::from_original_user_code(stringify!(
+            fn foo() -> i32 {
+                42
+            }
+            let x = foo();
+            x + 1
+        ));
+        state.apply(user_code.clone(), &code_info.nodes).unwrap();
+        // i32 has no evcxr_display method, so compiling would have fallen back to formatting it.
+        state.final_expression_formatted = true;
+        let mut outputs = EvalOutputs::new();
+        outputs
+            .content_by_mime_type
+            .insert("text/plain".to_owned(), "43".to_owned());
+        let cells = Vec::from_iter(state.take_cell(&user_code, &outputs));
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let crate_dir = tempdir.path();
+        let tests_dir = crate_dir.join("tests");
+        // We should only write tests into a crate.
+        assert!(
+            state
+                .export_test(&cells, crate_dir, "from_session")
+                .is_err()
+        );
+        std::fs::write(crate_dir.join("Cargo.toml"), "").unwrap();
+        assert!(
+            state
+                .export_test(&cells, crate_dir, "not-an-identifier")
+                .is_err()
+        );
+        state
+            .export_test(&cells, crate_dir, "from_session")
+            .unwrap();
+        let code = std::fs::read_to_string(tests_dir.join("from_session.rs")).unwrap();
+        assert!(code.contains("fn from_session() {"));
+        assert!(code.contains("fn foo() -> i32"));
+        assert!(code.contains("let x = foo();"));
+        assert!(code.contains(r#"assert_eq!(format!("{:?}", &(x + 1)), "43");"#));
+        assert!(code.contains("run().map_err("));
+        // We shouldn't overwrite an existing test.
+        assert!(
+            state
+                .export_test(&cells, crate_dir, "from_session")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_from_impl_types() {
+        let from_impl = |code: &str| {
+            let parsed = SourceFile::parse(code, crate::rust_analyzer::EDITION);
+            let impl_item = parsed
+                .syntax_node()
+                .descendants()
+                .find_map(ast::Impl::cast)
+                .unwrap();
+            from_impl_types(&impl_item)
+        };
+        assert_eq!(
+            from_impl("impl From<OldPoint> for Point {}"),
+            Some(("OldPoint".to_owned(), "Point".to_owned()))
+        );
+        assert_eq!(
+            from_impl("impl std::convert::From<v1::Point> for Vec<Point> {}"),
+            Some(("v1::Point".to_owned(), "Vec<Point>".to_owned()))
+        );
+        assert_eq!(from_impl("impl Into<Point> for OldPoint {}"), None);
+        assert_eq!(from_impl("impl Point {}"), None);
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        use super::parse_duration;
+        use std::time::Duration;
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("1.5").unwrap(), Duration::from_millis(1500));
+        assert_eq!(parse_duration("2m").unwrap(), Duration::from_secs(120));
+        assert_eq!(parse_duration("1h").unwrap(), Duration::from_secs(3600));
+        assert!(parse_duration("5 days").is_err());
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration("99999999999999999999999h").is_err());
+    }
+
+    #[test]
+    fn test_parse_duration_limit() {
+        use super::parse_duration_limit;
+        use std::time::Duration;
+        assert_eq!(
+            parse_duration_limit("2s").unwrap(),
+            Some(Duration::from_secs(2))
+        );
+        for text in ["off", "0", "0s", "0.0ms"] {
+            assert_eq!(parse_duration_limit(text).unwrap(), None);
+        }
+        assert!(parse_duration_limit("of").is_err());
+    }
 }
@@ -11,15 +11,392 @@
 
 pub const VARIABLE_CHANGED_TYPE: &str = "EVCXR_VARIABLE_CHANGED_TYPE:";
 pub const USER_ERROR_OCCURRED: &str = "EVCXR_ERROR_OCCURRED";
+pub const VARIABLE_DETAIL: &str = "EVCXR_VARIABLE_DETAIL ";
+/// Set by the runtime to `<pid>:<address>`, where address is that of a `ControlFn` that sends
+/// control messages to evcxr. The pid ensures that processes started by user code that inherit the
+/// variable don't try to use it.
+pub const CONTROL_FN_ENV: &str = "EVCXR_CONTROL_FN";
+/// Set by the runtime to `<pid>:<address>`, where address is that of a `SessionValueFn` that stores
+/// values for evcxr_runtime::session_value.
+pub const SESSION_VALUE_FN_ENV: &str = "EVCXR_SESSION_VALUE_FN";
+/// Set by the runtime to `<pid>:<address>`, where address is that of a `SessionValueFn` that stores
+/// the values of statics declared by the user. These are kept apart from values stored via
+/// evcxr_runtime, so that the user's keys can't clash with ours.
+pub const SESSION_STATIC_FN_ENV: &str = "EVCXR_SESSION_STATIC_FN";
+/// Set by the runtime to `<pid>:<address>`, where address is that of its copy of
+/// `thread_local_value`.
+pub const SESSION_THREAD_LOCAL_FN_ENV: &str = "EVCXR_SESSION_THREAD_LOCAL_FN";
+/// The maximum length of a variable preview, excluding the trailing ellipsis if truncated.
+pub const MAX_PREVIEW_LEN: usize = 100;
+
+/// Returns the size of a variable and, if its type implements Debug, a preview of its value.
+pub type Describer = fn(&dyn std::any::Any) -> (usize, Option<String>);
+
+/// Returns a variable serialized as JSON if its type implements serde, so that its value can be
+/// migrated if its type is redefined.
+pub type Serializer = fn(&dyn std::any::Any) -> Option<String>;
+
+/// Sends a control message with the supplied header and payload.
+pub type ControlFn = extern "C" fn(*const u8, usize, *const u8, usize);
+
+/// Given a key and a value, stores the value under the key unless there's already a value there,
+/// then returns the stored value. A null value just looks up the key, returning null if absent.
+pub type SessionValueFn =
+    extern "C" fn(*const u8, usize, *mut std::ffi::c_void) -> *mut std::ffi::c_void;
+
+/// Returns the address of a function that the runtime published via the environment variable
+/// `var`, provided that it was published by our process.
+fn runtime_fn_address(var: &str) -> Option<usize> {
+    let value = std::env::var(var).ok()?;
+    let (pid, address) = value.split_once(':')?;
+    if pid.parse::<u32>().ok()? != std::process::id() {
+        return None;
+    }
+    address.parse::<usize>().ok()
+}
+
+/// Sends a control message to evcxr. Anything that was printed to stdout beforehand will be output
+/// before the message is processed.
+pub fn send_control(header: &str, payload: &str) {
+    use std::io::Write;
+    static CONTROL_FN: std::sync::OnceLock<Option<ControlFn>> = std::sync::OnceLock::new();
+    let control_fn = CONTROL_FN.get_or_init(|| {
+        let address = runtime_fn_address(CONTROL_FN_ENV)?;
+        // Safety: The runtime that set the variable is in our process, so the address is that of
+        // its function.
+        Some(unsafe { std::mem::transmute::<usize, ControlFn>(address) })
+    });
+    let _ = std::io::stdout().flush();
+    if let Some(control_fn) = control_fn {
+        control_fn(
+            header.as_ptr(),
+            header.len(),
+            payload.as_ptr(),
+            payload.len(),
+        );
+    }
+}
+
+/// Returns the value of the static stored with the runtime under `key`, first storing the result
+/// of `init` if there isn't one. Values are never freed. The runtime outlives the code of every
+/// cell, so values stored this way are shared by all copies of the code that uses them.
+fn session_static<T: std::any::Any>(key: &str, init: impl FnOnce() -> T) -> &'static T {
+    use std::ffi::c_void;
+    type Stored = &'static dyn std::any::Any;
+    static SESSION_STATIC_FN: std::sync::OnceLock<Option<SessionValueFn>> =
+        std::sync::OnceLock::new();
+    let session_value_fn = SESSION_STATIC_FN.get_or_init(|| {
+        let address = runtime_fn_address(SESSION_STATIC_FN_ENV)?;
+        // Safety: The runtime that set the variable is in our process, so the address is that of
+        // its function.
+        Some(unsafe { std::mem::transmute::<usize, SessionValueFn>(address) })
+    });
+    // Outside of the runtime, we only have our own copy of the code, so can store values locally.
+    static LOCAL_VALUES: std::sync::Mutex<Option<std::collections::HashMap<String, usize>>> =
+        std::sync::Mutex::new(None);
+    let store = |key: &str, value: *mut c_void| -> *mut c_void {
+        if let Some(session_value_fn) = session_value_fn {
+            return session_value_fn(key.as_ptr(), key.len(), value);
+        }
+        let mut values = LOCAL_VALUES
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let values = values.get_or_insert_with(Default::default);
+        if value.is_null() {
+            values
+                .get(key)
+                .map_or(std::ptr::null_mut(), |value| *value as *mut c_void)
+        } else {
+            *values.entry(key.to_owned()).or_insert(value as usize) as *mut c_void
+        }
+    };
+    // Safety: Everything stored under our keys is a leaked `Stored`.
+    let lookup = |key: &str| {
+        let stored = store(key, std::ptr::null_mut());
+        (!stored.is_null()).then(|| unsafe { *stored.cast::<Stored>() })
+    };
+    let key = match lookup(key) {
+        Some(stored) => match stored.downcast_ref::<T>() {
+            Some(value) => return value,
+            None => retyped_key::<T>(key, "static", |key| lookup(key).is_some()),
+        },
+        None => key.to_owned(),
+    };
+    let stored = match lookup(&key) {
+        Some(stored) => stored,
+        None => {
+            let value: Stored = Box::leak(Box::new(init()));
+            let new_value = Box::into_raw(Box::new(value));
+            let stored = store(&key, new_value.cast());
+            if stored != new_value.cast() {
+                // Another thread stored a value first.
+                // Safety: new_value came from Box::into_raw and wasn't stored.
+                drop(unsafe { Box::from_raw(new_value) });
+            }
+            // Safety: As above.
+            unsafe { *stored.cast::<Stored>() }
+        }
+    };
+    match stored.downcast_ref::<T>() {
+        Some(value) => value,
+        None => panic!("Session value {key} changed type"),
+    }
+}
+
+/// Returns the key under which to store the value of the static that was stored under `key`, but
+/// whose type has since been redefined so that it's now `T`. The old value can't be used, so the
+/// static gets a new one, which we tell the user about the first time. `exists` returns whether
+/// there's a value stored under a key.
+fn retyped_key<T: std::any::Any>(key: &str, kind: &str, exists: impl Fn(&str) -> bool) -> String {
+    let retyped = format!("{key}/{:?}", std::any::TypeId::of::<T>());
+    if !exists(&retyped) {
+        let name = key.split('/').nth(1).unwrap_or(key);
+        eprintln!("The type of the {kind} {name} was redefined, so it was reinitialized.");
+    }
+    retyped
+}
+
+/// A `SessionValueFn` that stores the current thread's values of thread locals declared by the
+/// user, each a boxed `Box<dyn Any>`. Values are dropped when the thread exits. Returns null if the
+/// thread is exiting. The runtime publishes its copy, so that all copies of the code share values.
+pub extern "C" fn thread_local_value(
+    key: *const u8,
+    key_len: usize,
+    value: *mut std::ffi::c_void,
+) -> *mut std::ffi::c_void {
+    type Stored = Box<dyn std::any::Any>;
+    thread_local! {
+        static VALUES: std::cell::RefCell<std::collections::HashMap<String, Box<Stored>>> =
+            std::cell::RefCell::new(std::collections::HashMap::new());
+    }
+    // Safety: Callers pass a pointer and length of a valid slice, and values that are boxed
+    // `Stored`s.
+    let key = String::from_utf8_lossy(unsafe { std::slice::from_raw_parts(key, key_len) });
+    let value = (!value.is_null()).then(|| unsafe { Box::from_raw(value.cast::<Stored>()) });
+    VALUES
+        .try_with(|values| {
+            let mut values = values.borrow_mut();
+            let stored = match value {
+                Some(value) => values.entry(key.into_owned()).or_insert(value),
+                None => match values.get_mut(key.as_ref()) {
+                    Some(stored) => stored,
+                    None => return std::ptr::null_mut(),
+                },
+            };
+            // The outer box keeps the address the same if the map is resized.
+            (&mut **stored as *mut Stored).cast()
+        })
+        .unwrap_or(std::ptr::null_mut())
+}
+
+/// Backs a `static` declared by the user. The code for the static is compiled into the library
+/// for every cell, so we store the value with the runtime in order that every copy of the code
+/// sees the same value.
+pub struct SessionStatic<T: 'static> {
+    key: &'static str,
+    init: fn() -> T,
+    value: std::sync::OnceLock<&'static T>,
+}
+
+impl<T: Sync + 'static> SessionStatic<T> {
+    pub const fn new(key: &'static str, init: fn() -> T) -> SessionStatic<T> {
+        SessionStatic {
+            key,
+            init,
+            value: std::sync::OnceLock::new(),
+        }
+    }
+}
+
+impl<T: Sync + 'static> std::ops::Deref for SessionStatic<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+            .get_or_init(|| session_static(self.key, self.init))
+    }
+}
+
+/// Backs a static declared by the user in `thread_local!`. Like `SessionStatic`, but each thread
+/// has its own value, which is dropped when the thread exits. Has the same methods as
+/// `std::thread::LocalKey`, but isn't one, so can't be passed where a `&LocalKey` is expected.
+pub struct SessionThreadLocal<T: 'static> {
+    key: &'static str,
+    init: fn() -> T,
+}
+
+/// The error returned by `SessionThreadLocal::try_with` if the thread is exiting. Stands in for
+/// `std::thread::AccessError`, which can't be constructed outside of std.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AccessError;
+
+impl std::fmt::Display for AccessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("already destroyed")
+    }
+}
+
+impl std::error::Error for AccessError {}
+
+impl<T: 'static> SessionThreadLocal<T> {
+    pub const fn new(key: &'static str, init: fn() -> T) -> SessionThreadLocal<T> {
+        SessionThreadLocal { key, init }
+    }
+
+    pub fn with<F: FnOnce(&T) -> R, R>(&'static self, f: F) -> R {
+        self.try_with(f)
+            .unwrap_or_else(|_| self.accessed_while_exiting())
+    }
+
+    pub fn try_with<F: FnOnce(&T) -> R, R>(&'static self, f: F) -> Result<R, AccessError> {
+        self.initialize_with(self.init, f)
+    }
+
+    fn accessed_while_exiting(&self) -> ! {
+        panic!(
+            "Thread local {} accessed while its thread was exiting",
+            self.key
+        );
+    }
+
+    /// Calls `f` with the current thread's value, first storing the result of `init` if there
+    /// isn't one.
+    fn initialize_with<F: FnOnce(&T) -> R, R>(
+        &'static self,
+        init: impl FnOnce() -> T,
+        f: F,
+    ) -> Result<R, AccessError> {
+        type Stored = Box<dyn std::any::Any>;
+        static THREAD_LOCAL_FN: std::sync::OnceLock<SessionValueFn> = std::sync::OnceLock::new();
+        let thread_local_fn = *THREAD_LOCAL_FN.get_or_init(|| {
+            match runtime_fn_address(SESSION_THREAD_LOCAL_FN_ENV) {
+                // Safety: The runtime that set the variable is in our process, so the address is
+                // that of its function.
+                Some(address) => unsafe { std::mem::transmute::<usize, SessionValueFn>(address) },
+                // Outside of the runtime, we only have our own copy of the code.
+                None => thread_local_value,
+            }
+        });
+        // Safety: Values are boxed `Stored`s, which live until our thread exits.
+        let lookup = |key: &str| {
+            let stored = thread_local_fn(key.as_ptr(), key.len(), std::ptr::null_mut());
+            unsafe { stored.cast::<Stored>().as_ref() }
+        };
+        let mut key = std::borrow::Cow::Borrowed(self.key);
+        if let Some(stored) = lookup(&key) {
+            match stored.downcast_ref::<T>() {
+                Some(value) => return Ok(f(value)),
+                None => {
+                    key =
+                        retyped_key::<T>(&key, "thread local", |key| lookup(key).is_some()).into();
+                }
+            }
+        }
+        let stored = match lookup(&key) {
+            Some(stored) => stored,
+            None => {
+                let value: Stored = Box::new(init());
+                let stored = thread_local_fn(
+                    key.as_ptr(),
+                    key.len(),
+                    Box::into_raw(Box::new(value)).cast(),
+                );
+                // Safety: As above. Null means that the thread is exiting.
+                unsafe { stored.cast::<Stored>().as_ref() }.ok_or(AccessError)?
+            }
+        };
+        match stored.downcast_ref::<T>() {
+            Some(value) => Ok(f(value)),
+            None => panic!("Thread local {key} changed type"),
+        }
+    }
+}
+
+impl<T: 'static> SessionThreadLocal<std::cell::Cell<T>> {
+    pub fn set(&'static self, value: T) {
+        // Like `LocalKey::set`, we don't run the initializer if there's no value yet.
+        let value = std::cell::Cell::new(Some(value));
+        let result = self.initialize_with(
+            || std::cell::Cell::new(value.take().unwrap()),
+            |cell| {
+                if let Some(value) = value.take() {
+                    cell.set(value);
+                }
+            },
+        );
+        if result.is_err() {
+            self.accessed_while_exiting();
+        }
+    }
+
+    pub fn get(&'static self) -> T
+    where
+        T: Copy,
+    {
+        self.with(|cell| cell.get())
+    }
+
+    pub fn take(&'static self) -> T
+    where
+        T: Default,
+    {
+        self.with(|cell| cell.take())
+    }
+
+    pub fn replace(&'static self, value: T) -> T {
+        self.with(|cell| cell.replace(value))
+    }
+}
+
+impl<T: 'static> SessionThreadLocal<std::cell::RefCell<T>> {
+    pub fn with_borrow<F: FnOnce(&T) -> R, R>(&'static self, f: F) -> R {
+        self.with(|cell| f(&cell.borrow()))
+    }
+
+    pub fn with_borrow_mut<F: FnOnce(&mut T) -> R, R>(&'static self, f: F) -> R {
+        self.with(|cell| f(&mut cell.borrow_mut()))
+    }
+
+    pub fn set(&'static self, value: T) {
+        // Like `LocalKey::set`, we don't run the initializer if there's no value yet.
+        let value = std::cell::Cell::new(Some(value));
+        let result = self.initialize_with(
+            || std::cell::RefCell::new(value.take().unwrap()),
+            |cell| {
+                if let Some(value) = value.take() {
+                    *cell.borrow_mut() = value;
+                }
+            },
+        );
+        if result.is_err() {
+            self.accessed_while_exiting();
+        }
+    }
+
+    pub fn take(&'static self) -> T
+    where
+        T: Default,
+    {
+        self.with(|cell| cell.take())
+    }
+
+    pub fn replace(&'static self, value: T) -> T {
+        self.with(|cell| cell.replace(value))
+    }
+}
 
 pub struct VariableStore {
     variables: std::collections::HashMap<String, Box<dyn std::any::Any + 'static>>,
+    describers: std::collections::HashMap<String, Describer>,
+    serializers: std::collections::HashMap<String, Serializer>,
 }
 
 impl VariableStore {
     pub fn new() -> VariableStore {
         VariableStore {
             variables: std::collections::HashMap::new(),
+            describers: std::collections::HashMap::new(),
+            serializers: std::collections::HashMap::new(),
         }
     }
 
@@ -27,17 +404,62 @@ impl VariableStore {
         self.variables.insert(name.to_owned(), Box::new(value));
     }
 
+    pub fn set_describer(&mut self, name: &str, describer: Describer) {
+        self.describers.insert(name.to_owned(), describer);
+    }
+
+    pub fn set_serializer(&mut self, name: &str, serializer: Serializer) {
+        self.serializers.insert(name.to_owned(), serializer);
+    }
+
+    /// Sends details of each variable that has a describer.
+    pub fn send_variable_details(&self) {
+        for (name, value) in &self.variables {
+            if let Some(describer) = self.describers.get(name) {
+                let (size, preview) = describer(value.as_ref());
+                match preview {
+                    Some(preview) => {
+                        send_control(&format!("{VARIABLE_DETAIL}{name} {size} {preview}"), "")
+                    }
+                    None => send_control(&format!("{VARIABLE_DETAIL}{name} {size}"), ""),
+                }
+            }
+        }
+    }
+
     pub fn check_variable<T: 'static>(&mut self, name: &str) -> bool {
         if let Some(v) = self.variables.get(name)
             && v.downcast_ref::<T>().is_none()
         {
             eprintln!("The type of the variable {name} was redefined, so was lost.",);
-            println!("{VARIABLE_CHANGED_TYPE}{name}");
+            send_control(&format!("{VARIABLE_CHANGED_TYPE}{name}"), "");
             return false;
         }
         true
     }
 
+    /// Like `check_variable`, but if the type of the variable was redefined, first tries to
+    /// convert it to the new type by passing its serialized value to `deserialize`.
+    pub fn migrate_variable<T: 'static>(
+        &mut self,
+        name: &str,
+        deserialize: impl FnOnce(&str) -> Option<T>,
+    ) -> bool {
+        if let Some(v) = self.variables.get(name)
+            && v.downcast_ref::<T>().is_none()
+            && let Some(json) = self
+                .serializers
+                .get(name)
+                .and_then(|serializer| serializer(v.as_ref()))
+            && let Some(value) = deserialize(&json)
+        {
+            eprintln!("The type of the variable {name} was redefined, so its value was migrated.");
+            self.put_variable(name, value);
+            return true;
+        }
+        self.check_variable::<T>(name)
+    }
+
     pub fn take_variable<T: 'static>(&mut self, name: &str) -> T {
         match self.variables.remove(name) {
             Some(v) => {
@@ -71,9 +493,63 @@ impl VariableStore {
 
     pub fn merge(&mut self, mut other: VariableStore) {
         self.variables.extend(other.variables.drain());
+        self.describers.extend(other.describers.drain());
+        self.serializers.extend(other.serializers.drain());
     }
 }
 
 pub fn create_variable_store() -> *mut VariableStore {
     Box::into_raw(Box::new(VariableStore::new()))
 }
+
+/// Returns the Debug output of `value` on a single line, truncated to `MAX_PREVIEW_LEN`. We stop
+/// formatting once we hit the limit, so large values are cheap to preview.
+pub fn debug_preview<T: std::fmt::Debug + ?Sized>(value: &T) -> String {
+    struct Truncating(String);
+    impl std::fmt::Write for Truncating {
+        fn write_str(&mut self, s: &str) -> std::fmt::Result {
+            let remaining = MAX_PREVIEW_LEN - self.0.len();
+            if s.len() <= remaining {
+                self.0.push_str(s);
+                return Ok(());
+            }
+            let mut end = remaining;
+            while !s.is_char_boundary(end) {
+                end -= 1;
+            }
+            self.0.push_str(&s[..end]);
+            Err(std::fmt::Error)
+        }
+    }
+    let mut out = Truncating(String::new());
+    let truncated = std::fmt::Write::write_fmt(&mut out, format_args!("{value:?}")).is_err();
+    let mut preview = out.0.replace(['\r', '\n'], " ");
+    if truncated {
+        preview.push('…');
+    }
+    preview
+}
+
+// Used with autoref-based specialization to produce a preview only for types that implement Debug.
+// Generated code calls `(&Describe(&value)).evcxr_preview()`.
+pub struct Describe<'a, T>(pub &'a T);
+
+pub trait DescribeDebug {
+    fn evcxr_preview(&self) -> Option<String>;
+}
+
+impl<T: std::fmt::Debug> DescribeDebug for Describe<'_, T> {
+    fn evcxr_preview(&self) -> Option<String> {
+        Some(debug_preview(self.0))
+    }
+}
+
+pub trait DescribeFallback {
+    fn evcxr_preview(&self) -> Option<String>;
+}
+
+impl<T> DescribeFallback for &Describe<'_, T> {
+    fn evcxr_preview(&self) -> Option<String> {
+        None
+    }
+}
@@ -0,0 +1,344 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE
+// or https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Support for `:expand`, which shows the user's code with macro invocations replaced by their
+//! expansions. The expansions themselves come from elsewhere, since both rust-analyzer and rustc
+//! see the user's code wrapped in generated code. rust-analyzer expands function-like macros, but
+//! doesn't produce expansions for derives, so for those we take the impls from rustc's output.
+
+use crate::code_block::CodeBlock;
+use crate::code_block::CodeKind;
+use crate::item;
+use ra_ap_syntax::AstNode;
+use ra_ap_syntax::NodeOrToken;
+use ra_ap_syntax::SourceFile;
+use ra_ap_syntax::SyntaxKind;
+use ra_ap_syntax::SyntaxNode;
+use ra_ap_syntax::TextSize;
+use ra_ap_syntax::ast;
+use ra_ap_syntax::ast::HasAttrs;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::ops::Range;
+
+/// The user's code with macro invocations replaced by their expansions.
+pub(crate) struct Expanded {
+    pub(crate) code: String,
+    /// Macro invocations and derives that were left as written because they couldn't be expanded,
+    /// e.g. `foo!` or `#[derive] Point`.
+    pub(crate) unexpanded: Vec<String>,
+}
+
+/// Returns the Rust code in `code` with each macro invocation replaced by its expansion. Derive
+/// attributes are removed and the impls that they generate are placed after their items.
+/// `user_code` and `nodes` are the result of parsing `code`. `expand` is given the index in `nodes`
+/// of a node and the offset within that node's text of the name of a macro, and returns its
+/// expansion, or None if it can't be expanded, in which case the invocation is left as written.
+/// `derived_impls` is given the path of an item with derive attributes and returns the impls that
+/// they generate. The path is the item's name, preceded by the names of any modules and functions
+/// in the user's code that contain it, e.g. `outer::Point`.
+pub(crate) fn expand_macros(
+    code: &str,
+    user_code: &CodeBlock,
+    nodes: &[SyntaxNode],
+    mut expand: impl FnMut(usize, usize) -> Option<String>,
+    mut derived_impls: impl FnMut(&str) -> Vec<String>,
+) -> Expanded {
+    let mut edits: Vec<(Range<usize>, String)> = Vec::new();
+    let mut unexpanded: Vec<(Range<usize>, String)> = Vec::new();
+    let mut derived_items = HashSet::new();
+    let mut rust_range: Option<Range<usize>> = None;
+    for segment in &user_code.segments {
+        let CodeKind::OriginalUserCode(meta) = &segment.kind else {
+            continue;
+        };
+        // Segments always end with a newline, even if the user's code doesn't.
+        let segment_end = (meta.start_byte + segment.code.len()).min(code.len());
+        rust_range = Some(match rust_range {
+            Some(range) => range.start.min(meta.start_byte)..range.end.max(segment_end),
+            None => meta.start_byte..segment_end,
+        });
+        let node = &nodes[meta.node_index];
+        let Some(node_offset) = segment.code.find(&node.text().to_string()) else {
+            continue;
+        };
+        let node_start = node.text_range().start();
+        let to_code_offset =
+            |offset: TextSize| usize::from(offset - node_start) + node_offset + meta.start_byte;
+        for descendant in node.descendants() {
+            if let Some(macro_call) = ast::MacroCall::cast(descendant.clone()) {
+                let Some(name) = macro_call
+                    .path()
+                    .and_then(|path| path.segment())
+                    .and_then(|segment| segment.name_ref())
+                else {
+                    continue;
+                };
+                let range = macro_call.syntax().text_range();
+                let range = to_code_offset(range.start())..to_code_offset(range.end());
+                let name_offset = usize::from(name.syntax().text_range().start() - node_start);
+                match expand(meta.node_index, name_offset) {
+                    Some(expansion) => edits.push((range, expansion.trim_end().to_owned())),
+                    None => unexpanded.push((range, format!("{name}!"))),
+                }
+            } else if let Some(item) = ast::Item::cast(descendant) {
+                let derives: Vec<ast::Attr> = item
+                    .attrs()
+                    .filter(|attr| {
+                        attr.as_simple_call()
+                            .is_some_and(|(name, _)| name == "derive")
+                    })
+                    .collect();
+                if derives.is_empty() {
+                    continue;
+                }
+                let Some(name) = item::item_name(&item) else {
+                    continue;
+                };
+                let path = item_path(item.syntax(), node, name);
+                let item_range = item.syntax().text_range();
+                let item_range =
+                    to_code_offset(item_range.start())..to_code_offset(item_range.end());
+                let impls = derived_impls(&path);
+                if impls.is_empty() {
+                    unexpanded.push((item_range, format!("#[derive] {path}")));
+                    continue;
+                }
+                if !derived_items.insert(path) {
+                    continue;
+                }
+                for attr in derives {
+                    let attr_range = attr.syntax().text_range();
+                    let mut attr_end = attr_range.end();
+                    if let Some(NodeOrToken::Token(next)) = attr.syntax().next_sibling_or_token()
+                        && next.kind() == SyntaxKind::WHITESPACE
+                    {
+                        attr_end = next.text_range().end();
+                    }
+                    edits.push((
+                        to_code_offset(attr_range.start())..to_code_offset(attr_end),
+                        String::new(),
+                    ));
+                }
+                edits.push((
+                    item_range.end..item_range.end,
+                    format!("\n{}", impls.join("\n")),
+                ));
+            }
+        }
+    }
+    let Some(rust_range) = rust_range else {
+        return Expanded {
+            code: String::new(),
+            unexpanded: Vec::new(),
+        };
+    };
+    edits.sort_by_key(|(range, _)| (range.start, range.end));
+    let mut expanded = String::new();
+    let mut replaced: Vec<Range<usize>> = Vec::new();
+    let mut position = rust_range.start;
+    for (range, text) in edits {
+        // Anything within an invocation that we've already replaced was expanded along with it.
+        if range.start < position {
+            continue;
+        }
+        expanded.push_str(&code[position..range.start]);
+        expanded.push_str(&text);
+        position = range.end;
+        replaced.push(range);
+    }
+    expanded.push_str(&code[position..rust_range.end]);
+    Expanded {
+        code: expanded.trim().to_owned(),
+        // Invocations within ones that we replaced were dealt with as part of their expansion.
+        unexpanded: unexpanded
+            .into_iter()
+            .filter(|(range, _)| {
+                !replaced
+                    .iter()
+                    .any(|r| r.start < r.end && r.start <= range.start && range.end <= r.end)
+            })
+            .map(|(_, description)| description)
+            .collect(),
+    }
+}
+
+/// Returns `name` preceded by the names of the modules and functions within `root` that contain
+/// `node`.
+fn item_path(node: &SyntaxNode, root: &SyntaxNode, name: String) -> String {
+    let mut parts: Vec<String> = node
+        .ancestors()
+        .skip(1)
+        .take_while(|ancestor| root.text_range().contains_range(ancestor.text_range()))
+        .filter_map(|ancestor| match ast::Item::cast(ancestor)? {
+            item @ (ast::Item::Module(_) | ast::Item::Fn(_)) => item::item_name(&item),
+            _ => None,
+        })
+        .collect();
+    parts.reverse();
+    parts.push(name);
+    parts.join("::")
+}
+
+/// Returns the impls produced by derives in `expanded_crate`, which is the code of a crate after
+/// macro expansion as printed by rustc. The user's items are at the root of the crate, while their
+/// other statements are within the function `wrapper_fn`. Impls are keyed by the path of the type
+/// that they're for, relative to wherever the user's code was placed, as described for
+/// [`expand_macros`].
+pub(crate) fn derived_impls(
+    expanded_crate: &str,
+    wrapper_fn: &str,
+) -> HashMap<String, Vec<String>> {
+    let mut impls: HashMap<String, Vec<String>> = HashMap::new();
+    let source_file = SourceFile::parse(expanded_crate, crate::rust_analyzer::EDITION).tree();
+    for impl_item in source_file
+        .syntax()
+        .descendants()
+        .filter_map(ast::Impl::cast)
+    {
+        if !impl_item.attrs().any(|attr| {
+            attr.simple_name()
+                .is_some_and(|name| name == "automatically_derived")
+        }) {
+            continue;
+        }
+        let Some(ast::Type::PathType(self_ty)) = impl_item.self_ty() else {
+            continue;
+        };
+        let Some(name) = self_ty
+            .path()
+            .and_then(|path| path.segment())
+            .and_then(|segment| segment.name_ref())
+        else {
+            continue;
+        };
+        let path = item_path(
+            impl_item.syntax(),
+            source_file.syntax(),
+            name.text().to_string(),
+        );
+        let path = path
+            .strip_prefix(wrapper_fn)
+            .and_then(|path| path.strip_prefix("::"))
+            .map(str::to_owned)
+            .unwrap_or(path);
+        impls
+            .entry(path)
+            .or_default()
+            .push(impl_item.syntax().text().to_string());
+    }
+    impls
+}
+
+#[cfg(test)]
+mod test {
+    use super::derived_impls;
+    use super::expand_macros;
+    use crate::code_block::CodeBlock;
+
+    // Expands each macro to its name in upper case and each derive to an impl for the path of its
+    // item, so that we can see what was replaced. Macros named `unknown` and derives on items named
+    // `Unknown` can't be expanded.
+    fn expand_to_names(code: &str) -> (String, Vec<String>) {
+        let (user_code, code_info) = CodeBlock::from_original_user_code(code);
+        let expanded = expand_macros(
+            code,
+            &user_code,
+            &code_info.nodes,
+            |node_index, offset| {
+                let name: String = code_info.nodes[node_index].text().to_string()[offset..]
+                    .chars()
+                    .take_while(|c| c.is_alphanumeric() || *c == '_')
+                    .collect();
+                (name != "unknown").then(|| format!("{}\n", name.to_uppercase()))
+            },
+            |path| {
+                if path.ends_with("Unknown") {
+                    Vec::new()
+                } else {
+                    vec![format!("impl {path}")]
+                }
+            },
+        );
+        (expanded.code, expanded.unexpanded)
+    }
+
+    #[test]
+    fn test_expand_macros() {
+        assert_eq!(
+            expand_to_names(":dep foo\n  let a = vec![1];\nprintln!(\"{a:?}\");\n").0,
+            "let a = VEC;\nPRINTLN;"
+        );
+        assert_eq!(
+            expand_to_names("let a = 1; println!(\"x\"); let b = vec![a];").0,
+            "let a = 1; PRINTLN; let b = VEC;"
+        );
+        // Macros within ones that were expanded aren't reported, since they're part of the
+        // expansion.
+        assert_eq!(
+            expand_to_names("fn f() -> String { format!(\"{}\", unknown!()) }"),
+            ("fn f() -> String { FORMAT }".to_owned(), vec![])
+        );
+        assert_eq!(
+            expand_to_names("unknown!(1)"),
+            ("unknown!(1)".to_owned(), vec!["unknown!".to_owned()])
+        );
+        assert_eq!(
+            expand_to_names("#[derive(Debug)]\n#[allow(dead_code)]\n#[derive(Clone)]\nstruct A;").0,
+            "#[allow(dead_code)]\nstruct A;\nimpl A"
+        );
+        assert_eq!(
+            expand_to_names(
+                "mod m { #[derive(Debug)] struct A; }\n#[derive(Debug)] struct Unknown;"
+            ),
+            (
+                "mod m { struct A;\nimpl m::A }\n#[derive(Debug)] struct Unknown;".to_owned(),
+                vec!["#[derive] Unknown".to_owned()]
+            )
+        );
+    }
+
+    #[test]
+    fn test_derived_impls() {
+        let impls = derived_impls(
+            "struct A;\n\
+             #[automatically_derived]\n\
+             impl ::core::clone::Clone for A { fn clone(&self) -> A { A } }\n\
+             impl A { fn f() {} }\n\
+             mod m {\n\
+                 struct A;\n\
+                 #[automatically_derived]\n\
+                 impl ::core::fmt::Debug for A {}\n\
+             }\n\
+             fn wrapper() {\n\
+                 fn f() {\n\
+                     struct B<T>(T);\n\
+                     #[automatically_derived]\n\
+                     impl<T: ::core::fmt::Debug> ::core::fmt::Debug for B<T> {}\n\
+                 }\n\
+             }\n",
+            "wrapper",
+        );
+        assert_eq!(impls.len(), 3);
+        assert_eq!(
+            impls["A"],
+            vec![
+                "#[automatically_derived]\nimpl ::core::clone::Clone for A { fn clone(&self) -> A { A } }"
+            ]
+        );
+        assert_eq!(
+            impls["m::A"],
+            vec!["#[automatically_derived]\nimpl ::core::fmt::Debug for A {}"]
+        );
+        assert_eq!(
+            impls["f::B"],
+            vec![
+                "#[automatically_derived]\nimpl<T: ::core::fmt::Debug> ::core::fmt::Debug for B<T> {}"
+            ]
+        );
+    }
+}
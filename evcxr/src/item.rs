@@ -5,6 +5,8 @@
 // or https://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use ra_ap_syntax::AstNode;
+use ra_ap_syntax::SourceFile;
 use ra_ap_syntax::ast;
 
 /// Returns the name of an item if it has one.
@@ -29,3 +31,56 @@ fn item_ident(item: &ast::Item) -> Option<ast::Name> {
         _ => None,
     }
 }
+
+/// Returns whether `code` refers to the crate-root item `name`, either by its bare name or via a
+/// `crate::` or `self::` path. Paths that only end in `name`, such as `other::name`, refer to
+/// something else.
+pub(crate) fn refers_to(code: &str, name: &str) -> bool {
+    let source_file = SourceFile::parse(code, crate::rust_analyzer::EDITION).tree();
+    source_file
+        .syntax()
+        .descendants()
+        .filter_map(ast::Path::cast)
+        .any(|path| {
+            if path
+                .segment()
+                .and_then(|segment| segment.name_ref())
+                .is_none_or(|name_ref| name_ref.text() != name)
+            {
+                return false;
+            }
+            match path.qualifier() {
+                // Paths within a nested use tree, e.g. `use other::{name}`, are relative to the
+                // enclosing tree.
+                None => !path
+                    .syntax()
+                    .parent()
+                    .and_then(ast::UseTree::cast)
+                    .and_then(|tree| tree.syntax().parent())
+                    .is_some_and(|parent| ast::UseTreeList::can_cast(parent.kind())),
+                Some(qualifier) => {
+                    qualifier.qualifier().is_none()
+                        && qualifier.segment().is_some_and(|segment| {
+                            segment.crate_token().is_some() || segment.self_token().is_some()
+                        })
+                }
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::refers_to;
+
+    #[test]
+    fn test_refers_to() {
+        assert!(refers_to("fn f() -> Foo { Foo::new() }", "Foo"));
+        assert!(refers_to("type T = Vec<crate::Foo>;", "Foo"));
+        assert!(refers_to("impl std::fmt::Display for Foo {}", "Foo"));
+        assert!(refers_to("fn f() { bar!() }", "bar"));
+        assert!(!refers_to("type T = other::Foo;", "Foo"));
+        assert!(!refers_to("type T = FooBar;", "Foo"));
+        assert!(!refers_to("use other::{Foo, Bar};", "Foo"));
+        assert!(!refers_to("fn f(x: X) { x.Foo() }", "Foo"));
+    }
+}
@@ -15,27 +15,37 @@ mod cargo_metadata;
 mod child_process;
 mod code_block;
 mod command_context;
+mod control;
 mod crash_guard;
 mod crate_config;
 mod eval_context;
 #[allow(dead_code)]
 mod evcxr_internal_runtime;
+mod expand;
 mod item;
 mod module;
 mod runtime;
 mod rust_analyzer;
+mod sandbox;
+mod session;
 mod statement_splitter;
+mod statics;
 mod toml_parse;
 mod use_trees;
+mod user_stdin;
 
 pub use crate::command_context::CommandContext;
 pub use crate::errors::CompilationError;
 pub use crate::errors::Error;
 pub use crate::errors::Theme;
+pub use crate::eval_context::DisplayBundle;
 pub use crate::eval_context::EvalCallbacks;
 pub use crate::eval_context::EvalContext;
 pub use crate::eval_context::EvalContextOutputs;
 pub use crate::eval_context::EvalOutputs;
+pub use crate::eval_context::InputRequest;
+pub use crate::eval_context::LateOutput;
+pub use crate::eval_context::VariableDetails;
 pub use crate::runtime::runtime_hook;
 pub use rust_analyzer::Completions;
 
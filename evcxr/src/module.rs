@@ -123,6 +123,9 @@ fn rename_or_copy_so_file(src: &Path, dest: &Path) -> Result<(), Error> {
 pub(crate) struct Module {
     build_num: i32,
     last_allow_static: Option<bool>,
+    /// Prefix for the names of the shared objects that we compile. Sessions other than the first
+    /// share a target directory with the first, so need a distinct prefix.
+    so_name_prefix: String,
 }
 
 const CRATE_NAME: &str = "ctx";
@@ -132,9 +135,17 @@ impl Module {
         Ok(Module {
             build_num: 0,
             last_allow_static: None,
+            so_name_prefix: "code_".to_owned(),
         })
     }
 
+    pub(crate) fn for_session(session_name: &str) -> Module {
+        Module {
+            so_name_prefix: format!("code_{session_name}_"),
+            ..Module::default()
+        }
+    }
+
     // Writes Cargo.toml. Should be called before compile.
     pub(crate) fn write_cargo_toml(&self, state: &ContextState) -> Result<(), Error> {
         write_file(
@@ -171,6 +182,26 @@ impl Module {
         Ok(errors)
     }
 
+    /// Returns the code of the crate after macro expansion, as printed by rustc.
+    pub(crate) fn expanded_code(
+        &self,
+        code_block: &CodeBlock,
+        config: &Config,
+    ) -> Result<String, Error> {
+        self.write_code(code_block, config)?;
+        let mut command = config.cargo_command("rustc");
+        command
+            .arg("--profile=check")
+            .arg("--target")
+            .arg(&config.target)
+            .arg("--")
+            .arg("-Zunpretty=expanded")
+            // Allows -Z options on stable, but only for our crate.
+            .env("RUSTC_BOOTSTRAP", CRATE_NAME);
+        let cargo_output = run_cargo(command, code_block)?;
+        Ok(String::from_utf8_lossy(&cargo_output.stdout).into_owned())
+    }
+
     pub(crate) fn compile(
         &mut self,
         code_block: &CodeBlock,
@@ -213,7 +244,8 @@ impl Module {
         };
 
         let copied_so_file = so_file.with_file_name(shared_object_name_from_crate_name(&format!(
-            "code_{next_build_num}"
+            "{}{next_build_num}",
+            self.so_name_prefix
         )));
 
         rename_or_copy_so_file(&so_file, &copied_so_file)?;
@@ -259,7 +291,7 @@ impl Module {
 [package]
 name = "{}"
 version = "1.0.0"
-edition = "2024"
+edition = "{}"
 
 [lib]
 crate-type = ["cdylib"]
@@ -281,6 +313,7 @@ overflow-checks = true
 {}
 "#,
             CRATE_NAME,
+            state.edition(),
             state.opt_level(),
             crate_imports
         )
@@ -5,19 +5,34 @@
 // or https://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use crate::control::ControlWriter;
 use crate::errors::Error;
 use crate::errors::bail;
+use crate::evcxr_internal_runtime;
+use crate::user_stdin;
 use once_cell::sync::Lazy;
 use regex::Regex;
+use std::collections::HashMap;
+use std::ffi::c_void;
 use std::io;
 use std::marker::PhantomData;
 use std::rc::Rc;
+use std::sync::Mutex;
 use std::{self};
 
 pub(crate) const EVCXR_IS_RUNTIME_VAR: &str = "EVCXR_IS_RUNTIME";
 pub(crate) const EVCXR_EXECUTION_COMPLETE: &str = "EVCXR_EXECUTION_COMPLETE";
+/// Sent, followed by the build number, when we start running a cell. Used to mark the end of any
+/// output from previous cells.
+pub(crate) const EVCXR_EXECUTION_STARTED: &str = "EVCXR_EXECUTION_STARTED ";
 pub(crate) const WRAP_RUSTC_ENV: &str = "EVCXR_RUSTC_WRAPPER";
 pub(crate) const FORCE_DYLIB_ENV: &str = "EVCXR_FORCE_DYLIB";
+/// Instruction to print details of all stored variables. Output is terminated in the same way as
+/// for LOAD_AND_RUN.
+pub(crate) const VARIABLE_DETAILS: &str = "VARIABLE_DETAILS";
+/// A function exported by each compiled crate that has a variable store. It prints variable
+/// details from the supplied store.
+pub(crate) const VARIABLE_DETAILS_FN: &str = "evcxr_variable_details";
 
 /// Binaries can call this just after staring. If we detect that we're actually running as a
 /// subprocess, control will not return. There are two kinds of subprocesses that we may be acting
@@ -31,6 +46,141 @@ pub fn runtime_hook() {
     }
 }
 
+/// Values that the user's code has stored via evcxr_runtime::session_value, each the address of a
+/// boxed `Arc<dyn Any + Send + Sync>`. They're never freed, since they're needed for the life of the
+/// process.
+static SESSION_VALUES: Lazy<Mutex<HashMap<String, usize>>> = Lazy::new(Default::default);
+
+/// Values of statics declared by the user, each the address of a boxed `&dyn Any`. Like
+/// `SESSION_VALUES`, they're never freed.
+static SESSION_STATICS: Lazy<Mutex<HashMap<String, usize>>> = Lazy::new(Default::default);
+
+/// If `value` is null, returns the value stored in `values` under the key, or null if there isn't
+/// one. Otherwise, stores `value` unless there's already a value, then returns whichever is stored.
+fn store_value(
+    values: &Mutex<HashMap<String, usize>>,
+    key: *const u8,
+    key_len: usize,
+    value: *mut c_void,
+) -> *mut c_void {
+    // Safety: Callers pass a pointer and length of a valid slice.
+    let key = String::from_utf8_lossy(unsafe { std::slice::from_raw_parts(key, key_len) });
+    let mut values = values
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if value.is_null() {
+        values
+            .get(key.as_ref())
+            .map_or(std::ptr::null_mut(), |value| *value as *mut c_void)
+    } else {
+        *values.entry(key.into_owned()).or_insert(value as usize) as *mut c_void
+    }
+}
+
+extern "C" fn session_value(key: *const u8, key_len: usize, value: *mut c_void) -> *mut c_void {
+    store_value(&SESSION_VALUES, key, key_len, value)
+}
+
+extern "C" fn session_static(key: *const u8, key_len: usize, value: *mut c_void) -> *mut c_void {
+    store_value(&SESSION_STATICS, key, key_len, value)
+}
+
+static CONTROL_WRITER: Lazy<Mutex<ControlWriter>> =
+    Lazy::new(|| Mutex::new(ControlWriter::from_env()));
+
+/// Sends a control message to our parent. Code that we load calls this via the address that we
+/// put in `evcxr_internal_runtime::CONTROL_FN_ENV`.
+extern "C" fn send_control(
+    header: *const u8,
+    header_len: usize,
+    payload: *const u8,
+    payload_len: usize,
+) {
+    // Safety: Callers pass pointers and lengths of valid slices.
+    let (header, payload) = unsafe {
+        (
+            std::slice::from_raw_parts(header, header_len),
+            std::slice::from_raw_parts(payload, payload_len),
+        )
+    };
+    if header.starts_with(evcxr_input::GET_CMD.as_bytes()) {
+        user_stdin::input_requested();
+    }
+    let mut writer = CONTROL_WRITER
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Err(error) = writer.send(header, payload) {
+        eprintln!("Failed to send control message to parent: {error}");
+        std::process::exit(1);
+    }
+}
+
+/// Receives our parent's acknowledgements of control messages. Only set if we have a command pipe,
+/// since otherwise commands are read by the main thread, which is the one that waits.
+static ACKNOWLEDGEMENTS: Mutex<Option<std::sync::mpsc::Receiver<()>>> = Mutex::new(None);
+
+/// Waits for our parent to acknowledge a control message that we've sent, if it acknowledges
+/// messages. Returns early if our parent stops sending commands.
+fn wait_for_acknowledgement() {
+    let acknowledgements = ACKNOWLEDGEMENTS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some(acknowledgements) = acknowledgements.as_ref() {
+        let _ = acknowledgements.recv();
+    }
+}
+
+/// Returns the commands that our parent sends us. On Unix, these are read by a separate thread from
+/// a dedicated pipe and our stdin is redirected so that the user's code can read input. Elsewhere,
+/// they're read from stdin.
+fn receive_commands() -> Result<Box<dyn Iterator<Item = io::Result<String>>>, Error> {
+    use std::io::BufRead;
+    #[cfg(unix)]
+    if let Ok(fd) = std::env::var(crate::control::COMMAND_FD_ENV) {
+        use std::os::fd::FromRawFd;
+        let Ok(fd) = fd.parse::<std::os::fd::RawFd>() else {
+            bail!("Invalid command fd: {}", fd);
+        };
+        // Safety: The fd was set up by our parent for our exclusive use. Processes that the user's
+        // code starts shouldn't inherit it.
+        let pipe = unsafe {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+            std::fs::File::from_raw_fd(fd)
+        };
+        user_stdin::redirect()?;
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let (acknowledgement_sender, acknowledgements) = std::sync::mpsc::channel();
+        *ACKNOWLEDGEMENTS
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(acknowledgements);
+        std::thread::spawn(move || {
+            for line in io::BufReader::new(pipe).lines() {
+                // Input and acknowledgements are passed straight through, since the main thread
+                // will be busy running the user's code or waiting.
+                if matches!(&line, Ok(line) if line == crate::control::ACKNOWLEDGE_COMMAND) {
+                    let _ = acknowledgement_sender.send(());
+                    continue;
+                }
+                let line = match line {
+                    Ok(line) => match line.strip_prefix(user_stdin::INPUT_COMMAND) {
+                        Some(input) => match user_stdin::provide_input(input) {
+                            Ok(()) => continue,
+                            Err(error) => Err(error),
+                        },
+                        None => Ok(line),
+                    },
+                    Err(error) => Err(error),
+                };
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        return Ok(Box::new(receiver.into_iter()));
+    }
+    Ok(Box::new(io::stdin().lines()))
+}
+
 struct Runtime {
     shared_objects: Vec<libloading::Library>,
     variable_store_ptr: *mut std::os::raw::c_void,
@@ -49,12 +199,50 @@ impl Runtime {
     }
 
     fn run_loop(&mut self) -> ! {
-        use std::io::BufRead;
+        if let Err(error) = crate::sandbox::enter_if_configured() {
+            eprintln!("Failed to enter sandbox: {error}");
+            std::process::exit(98);
+        }
+        if std::env::var_os(crate::sandbox::SANDBOX_PROBE_ENV).is_some() {
+            std::process::exit(0);
+        }
+
+        let control_fn: evcxr_internal_runtime::ControlFn = send_control;
+        let session_value_fn: evcxr_internal_runtime::SessionValueFn = session_value;
+        let session_static_fn: evcxr_internal_runtime::SessionValueFn = session_static;
+        let thread_local_fn: evcxr_internal_runtime::SessionValueFn =
+            evcxr_internal_runtime::thread_local_value;
+        // Safety: We haven't started any threads yet.
+        unsafe {
+            std::env::set_var(
+                evcxr_internal_runtime::CONTROL_FN_ENV,
+                format!("{}:{}", std::process::id(), control_fn as usize),
+            );
+            std::env::set_var(
+                evcxr_internal_runtime::SESSION_VALUE_FN_ENV,
+                format!("{}:{}", std::process::id(), session_value_fn as usize),
+            );
+            std::env::set_var(
+                evcxr_internal_runtime::SESSION_STATIC_FN_ENV,
+                format!("{}:{}", std::process::id(), session_static_fn as usize),
+            );
+            std::env::set_var(
+                evcxr_internal_runtime::SESSION_THREAD_LOCAL_FN_ENV,
+                format!("{}:{}", std::process::id(), thread_local_fn as usize),
+            );
+        }
+        Lazy::force(&CONTROL_WRITER);
 
         self.install_crash_handlers();
 
-        let stdin = std::io::stdin();
-        for line in stdin.lock().lines() {
+        let commands = match receive_commands() {
+            Ok(commands) => commands,
+            Err(error) => {
+                eprintln!("Failed to set up command channel: {error}");
+                std::process::exit(99);
+            }
+        };
+        for line in commands {
             if let Err(error) = self.handle_line(&line) {
                 eprintln!("While processing instruction `{line:?}`, got error: {error:?}",);
                 std::process::exit(99);
@@ -66,27 +254,56 @@ impl Runtime {
     fn handle_line(&mut self, line: &io::Result<String>) -> Result<(), Error> {
         let line = line.as_ref()?;
         static LOAD_AND_RUN: Lazy<Regex> =
-            Lazy::new(|| Regex::new("LOAD_AND_RUN ([^ ]+) ([^ ]+)").unwrap());
+            Lazy::new(|| Regex::new("LOAD_AND_RUN ([^ ]+) ([^ ]+) ([0-9]+)").unwrap());
         if let Some(captures) = LOAD_AND_RUN.captures(line) {
-            self.load_and_run(&captures[1], &captures[2])
+            self.load_and_run(&captures[1], &captures[2], &captures[3])
+        } else if line == VARIABLE_DETAILS {
+            self.print_variable_details()
         } else {
             bail!("Unrecognised line: {}", line);
         }
     }
 
-    fn load_and_run(&mut self, so_path: &str, fn_name: &str) -> Result<(), Error> {
+    fn load_and_run(&mut self, so_path: &str, fn_name: &str, build_num: &str) -> Result<(), Error> {
         use std::os::raw::c_void;
         let shared_object = unsafe { libloading::Library::new(so_path) }?;
         unsafe {
             let user_fn = shared_object
                 .get::<extern "C" fn(*mut c_void) -> *mut c_void>(fn_name.as_bytes())?;
+            evcxr_internal_runtime::send_control(
+                &format!("{EVCXR_EXECUTION_STARTED}{build_num}"),
+                "",
+            );
+            // Output that the user's code prints needs to be seen to come after this.
+            wait_for_acknowledgement();
+            user_stdin::set_cell_running(true);
             self.variable_store_ptr = user_fn(self.variable_store_ptr);
+            user_stdin::set_cell_running(false);
         }
-        println!("{EVCXR_EXECUTION_COMPLETE}");
+        evcxr_internal_runtime::send_control(EVCXR_EXECUTION_COMPLETE, "");
         self.shared_objects.push(shared_object);
         Ok(())
     }
 
+    fn print_variable_details(&mut self) -> Result<(), Error> {
+        use std::os::raw::c_void;
+        if !self.variable_store_ptr.is_null() {
+            // The most recently loaded code may not have needed a variable store, in which case it
+            // won't export the function. Code from earlier loads remains valid, since we never
+            // unload anything.
+            for shared_object in self.shared_objects.iter().rev() {
+                if let Ok(details_fn) = unsafe {
+                    shared_object.get::<extern "C" fn(*mut c_void)>(VARIABLE_DETAILS_FN.as_bytes())
+                } {
+                    details_fn(self.variable_store_ptr);
+                    break;
+                }
+            }
+        }
+        evcxr_internal_runtime::send_control(EVCXR_EXECUTION_COMPLETE, "");
+        Ok(())
+    }
+
     #[cfg(all(unix, not(target_os = "freebsd")))]
     pub fn install_crash_handlers(&self) {
         use backtrace::Backtrace;
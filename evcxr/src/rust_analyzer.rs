@@ -60,6 +60,9 @@ pub(crate) struct RustAnalyzer {
 #[derive(Debug, PartialEq, Eq)]
 pub enum TypeName {
     Named(String),
+    /// A type that can't be named, such as a closure or an `impl Trait`, but which can be stored as
+    /// the contained boxed trait object, e.g. `Box<dyn Fn(i32) -> i32>`.
+    Boxed(String),
     Closure,
     Unknown,
 }
@@ -370,6 +373,21 @@ impl RustAnalyzer {
         })
     }
 
+    /// Returns the expansion of the macro invoked at `position`, which should be the offset of the
+    /// macro's name, or None if there's nothing there that we can expand.
+    pub(crate) fn expand_macro(&self, position: usize) -> Result<Option<String>> {
+        match self
+            .analysis_host
+            .analysis()
+            .expand_macro(ra_ide::FilePosition {
+                file_id: self.source_file_id,
+                offset: (position as u32).into(),
+            }) {
+            Ok(expanded) => Ok(expanded.map(|expanded| expanded.expansion)),
+            _ => bail!("expand macro fail"),
+        }
+    }
+
     pub(crate) fn hover(
         &self,
         text_range: TextRange,
@@ -454,17 +472,72 @@ fn get_type_name(
     }
     if let Some(ty) = inferred_type {
         if ty.is_closure() {
-            return TypeName::Closure;
+            return boxed_type_name(&ty, sema, module).unwrap_or(TypeName::Closure);
         }
         if let Ok(type_name) = ty.display_source_code(sema.db, module.into(), true)
             && is_type_valid(&type_name)
         {
+            // Opaque types and closures nested in other types, e.g. in iterator adapters, are
+            // displayed as `impl Trait`, which we can't use as the type of a variable.
+            if contains_impl_trait(&type_name)
+                && let Some(boxed) = boxed_type_name(&ty, sema, module)
+            {
+                return boxed;
+            }
             return TypeName::Named(type_name);
         }
     }
     TypeName::Unknown
 }
 
+/// Returns the boxed trait object type that a value of type `ty` can be stored as, if `ty` is a
+/// closure, or an opaque type that implements one of the `Fn` traits or `Iterator`.
+fn boxed_type_name(
+    ty: &ra_hir::Type,
+    sema: &ra_hir::Semantics<ra_ide::RootDatabase>,
+    module: ra_hir::Module,
+) -> Option<TypeName> {
+    use ra_hir::HirDisplay;
+    let source_code = |ty: &ra_hir::Type| {
+        ty.display_source_code(sema.db, module.into(), true)
+            .ok()
+            .filter(|type_name| is_type_valid(type_name))
+    };
+    let trait_object = if let Some(callable) = ty.as_callable(sema.db) {
+        let fn_trait = match callable.kind() {
+            ra_hir::CallableKind::Closure(closure) => closure.fn_trait(sema.db),
+            ra_hir::CallableKind::FnImpl(fn_trait) => fn_trait,
+            _ => return None,
+        };
+        if !matches!(
+            fn_trait,
+            ra_hir::FnTrait::Fn | ra_hir::FnTrait::FnMut | ra_hir::FnTrait::FnOnce
+        ) {
+            return None;
+        }
+        let params = callable
+            .params()
+            .iter()
+            .map(|param| source_code(param.ty()))
+            .collect::<Option<Vec<_>>>()?
+            .join(", ");
+        let return_type = callable.return_type();
+        if return_type.is_unit() {
+            format!("{fn_trait}({params})")
+        } else {
+            format!("{fn_trait}({params}) -> {}", source_code(&return_type)?)
+        }
+    } else if ty.clone().impls_iterator(sema.db) {
+        format!(
+            "Iterator<Item = {}>",
+            source_code(&ty.clone().iterator_item(sema.db)?)?
+        )
+    } else {
+        return None;
+    };
+    Some(TypeName::Boxed(format!("Box<dyn {trait_object}>")))
+}
+
 /// Completions found in a particular context.
 #[derive(Default)]
 pub struct Completions {
@@ -545,10 +618,22 @@ pub(crate) fn is_type_valid(type_name: &str) -> bool {
     true
 }
 
+/// Returns whether `type_name`, which should be valid, contains an `impl Trait` type.
+fn contains_impl_trait(type_name: &str) -> bool {
+    use ra_ap_syntax::SyntaxKind;
+    let wrapped_source = format!("const _: {type_name} = foo();");
+    let parsed = ast::SourceFile::parse(&wrapped_source, EDITION);
+    parsed
+        .syntax_node()
+        .descendants()
+        .any(|node| node.kind() == SyntaxKind::IMPL_TRAIT_TYPE)
+}
+
 #[cfg(test)]
 mod test {
     use super::RustAnalyzer;
     use super::TypeName;
+    use super::contains_impl_trait;
     use super::is_type_valid;
     use anyhow::Result;
 
@@ -630,4 +715,14 @@ mod test {
         assert!(!is_type_valid("Vec<_>"));
         assert!(is_type_valid("Foo<42>"));
     }
+
+    #[test]
+    fn test_contains_impl_trait() {
+        assert!(contains_impl_trait("impl Iterator<Item = u32>"));
+        assert!(contains_impl_trait(
+            "Map<Range<i32>, impl FnMut(i32) -> i32>"
+        ));
+        assert!(!contains_impl_trait("Box<dyn Fn(i32) -> i32>"));
+        assert!(!contains_impl_trait("Vec<String>"));
+    }
 }
@@ -0,0 +1,355 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE
+// or https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Restricts what the runtime subprocess can do. When sandboxed, the subprocess can only write to
+//! the directories that it's given and can't create sockets, so can't use the network or connect to
+//! Unix sockets of services on the host. Restrictions are inherited by any processes that it starts.
+//! Compilation happens in other processes, so isn't affected.
+
+use crate::errors::Error;
+use crate::errors::bail;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Stdio;
+
+/// If set, the runtime subprocess sandboxes itself before doing anything else. The value is a list
+/// of directories, in the same format as PATH, that the subprocess may write to.
+pub(crate) const SANDBOX_ENV: &str = "EVCXR_SANDBOX";
+
+/// Configures `command` so that the process that it starts will sandbox itself, permitting writes
+/// only within `writable_dirs`. The first of `writable_dirs` becomes the working directory. If
+/// `writable_dirs` is empty, then the process won't be sandboxed.
+pub(crate) fn configure_command(
+    command: &mut Command,
+    writable_dirs: &[PathBuf],
+) -> Result<(), Error> {
+    let Some(working_dir) = writable_dirs.first() else {
+        command.env_remove(SANDBOX_ENV);
+        return Ok(());
+    };
+    if cfg!(not(target_os = "linux")) {
+        bail!("Sandboxing is only supported on Linux");
+    }
+    for dir in writable_dirs {
+        std::fs::create_dir_all(dir)?;
+    }
+    let Ok(dirs) = std::env::join_paths(writable_dirs) else {
+        bail!("Sandbox directories can't contain path separators");
+    };
+    command.env(SANDBOX_ENV, dirs).current_dir(working_dir);
+    Ok(())
+}
+
+/// If set along with SANDBOX_ENV, the runtime subprocess exits as soon as it has sandboxed itself.
+pub(crate) const SANDBOX_PROBE_ENV: &str = "EVCXR_SANDBOX_PROBE";
+
+/// Checks that a process started by `command` is able to sandbox itself, permitting writes only
+/// within `writable_dirs`. This is done using a separate process that exits as soon as it's
+/// sandboxed, since once a session's subprocess is sandboxed, it can't be unsandboxed, so if
+/// sandboxing failed, the session would be unusable.
+pub(crate) fn probe(command: &Command, writable_dirs: &[PathBuf]) -> Result<(), Error> {
+    let mut probe = Command::new(command.get_program());
+    probe.args(command.get_args());
+    for (key, value) in command.get_envs() {
+        match value {
+            Some(value) => probe.env(key, value),
+            None => probe.env_remove(key),
+        };
+    }
+    configure_command(&mut probe, writable_dirs)?;
+    let output = match probe
+        .env(SANDBOX_PROBE_ENV, "1")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .output()
+    {
+        Ok(output) => output,
+        Err(error) => bail!("Failed to check whether sandboxing works: {}", error),
+    };
+    if !output.status.success() {
+        bail!(
+            "Sandboxing isn't supported here. {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Called by the runtime subprocess on startup, prior to starting any threads.
+pub(crate) fn enter_if_configured() -> Result<(), Error> {
+    if let Some(dirs) = std::env::var_os(SANDBOX_ENV) {
+        let writable_dirs: Vec<PathBuf> = std::env::split_paths(&dirs).collect();
+        enter(&writable_dirs)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn enter(_writable_dirs: &[PathBuf]) -> Result<(), Error> {
+    bail!("Sandboxing is only supported on Linux");
+}
+
+#[cfg(target_os = "linux")]
+fn enter(writable_dirs: &[PathBuf]) -> Result<(), Error> {
+    // Required in order for an unprivileged process to install a seccomp filter or restrict itself
+    // with landlock. It also means that nothing we run can gain privileges, e.g. via setuid.
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        bail!(
+            "Failed to set no_new_privs: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    landlock::restrict_writes(writable_dirs)?;
+    seccomp::deny_network()?;
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod landlock {
+    use super::*;
+    use std::os::fd::AsRawFd;
+    use std::os::fd::FromRawFd;
+    use std::os::fd::OwnedFd;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    // Definitions from linux/landlock.h, which libc doesn't provide.
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    const CREATE_RULESET_VERSION: u32 = 1;
+    const RULE_PATH_BENEATH: u32 = 1;
+
+    const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+    const ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+    const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+    const ACCESS_FS_MAKE_CHAR: u64 = 1 << 6;
+    const ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+    const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+    const ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+    const ACCESS_FS_MAKE_FIFO: u64 = 1 << 10;
+    const ACCESS_FS_MAKE_BLOCK: u64 = 1 << 11;
+    const ACCESS_FS_MAKE_SYM: u64 = 1 << 12;
+    /// Added in ABI version 2.
+    const ACCESS_FS_REFER: u64 = 1 << 13;
+    /// Added in ABI version 3.
+    const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+    /// Files that are commonly written to, but which are harmless.
+    const WRITABLE_FILES: &[&str] = &["/dev/null"];
+
+    pub(super) fn restrict_writes(writable_dirs: &[PathBuf]) -> Result<(), Error> {
+        let abi = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<RulesetAttr>(),
+                0,
+                CREATE_RULESET_VERSION,
+            )
+        };
+        if abi < 1 {
+            bail!(
+                "Sandboxing requires landlock, which isn't available: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        let mut file_access = ACCESS_FS_WRITE_FILE;
+        if abi >= 3 {
+            file_access |= ACCESS_FS_TRUNCATE;
+        }
+        let mut dir_access = file_access
+            | ACCESS_FS_REMOVE_DIR
+            | ACCESS_FS_REMOVE_FILE
+            | ACCESS_FS_MAKE_CHAR
+            | ACCESS_FS_MAKE_DIR
+            | ACCESS_FS_MAKE_REG
+            | ACCESS_FS_MAKE_SOCK
+            | ACCESS_FS_MAKE_FIFO
+            | ACCESS_FS_MAKE_BLOCK
+            | ACCESS_FS_MAKE_SYM;
+        if abi >= 2 {
+            dir_access |= ACCESS_FS_REFER;
+        }
+        let attr = RulesetAttr {
+            handled_access_fs: dir_access,
+        };
+        let ruleset = check_fd(
+            unsafe {
+                libc::syscall(
+                    libc::SYS_landlock_create_ruleset,
+                    &attr,
+                    std::mem::size_of::<RulesetAttr>(),
+                    0,
+                )
+            },
+            "create landlock ruleset",
+        )?;
+        for dir in writable_dirs {
+            add_rule(&ruleset, dir, dir_access)?;
+        }
+        for file in WRITABLE_FILES {
+            let file = Path::new(file);
+            if file.exists() {
+                add_rule(&ruleset, file, file_access)?;
+            }
+        }
+        if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) } != 0 {
+            bail!(
+                "Failed to apply landlock ruleset: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        Ok(())
+    }
+
+    fn add_rule(ruleset: &OwnedFd, path: &Path, allowed_access: u64) -> Result<(), Error> {
+        let file = std::fs::File::options()
+            .read(true)
+            .custom_flags(libc::O_PATH)
+            .open(path);
+        let file = match file {
+            Ok(file) => file,
+            Err(error) => bail!("Failed to open '{}': {}", path.display(), error),
+        };
+        let attr = PathBeneathAttr {
+            allowed_access,
+            parent_fd: file.as_raw_fd(),
+        };
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                RULE_PATH_BENEATH,
+                &attr,
+                0,
+            )
+        };
+        if result != 0 {
+            bail!(
+                "Failed to allow writes to '{}': {}",
+                path.display(),
+                std::io::Error::last_os_error()
+            );
+        }
+        Ok(())
+    }
+
+    fn check_fd(result: libc::c_long, action: &str) -> Result<OwnedFd, Error> {
+        if result < 0 {
+            bail!("Failed to {}: {}", action, std::io::Error::last_os_error());
+        }
+        // Safety: The syscall succeeded, so returned a file descriptor that we now own.
+        Ok(unsafe { OwnedFd::from_raw_fd(result as i32) })
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod seccomp {
+    use super::*;
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    /// System calls with numbers at or above this on x86_64 use the x32 ABI, which we don't
+    /// permit, since our filter checks syscall numbers for the native ABI.
+    #[cfg(target_arch = "x86_64")]
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+    #[cfg(not(target_arch = "x86_64"))]
+    const X32_SYSCALL_BIT: u32 = u32::MAX;
+
+    // Offsets of fields within `struct seccomp_data`.
+    const NR_OFFSET: u32 = 0;
+    const ARCH_OFFSET: u32 = 4;
+
+    fn statement(code: u32, k: u32) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+
+    fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt,
+            jf,
+            k,
+        }
+    }
+
+    /// Installs a filter that causes creation of sockets to fail. This includes Unix domain
+    /// sockets, since otherwise the sandboxed code could connect to sockets of services on the host,
+    /// such as Docker or D-Bus. socketpair is still permitted, since the sockets that it creates
+    /// can only be connected to each other. io_uring is also denied, since it can be used to create
+    /// sockets without going through the socket system call.
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    pub(super) fn deny_network() -> Result<(), Error> {
+        use libc::BPF_ABS;
+        use libc::BPF_JEQ;
+        use libc::BPF_JGE;
+        use libc::BPF_JMP;
+        use libc::BPF_K;
+        use libc::BPF_LD;
+        use libc::BPF_RET;
+        use libc::BPF_W;
+        let deny = libc::SECCOMP_RET_ERRNO | libc::EACCES as u32;
+        let filter = [
+            statement(BPF_LD | BPF_W | BPF_ABS, ARCH_OFFSET),
+            jump(BPF_JMP | BPF_JEQ | BPF_K, AUDIT_ARCH, 1, 0),
+            statement(BPF_RET | BPF_K, deny),
+            statement(BPF_LD | BPF_W | BPF_ABS, NR_OFFSET),
+            jump(BPF_JMP | BPF_JGE | BPF_K, X32_SYSCALL_BIT, 0, 1),
+            statement(BPF_RET | BPF_K, deny),
+            jump(
+                BPF_JMP | BPF_JEQ | BPF_K,
+                libc::SYS_io_uring_setup as u32,
+                0,
+                1,
+            ),
+            statement(BPF_RET | BPF_K, deny),
+            jump(BPF_JMP | BPF_JEQ | BPF_K, libc::SYS_socket as u32, 0, 1),
+            statement(BPF_RET | BPF_K, deny),
+            statement(BPF_RET | BPF_K, libc::SECCOMP_RET_ALLOW),
+        ];
+        let program = libc::sock_fprog {
+            len: filter.len() as u16,
+            filter: filter.as_ptr() as *mut libc::sock_filter,
+        };
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                libc::SECCOMP_FILTER_FLAG_TSYNC,
+                &program,
+            )
+        };
+        if result != 0 {
+            bail!(
+                "Failed to install seccomp filter: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        Ok(())
+    }
+
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    pub(super) fn deny_network() -> Result<(), Error> {
+        bail!("Sandboxing isn't supported on this architecture");
+    }
+}
@@ -0,0 +1,364 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE
+// or https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::errors::Error;
+use crate::errors::bail;
+use crate::statement_splitter;
+use ra_ap_syntax::AstNode;
+use ra_ap_syntax::SyntaxKind;
+use ra_ap_syntax::SyntaxNode;
+use ra_ap_syntax::ast;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashSet;
+use std::path::Path;
+
+/// Bumped whenever a change is made to `SessionFile` that would prevent older files from loading
+/// correctly.
+const SESSION_FORMAT_VERSION: u32 = 1;
+
+/// A cell that was successfully executed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Cell {
+    /// The Rust code that the user supplied, excluding any commands.
+    pub(crate) code: String,
+    /// Variables that were newly defined by this cell.
+    pub(crate) defined_variables: Vec<String>,
+    /// The text displayed for the cell's final expression, if any.
+    #[serde(default)]
+    pub(crate) output: Option<String>,
+    /// Whether the cell's final expression, if any, was displayed by calling its `evcxr_display`
+    /// method, rather than by formatting it.
+    #[serde(default)]
+    pub(crate) evcxr_display: bool,
+    /// Items defined by this cell and the names that the cell refers to.
+    #[serde(default)]
+    pub(crate) names: CellNames,
+}
+
+impl Cell {
+    /// Returns the statements and expressions from this cell. Items, use statements and attributes
+    /// are omitted, since those get stored in `ContextState` and don't need to be run again.
+    pub(crate) fn statements(&self) -> String {
+        let mut out = String::new();
+        for statement in statement_splitter::split_into_statements(&self.code) {
+            if ast::Item::can_cast(statement.node.kind())
+                || ast::Attr::can_cast(statement.node.kind())
+            {
+                continue;
+            }
+            out.push_str(statement.code);
+        }
+        out
+    }
+
+    /// Like `statements`, but suitable for running outside of Evcxr. If the cell ends with an
+    /// expression, code to display it the same way as Evcxr did is emitted in its place, i.e. by
+    /// calling `evcxr_display` or by printing it using `output_format`.
+    pub(crate) fn standalone_statements(&self, output_format: &str) -> String {
+        self.statements_with_final_expression(|expression| {
+            if self.evcxr_display {
+                format!("({expression}).evcxr_display();\n")
+            } else {
+                format!("println!(\"{output_format}\", &({expression}));\n")
+            }
+        })
+    }
+
+    /// Like `standalone_statements`, but instead of printing the final expression, asserts that
+    /// it formats the same as it did when the cell was run. Expressions that were displayed by
+    /// calling `evcxr_display` are displayed the same way, but not checked.
+    pub(crate) fn test_statements(&self, output_format: &str) -> String {
+        self.statements_with_final_expression(|expression| match &self.output {
+            _ if self.evcxr_display => format!("({expression}).evcxr_display();\n"),
+            Some(output) => {
+                format!("assert_eq!(format!(\"{output_format}\", &({expression})), {output:?});\n")
+            }
+            None => format!("let _ = {expression};\n"),
+        })
+    }
+
+    fn statements_with_final_expression(
+        &self,
+        final_expression: impl Fn(&str) -> String,
+    ) -> String {
+        let statements = statement_splitter::split_into_statements(&self.code);
+        let num_statements = statements.len();
+        let mut out = String::new();
+        for (index, statement) in statements.into_iter().enumerate() {
+            if ast::Item::can_cast(statement.node.kind())
+                || ast::Attr::can_cast(statement.node.kind())
+            {
+                continue;
+            }
+            if index == num_statements - 1 && ast::Expr::can_cast(statement.node.kind()) {
+                out.push_str(&final_expression(statement.code.trim_end()));
+            } else {
+                out.push_str(statement.code);
+            }
+        }
+        out
+    }
+}
+
+/// Names defined and referenced by a cell. Names are collected from identifiers, including those
+/// within macro invocations, so may include things that aren't actually references to items or
+/// variables. That's fine, since we only use them to determine which cells might need to be run
+/// again.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct CellNames {
+    /// Items, including imports, that the cell defined.
+    pub(crate) defined_items: Vec<String>,
+    /// For each item defined by the cell, the names that its definition refers to.
+    pub(crate) item_references: BTreeMap<String, BTreeSet<String>>,
+    /// Names referred to by the cell's statements and expressions.
+    pub(crate) references: BTreeSet<String>,
+}
+
+impl CellNames {
+    pub(crate) fn add_item(&mut self, name: &str, node: &SyntaxNode) {
+        if !self.defined_items.iter().any(|item| item == name) {
+            self.defined_items.push(name.to_owned());
+        }
+        let references = self.item_references.entry(name.to_owned()).or_default();
+        references.extend(identifiers(node).filter(|identifier| identifier != name));
+    }
+
+    pub(crate) fn add_statement(&mut self, node: &SyntaxNode) {
+        self.references.extend(identifiers(node));
+    }
+}
+
+fn identifiers(node: &SyntaxNode) -> impl Iterator<Item = String> {
+    node.descendants_with_tokens()
+        .filter_map(|element| element.into_token())
+        .filter(|token| token.kind() == SyntaxKind::IDENT)
+        .map(|token| token.text().to_owned())
+}
+
+/// What to do when a cell redefines an item that was used by earlier cells.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ReactiveMode {
+    Off,
+    /// Report which cells are stale.
+    List,
+    /// Run the statements of stale cells again.
+    Rerun,
+}
+
+/// Returns the indices of cells that ran against an earlier definition of an item that the last
+/// cell in `cells` redefined. This includes cells that used such an item indirectly, via some
+/// other item or via a variable defined by another stale cell.
+pub(crate) fn stale_cells(cells: &[Cell]) -> Vec<usize> {
+    let Some((last, earlier)) = cells.split_last() else {
+        return Vec::new();
+    };
+    let mut stale_names: HashSet<&str> = last
+        .names
+        .defined_items
+        .iter()
+        .filter(|item| {
+            earlier
+                .iter()
+                .any(|cell| cell.names.defined_items.contains(item))
+        })
+        .map(String::as_str)
+        .collect();
+    if stale_names.is_empty() {
+        return Vec::new();
+    }
+    // Later definitions of an item replace earlier ones.
+    let mut item_references: BTreeMap<&str, &BTreeSet<String>> = BTreeMap::new();
+    for cell in cells {
+        for (name, references) in &cell.names.item_references {
+            item_references.insert(name, references);
+        }
+    }
+    loop {
+        let newly_stale: Vec<&str> = item_references
+            .iter()
+            .filter(|(name, references)| {
+                !stale_names.contains(*name)
+                    && references
+                        .iter()
+                        .any(|reference| stale_names.contains(reference.as_str()))
+            })
+            .map(|(name, _)| *name)
+            .collect();
+        if newly_stale.is_empty() {
+            break;
+        }
+        stale_names.extend(newly_stale);
+    }
+    let mut stale = Vec::new();
+    for (index, cell) in earlier.iter().enumerate() {
+        if cell
+            .names
+            .references
+            .iter()
+            .any(|reference| stale_names.contains(reference.as_str()))
+        {
+            stale.push(index);
+            stale_names.extend(cell.defined_variables.iter().map(String::as_str));
+        }
+    }
+    stale
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct SessionDependency {
+    pub(crate) name: String,
+    pub(crate) config: String,
+}
+
+/// Everything needed to recreate a session in a new process. Produced by
+/// `ContextState::to_session_file`.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct SessionFile {
+    pub(crate) version: u32,
+    /// Keyed by the normalized crate name.
+    pub(crate) dependencies: BTreeMap<String, SessionDependency>,
+    pub(crate) extern_crate_stmts: BTreeMap<String, String>,
+    pub(crate) items: BTreeMap<String, String>,
+    pub(crate) unnamed_items: Vec<String>,
+    pub(crate) attributes: BTreeMap<String, String>,
+    pub(crate) async_mode: bool,
+    pub(crate) allow_question_mark: bool,
+    pub(crate) error_format: String,
+    pub(crate) output_format: String,
+    pub(crate) display_types: bool,
+    pub(crate) opt_level: String,
+    pub(crate) preserve_vars_on_panic: bool,
+    #[serde(default)]
+    pub(crate) checkpoint_vars: bool,
+    #[serde(default)]
+    pub(crate) migrate_vars: bool,
+    pub(crate) toolchain: String,
+    pub(crate) build_envs: BTreeMap<String, String>,
+    pub(crate) cells: Vec<Cell>,
+}
+
+impl SessionFile {
+    pub(crate) fn current_version() -> u32 {
+        SESSION_FORMAT_VERSION
+    }
+
+    pub(crate) fn write(&self, path: &Path) -> Result<(), Error> {
+        let json = serde_json::to_string_pretty(self)?;
+        if let Err(error) = std::fs::write(path, json) {
+            bail!("Error writing session to '{}': {}", path.display(), error);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn read(path: &Path) -> Result<SessionFile, Error> {
+        let json = match std::fs::read_to_string(path) {
+            Ok(json) => json,
+            Err(error) => bail!("Error reading session from '{}': {}", path.display(), error),
+        };
+        let session: SessionFile = serde_json::from_str(&json)?;
+        if session.version != SESSION_FORMAT_VERSION {
+            bail!(
+                "Session file '{}' has version {}, but only version {} is supported",
+                path.display(),
+                session.version,
+                SESSION_FORMAT_VERSION
+            );
+        }
+        Ok(session)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cell;
+    use super::CellNames;
+    use crate::statement_splitter;
+    use ra_ap_syntax::AstNode;
+    use ra_ap_syntax::ast;
+
+    fn cell(code: &str, defined_variables: &[&str]) -> Cell {
+        let mut names = CellNames::default();
+        for statement in statement_splitter::split_into_statements(code) {
+            if let Some(item) = ast::Item::cast(statement.node.clone()) {
+                if let Some(name) = crate::item::item_name(&item) {
+                    names.add_item(&name, &statement.node);
+                }
+            } else {
+                names.add_statement(&statement.node);
+            }
+        }
+        Cell {
+            code: code.to_owned(),
+            defined_variables: defined_variables.iter().map(|v| (*v).to_owned()).collect(),
+            output: None,
+            evcxr_display: false,
+            names,
+        }
+    }
+
+    #[test]
+    fn statements_exclude_items() {
+        let cell = Cell {
+            code: "use std::fmt::Debug; fn foo() -> i32 {42} let x = foo(); x + 1".to_owned(),
+            defined_variables: vec!["x".to_owned()],
+            output: Some("43".to_owned()),
+            evcxr_display: false,
+            names: Default::default(),
+        };
+        assert_eq!(cell.statements(), "let x = foo(); x + 1");
+    }
+
+    #[test]
+    fn final_expression_replaced() {
+        let cell = Cell {
+            code: "fn foo() -> i32 {42} let x = foo(); x + 1".to_owned(),
+            defined_variables: vec!["x".to_owned()],
+            output: Some("43".to_owned()),
+            evcxr_display: false,
+            names: Default::default(),
+        };
+        assert_eq!(
+            cell.standalone_statements("{:?}"),
+            "let x = foo(); println!(\"{:?}\", &(x + 1));\n"
+        );
+        assert_eq!(
+            cell.test_statements("{:?}"),
+            "let x = foo(); assert_eq!(format!(\"{:?}\", &(x + 1)), \"43\");\n"
+        );
+        let cell = Cell {
+            evcxr_display: true,
+            ..cell
+        };
+        assert_eq!(
+            cell.standalone_statements("{:?}"),
+            "let x = foo(); (x + 1).evcxr_display();\n"
+        );
+        assert_eq!(
+            cell.test_statements("{:?}"),
+            "let x = foo(); (x + 1).evcxr_display();\n"
+        );
+    }
+
+    #[test]
+    fn stale_cells_after_redefinition() {
+        let cells = vec![
+            cell("fn f() -> i32 {1} fn g() -> i32 {f() + 1}", &[]),
+            cell("let a = f();", &["a"]),
+            cell("let b = g();", &["b"]),
+            cell("let c = 42;", &["c"]),
+            cell("println!(\"{}\", a + c);", &[]),
+            cell("let d = c + 1;", &["d"]),
+            cell("fn f() -> i32 {2}", &[]),
+        ];
+        assert_eq!(super::stale_cells(&cells), vec![1, 2, 4]);
+        // Defining a new item doesn't make anything stale.
+        assert!(super::stale_cells(&cells[..6]).is_empty());
+    }
+}
@@ -0,0 +1,222 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE
+// or https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Each cell is compiled into a new library that contains all the items defined so far, so a
+//! static would otherwise be reinitialized whenever a cell is run. We rewrite statics whose values
+//! can change so that their values are stored with the runtime, where they persist for the session.
+
+use crate::code_block::CodeBlock;
+use crate::code_block::Segment;
+use crate::item;
+use ra_ap_syntax::AstNode;
+use ra_ap_syntax::SourceFile;
+use ra_ap_syntax::SyntaxNode;
+use ra_ap_syntax::TextRange;
+use ra_ap_syntax::ast;
+use ra_ap_syntax::ast::HasModuleItem;
+use std::ops::Range;
+
+/// If `node` declares statics that need to be stored with the runtime, returns the name of each
+/// together with replacement code for its definition. `segment` contains the code for `node`.
+/// `generation` distinguishes this definition from earlier ones with the same name, so that
+/// redefining a static gives it a new value.
+pub(crate) fn session_statics(
+    node: &SyntaxNode,
+    segment: &Segment,
+    generation: i32,
+) -> Option<Vec<(String, CodeBlock)>> {
+    let node_offset = segment.code.find(&node.text().to_string())?;
+    // Converts a range within `node` to a range within `segment`.
+    let node_start = node.text_range().start();
+    let to_segment_range = |range: TextRange| {
+        usize::from(range.start() - node_start) + node_offset
+            ..usize::from(range.end() - node_start) + node_offset
+    };
+    if let Some(ast::Item::Static(static_item)) = ast::Item::cast(node.clone()) {
+        if static_item.mut_token().is_some() || !static_item.ty().is_some_and(|ty| may_change(&ty))
+        {
+            return None;
+        }
+        let rewritten = rewrite_static(
+            &static_item,
+            "SessionStatic",
+            segment,
+            generation,
+            to_segment_range,
+        )?;
+        return Some(vec![rewritten]);
+    }
+    let token_tree = thread_local_call(node)?.token_tree()?;
+    let tree_range = to_segment_range(token_tree.syntax().text_range());
+    // Skip the opening delimiter.
+    let contents_start = tree_range.start + 1;
+    let mut statics = Vec::new();
+    for item in parse_thread_local_contents(&token_tree).items() {
+        let ast::Item::Static(static_item) = item else {
+            return None;
+        };
+        statics.push(rewrite_static(
+            &static_item,
+            "SessionThreadLocal",
+            segment,
+            generation,
+            |range| {
+                usize::from(range.start()) + contents_start
+                    ..usize::from(range.end()) + contents_start
+            },
+        )?);
+    }
+    Some(statics)
+}
+
+/// Returns the name of the static if `node` declares a `static mut`. These can't be stored with the
+/// runtime, since code that uses them needs a place that it can assign to.
+pub(crate) fn static_mut_name(node: &SyntaxNode) -> Option<String> {
+    let Some(ast::Item::Static(static_item)) = ast::Item::cast(node.clone()) else {
+        return None;
+    };
+    static_item.mut_token()?;
+    Some(ast::HasName::name(&static_item)?.text().to_string())
+}
+
+/// Returns the names of the statics declared if `node` is an invocation of `thread_local!`.
+pub(crate) fn thread_local_names(node: &SyntaxNode) -> Option<Vec<String>> {
+    let token_tree = thread_local_call(node)?.token_tree()?;
+    Some(
+        parse_thread_local_contents(&token_tree)
+            .items()
+            .filter_map(|item| item::item_name(&item))
+            .collect(),
+    )
+}
+
+/// Parses the contents of the token tree passed to `thread_local!`, excluding its delimiters.
+fn parse_thread_local_contents(token_tree: &ast::TokenTree) -> SourceFile {
+    let text = token_tree.syntax().text().to_string();
+    let contents = text.get(1..text.len().saturating_sub(1)).unwrap_or("");
+    SourceFile::parse(contents, crate::rust_analyzer::EDITION).tree()
+}
+
+/// Returns the macro call if `node` is an invocation of `thread_local!`. In a function body, which
+/// is where we parse the user's code, such an invocation is an expression statement, or if it's
+/// the last thing in the cell, an expression.
+fn thread_local_call(node: &SyntaxNode) -> Option<ast::MacroCall> {
+    let macro_expr_call = |expr: ast::Expr| match expr {
+        ast::Expr::MacroExpr(macro_expr) => macro_expr.macro_call(),
+        _ => None,
+    };
+    let macro_call = match ast::Stmt::cast(node.clone()) {
+        Some(ast::Stmt::Item(ast::Item::MacroCall(macro_call))) => macro_call,
+        Some(ast::Stmt::ExprStmt(expr_stmt)) => macro_expr_call(expr_stmt.expr()?)?,
+        Some(_) => return None,
+        None => macro_expr_call(ast::Expr::cast(node.clone())?)?,
+    };
+    let name = macro_call.path()?.segment()?.syntax().text().to_string();
+    (name == "thread_local").then_some(macro_call)
+}
+
+/// Returns the name of `static_item` and code that defines it with type `wrapper<T>`, where `T` is
+/// its original type. `to_segment_range` converts ranges of `static_item` to ranges of `segment`.
+fn rewrite_static(
+    static_item: &ast::Static,
+    wrapper: &str,
+    segment: &Segment,
+    generation: i32,
+    to_segment_range: impl Fn(TextRange) -> Range<usize>,
+) -> Option<(String, CodeBlock)> {
+    let name = ast::HasName::name(static_item)?.text().to_string();
+    let static_range = to_segment_range(static_item.static_token()?.text_range());
+    let ty_range = to_segment_range(static_item.ty()?.syntax().text_range());
+    let body_range = to_segment_range(static_item.body()?.syntax().text_range());
+    let start = to_segment_range(static_item.syntax().text_range()).start;
+    let mut code = CodeBlock::new();
+    // Attributes, doc comments and visibility.
+    if static_range.start > start {
+        code = code.with_segment(segment.slice(start..static_range.start));
+    }
+    let code = code
+        .generated(format!("static {name}: evcxr_internal_runtime::{wrapper}<"))
+        .with_segment(segment.slice(ty_range))
+        .generated(format!(
+            "> = evcxr_internal_runtime::{wrapper}::new(\"evcxr_static/{name}/{generation}\", || "
+        ))
+        .with_segment(segment.slice(body_range))
+        .generated(");");
+    Some((name, code))
+}
+
+/// Returns whether a static of type `ty` might have a value that changes. Statics without interior
+/// mutability, such as those of primitive types, are left alone, since they're always initialized
+/// to the same value and can then be used exactly as the user wrote them.
+fn may_change(ty: &ast::Type) -> bool {
+    match ty {
+        ast::Type::PathType(path_type) => !path_type.path().is_some_and(|path| {
+            matches!(
+                path.syntax().text().to_string().as_str(),
+                "bool"
+                    | "char"
+                    | "str"
+                    | "f32"
+                    | "f64"
+                    | "i8"
+                    | "i16"
+                    | "i32"
+                    | "i64"
+                    | "i128"
+                    | "isize"
+                    | "u8"
+                    | "u16"
+                    | "u32"
+                    | "u64"
+                    | "u128"
+                    | "usize"
+            )
+        }),
+        ast::Type::ArrayType(array) => array.ty().is_none_or(|ty| may_change(&ty)),
+        ast::Type::SliceType(slice) => slice.ty().is_none_or(|ty| may_change(&ty)),
+        ast::Type::ParenType(paren) => paren.ty().is_none_or(|ty| may_change(&ty)),
+        ast::Type::TupleType(tuple) => tuple.fields().any(|ty| may_change(&ty)),
+        ast::Type::RefType(_)
+        | ast::Type::PtrType(_)
+        | ast::Type::FnPtrType(_)
+        | ast::Type::NeverType(_) => false,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::may_change;
+    use ra_ap_syntax::AstNode;
+    use ra_ap_syntax::SourceFile;
+    use ra_ap_syntax::ast;
+
+    fn static_type_may_change(ty: &str) -> bool {
+        let parsed = SourceFile::parse(
+            &format!("static X: {ty} = foo();"),
+            crate::rust_analyzer::EDITION,
+        );
+        let ty = parsed
+            .syntax_node()
+            .descendants()
+            .find_map(ast::Type::cast)
+            .unwrap();
+        may_change(&ty)
+    }
+
+    #[test]
+    fn test_may_change() {
+        assert!(!static_type_may_change("u32"));
+        assert!(!static_type_may_change("&str"));
+        assert!(!static_type_may_change("[(i32, bool); 3]"));
+        assert!(!static_type_may_change("&[Mutex<i32>]"));
+        assert!(static_type_may_change("AtomicUsize"));
+        assert!(static_type_may_change("std::sync::Mutex<Vec<String>>"));
+        assert!(static_type_may_change("[AtomicBool; 2]"));
+        assert!(static_type_may_change("(u8, Cell<u8>)"));
+    }
+}
@@ -31,6 +31,15 @@ struct EvcxrToml {
     prelude: Option<String>,
     #[serde(default = "default_value::opt_level")]
     opt_level: String,
+    /// How long a cell may run before it's killed, e.g. "30s".
+    timeout: Option<String>,
+    /// Maximum address space of the subprocess, e.g. "4G".
+    memory_limit: Option<String>,
+    /// Maximum CPU time used by the subprocess, e.g. "60s".
+    cpu_limit: Option<String>,
+    /// Whether to sandbox the subprocess.
+    #[serde(default)]
+    sandbox: bool,
 }
 
 pub(crate) enum TmpDirVar {
@@ -114,6 +123,27 @@ impl ConfigToml {
         config.sccache = self.evcxr.sccache.map(PathBuf::from);
         config.allow_static_linking = self.evcxr.allow_static_linking;
         config.opt_level = self.evcxr.opt_level;
+        config.timeout = self
+            .evcxr
+            .timeout
+            .as_deref()
+            .map(crate::eval_context::parse_duration_limit)
+            .transpose()?
+            .flatten();
+        config.limits.memory_bytes = self
+            .evcxr
+            .memory_limit
+            .as_deref()
+            .map(crate::child_process::parse_memory_size)
+            .transpose()?;
+        config.sandbox = self.evcxr.sandbox;
+        config.limits.cpu = self
+            .evcxr
+            .cpu_limit
+            .as_deref()
+            .map(crate::eval_context::parse_duration_limit)
+            .transpose()?
+            .flatten();
         Ok(())
     }
 
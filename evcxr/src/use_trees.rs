@@ -7,6 +7,8 @@ use ra_ap_syntax::ast;
 // or https://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use ra_ap_syntax::ast::HasModuleItem;
+
 #[derive(Debug, Eq, PartialEq)]
 pub(crate) enum Import {
     /// use x as _;
@@ -96,27 +98,26 @@ pub(crate) fn use_tree_names_do(use_tree: &ast::UseTree, out: &mut impl FnMut(Im
     process_use_tree(use_tree, &[], out);
 }
 
+/// Returns the imports from all use statements in `code`.
+pub(crate) fn use_tree_names(code: &str) -> Vec<Import> {
+    let mut out = Vec::new();
+    let file = ast::SourceFile::parse(code, crate::rust_analyzer::EDITION);
+    for item in file.tree().items() {
+        if let ast::Item::Use(use_stmt) = item
+            && let Some(use_tree) = use_stmt.use_tree()
+        {
+            use_tree_names_do(&use_tree, &mut |import| {
+                out.push(import);
+            });
+        }
+    }
+    out
+}
+
 #[cfg(test)]
 mod tests {
     use super::Import;
-    use super::use_tree_names_do;
-    use ra_ap_syntax::ast;
-    use ra_ap_syntax::ast::HasModuleItem;
-
-    fn use_tree_names(code: &str) -> Vec<Import> {
-        let mut out = Vec::new();
-        let file = ast::SourceFile::parse(code, crate::rust_analyzer::EDITION);
-        for item in file.tree().items() {
-            if let ast::Item::Use(use_stmt) = item
-                && let Some(use_tree) = use_stmt.use_tree()
-            {
-                use_tree_names_do(&use_tree, &mut |import| {
-                    out.push(import);
-                });
-            }
-        }
-        out
-    }
+    use super::use_tree_names;
 
     fn unnamed(code: &str) -> Import {
         Import::Unnamed(code.to_owned())
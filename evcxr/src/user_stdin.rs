@@ -0,0 +1,201 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE
+// or https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Lets the user's code read from stdin. On Unix, the runtime subprocess replaces its stdin with a
+//! pipe that only it writes to, and receives commands via a separate pipe. evcxr_input requests
+//! input explicitly via the control pipe, at which point evcxr gets it from
+//! `EvalCallbacks::input_reader` and sends it back a line at a time. On Linux, a thread also watches
+//! for the user's code blocking on a read from stdin without having requested input, which results
+//! in a request too. Elsewhere on Unix, stdin is non-blocking unless input has been requested, so
+//! that such reads fail rather than waiting forever. On other platforms, stdin is also used for
+//! commands, so the user's code should only read from it via evcxr_input.
+
+use std::io;
+
+/// Sent by the runtime subprocess when the user's code is blocked reading from stdin. As the
+/// message is received, evcxr sets the payload to any partial line of stdout that preceded it,
+/// since that's most likely a prompt.
+pub(crate) const STDIN_READ: &str = "EVCXR_STDIN_READ";
+
+/// Prefix of the command that provides a line of input to the user's code.
+pub(crate) const INPUT_COMMAND: &str = "INPUT ";
+
+/// Whether the runtime subprocess tells us when the user's code blocks reading from stdin.
+pub(crate) const DETECTS_READS: bool = cfg!(target_os = "linux");
+
+#[cfg(unix)]
+struct UserStdin {
+    writer: std::sync::Mutex<io::PipeWriter>,
+    cell_running: std::sync::atomic::AtomicBool,
+    /// Whether we've asked for input and are yet to receive it.
+    request_outstanding: std::sync::atomic::AtomicBool,
+}
+
+#[cfg(unix)]
+static USER_STDIN: std::sync::OnceLock<UserStdin> = std::sync::OnceLock::new();
+
+/// Replaces our stdin with a pipe to which `provide_input` writes and, if supported, starts
+/// watching for reads from it. Should be called before any other threads are started.
+#[cfg(unix)]
+pub(crate) fn redirect() -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    let (reader, writer) = io::pipe()?;
+    // Safety: dup2 doesn't affect any memory. Nothing else should be using stdin yet.
+    if unsafe { libc::dup2(reader.as_raw_fd(), libc::STDIN_FILENO) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if !DETECTS_READS {
+        set_stdin_nonblocking(true)?;
+    }
+    let user_stdin = USER_STDIN.get_or_init(|| UserStdin {
+        writer: std::sync::Mutex::new(writer),
+        cell_running: Default::default(),
+        request_outstanding: Default::default(),
+    });
+    #[cfg(target_os = "linux")]
+    std::thread::spawn(move || watch_for_reads(user_stdin));
+    #[cfg(not(target_os = "linux"))]
+    let _ = user_stdin;
+    Ok(())
+}
+
+/// Records whether user code is running. Reads from stdin outside of a cell don't result in input
+/// being requested.
+pub(crate) fn set_cell_running(running: bool) {
+    #[cfg(unix)]
+    if let Some(user_stdin) = USER_STDIN.get() {
+        user_stdin
+            .cell_running
+            .store(running, std::sync::atomic::Ordering::SeqCst);
+    }
+    #[cfg(not(unix))]
+    let _ = running;
+}
+
+/// Records that the user's code has explicitly asked for input, which it will then read from stdin.
+/// Should be called before the request is sent, so that the input can't arrive first.
+pub(crate) fn input_requested() {
+    #[cfg(unix)]
+    if let Some(user_stdin) = USER_STDIN.get() {
+        user_stdin
+            .request_outstanding
+            .store(true, std::sync::atomic::Ordering::SeqCst);
+        if !DETECTS_READS && let Err(error) = set_stdin_nonblocking(false) {
+            eprintln!("Failed to make stdin blocking: {error}");
+        }
+    }
+}
+
+/// Makes `line` available to be read from stdin by the user's code.
+#[cfg(unix)]
+pub(crate) fn provide_input(line: &str) -> io::Result<()> {
+    use std::io::Write;
+    let Some(user_stdin) = USER_STDIN.get() else {
+        return Ok(());
+    };
+    let mut writer = user_stdin.writer.lock().unwrap();
+    writer.write_all(line.as_bytes())?;
+    writer.write_all(b"\n")?;
+    user_stdin
+        .request_outstanding
+        .store(false, std::sync::atomic::Ordering::SeqCst);
+    // A read that's already waiting will still get the line, as will a later one, since the line
+    // is already in the pipe.
+    if !DETECTS_READS {
+        set_stdin_nonblocking(true)?;
+    }
+    Ok(())
+}
+
+/// Sets whether reads from stdin fail rather than wait when there's nothing to read.
+#[cfg(unix)]
+fn set_stdin_nonblocking(nonblocking: bool) -> io::Result<()> {
+    // Safety: Getting and setting the flags of stdin doesn't affect any memory.
+    unsafe {
+        let flags = libc::fcntl(libc::STDIN_FILENO, libc::F_GETFL);
+        if flags < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let flags = if nonblocking {
+            flags | libc::O_NONBLOCK
+        } else {
+            flags & !libc::O_NONBLOCK
+        };
+        if libc::fcntl(libc::STDIN_FILENO, libc::F_SETFL, flags) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn watch_for_reads(user_stdin: &UserStdin) {
+    use std::sync::atomic::Ordering;
+    loop {
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        if user_stdin.cell_running.load(Ordering::SeqCst)
+            && !user_stdin.request_outstanding.load(Ordering::SeqCst)
+            && stdin_is_empty()
+            && thread_blocked_reading_stdin()
+        {
+            user_stdin.request_outstanding.store(true, Ordering::SeqCst);
+            crate::evcxr_internal_runtime::send_control(STDIN_READ, "");
+        }
+    }
+}
+
+/// Returns whether there's nothing waiting to be read from stdin.
+#[cfg(target_os = "linux")]
+fn stdin_is_empty() -> bool {
+    let mut available: libc::c_int = 0;
+    // Safety: FIONREAD writes a c_int to the supplied pointer.
+    let result = unsafe { libc::ioctl(libc::STDIN_FILENO, libc::FIONREAD, &mut available) };
+    result == 0 && available == 0
+}
+
+/// Returns whether any of our threads is in a system call that reads from stdin.
+#[cfg(target_os = "linux")]
+fn thread_blocked_reading_stdin() -> bool {
+    let Ok(tasks) = std::fs::read_dir("/proc/self/task") else {
+        return false;
+    };
+    tasks.flatten().any(|task| {
+        std::fs::read_to_string(task.path().join("syscall"))
+            .is_ok_and(|syscall| is_stdin_read(&syscall))
+    })
+}
+
+/// Returns whether `syscall`, the content of /proc/<pid>/task/<tid>/syscall, is for a read from
+/// stdin. The content starts with the system call number followed by the arguments in hex, or is
+/// "running" or "-1 ..." if the thread isn't in a system call.
+#[cfg(target_os = "linux")]
+fn is_stdin_read(syscall: &str) -> bool {
+    let mut parts = syscall.split_whitespace();
+    let (Some(Ok(number)), Some(fd)) = (parts.next().map(str::parse::<libc::c_long>), parts.next())
+    else {
+        return false;
+    };
+    (number == libc::SYS_read || number == libc::SYS_readv) && fd == "0x0"
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::is_stdin_read;
+
+    #[test]
+    fn test_is_stdin_read() {
+        let read = libc::SYS_read;
+        assert!(is_stdin_read(&format!(
+            "{read} 0x0 0x7ffd1c 0x2000 0x0 0x0 0x0 0x7ffd 0x7f12\n"
+        )));
+        assert!(!is_stdin_read(&format!(
+            "{read} 0x4 0x7ffd1c 0x2000 0x0 0x0 0x0 0x7ffd 0x7f12\n"
+        )));
+        assert!(!is_stdin_read("running\n"));
+        assert!(!is_stdin_read("-1 0x7ffd 0x7f12\n"));
+    }
+}
@@ -9,6 +9,7 @@ use evcxr::CommandContext;
 use evcxr::Error;
 use evcxr::EvalContext;
 use evcxr::EvalContextOutputs;
+use evcxr::VariableDetails;
 use once_cell::sync::Lazy;
 use std::collections::HashMap;
 use std::collections::HashSet;
@@ -136,6 +137,20 @@ fn variable_names_and_types(ctx: &CommandContext) -> Vec<(&str, &str)> {
     var_names
 }
 
+/// Adds a dependency on one of the crates in this repository, e.g. evcxr_runtime.
+fn add_local_dep(ctx: &mut CommandContext, crate_name: &str) {
+    let crate_path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("..")
+        .join(crate_name)
+        .canonicalize()
+        .unwrap();
+    ctx.execute(&format!(
+        ":dep {crate_name} = {{ path = {:?} }}",
+        crate_path.to_str().unwrap()
+    ))
+    .unwrap();
+}
+
 fn variable_names(ctx: &CommandContext) -> Vec<&str> {
     let mut var_names = ctx
         .variables_and_types()
@@ -230,6 +245,460 @@ fn printing() {
     handle.join().unwrap();
 }
 
+#[test]
+fn multiple_displays() {
+    let mut e = new_context();
+    add_local_dep(&mut e, "evcxr_runtime");
+    eval!(
+        e,
+        fn show(text: &str) -> u32 {
+            evcxr_runtime::mime_type("text/html").text(format!("<b>{text}</b>"));
+            42
+        }
+    );
+    let displayed = Mutex::new(Vec::new());
+    let outputs = e
+        .execute_with_callbacks(
+            r#"show("first"); show("second"); show("third")"#,
+            &mut evcxr::EvalCallbacks {
+                display: &|display| {
+                    displayed
+                        .lock()
+                        .unwrap()
+                        .push(display.content_by_mime_type["text/html"].clone());
+                },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    let expected = vec!["<b>first</b>", "<b>second</b>", "<b>third</b>"];
+    assert_eq!(*displayed.lock().unwrap(), expected);
+    assert_eq!(
+        outputs
+            .displays
+            .iter()
+            .map(|display| display.content_by_mime_type["text/html"].as_str())
+            .collect::<Vec<_>>(),
+        expected
+    );
+    // The value of the final expression is the result of the cell, not a display.
+    assert_eq!(outputs.content_by_mime_type, text_plain("42"));
+}
+
+#[test]
+fn display_with_multiple_mime_types() {
+    let mut e = new_context();
+    add_local_dep(&mut e, "evcxr_runtime");
+    let outputs = e
+        .execute(stringify!(
+            evcxr_runtime::display(|| {
+                evcxr_runtime::mime_type("text/html").text("<b>42</b>");
+                evcxr_runtime::mime_type("text/plain").text("42");
+            });
+            evcxr_runtime::mime_type("text/plain").text("separate");
+        ))
+        .unwrap();
+    assert_eq!(
+        outputs.displays,
+        vec![
+            evcxr::DisplayBundle {
+                content_by_mime_type: HashMap::from([
+                    ("text/html".to_owned(), "<b>42</b>".to_owned()),
+                    ("text/plain".to_owned(), "42".to_owned()),
+                ]),
+                display_id: None,
+                update: false,
+            },
+            evcxr::DisplayBundle {
+                content_by_mime_type: text_plain("separate"),
+                display_id: None,
+                update: false,
+            },
+        ]
+    );
+}
+
+#[test]
+fn binary_content() {
+    let mut e = new_context();
+    let crate_path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("../evcxr_runtime")
+        .canonicalize()
+        .unwrap();
+    e.execute(&format!(
+        r#":dep evcxr_runtime = {{ path = {:?}, features = ["bytes"] }}"#,
+        crate_path.to_str().unwrap()
+    ))
+    .unwrap();
+    let outputs = e
+        .execute(stringify!(
+            evcxr_runtime::mime_type("image/png").bytes(&[0x89, b'P', b'N', b'G']);
+            evcxr_runtime::mime_type("text/plain").bytes(b"caf\xe9");
+        ))
+        .unwrap();
+    let content = |display: &evcxr::DisplayBundle| {
+        display
+            .content_by_mime_type
+            .values()
+            .next()
+            .unwrap()
+            .clone()
+    };
+    assert_eq!(
+        outputs.displays.iter().map(content).collect::<Vec<_>>(),
+        vec!["iVBORw==".to_owned(), "caf\u{FFFD}".to_owned()]
+    );
+}
+
+#[test]
+fn updatable_displays() {
+    let mut e = new_context();
+    add_local_dep(&mut e, "evcxr_runtime");
+    let outputs = e
+        .execute(stringify!(
+            evcxr_runtime::mime_type("text/plain").display_id("progress").text("0%");
+            evcxr_runtime::mime_type("text/plain").display_id("progress").update("100%");
+        ))
+        .unwrap();
+    assert_eq!(
+        outputs.displays,
+        vec![
+            evcxr::DisplayBundle {
+                content_by_mime_type: text_plain("0%"),
+                display_id: Some("progress".to_owned()),
+                update: false,
+            },
+            evcxr::DisplayBundle {
+                content_by_mime_type: text_plain("100%"),
+                display_id: Some("progress".to_owned()),
+                update: true,
+            },
+        ]
+    );
+    assert!(outputs.is_empty());
+}
+
+#[test]
+fn late_output() {
+    let (mut e, outputs) = new_command_context_and_outputs();
+    send_output(outputs.stderr, io::stderr());
+
+    let handle = std::thread::spawn(move || {
+        let output = e
+            .execute(stringify!(
+                std::thread::spawn(|| {
+                    std::thread::sleep(std::time::Duration::from_millis(200));
+                    println!("late");
+                });
+                println!("on time");
+            ))
+            .unwrap();
+        // The context needs to outlive the thread that the cell started.
+        (e, output)
+    });
+
+    assert_eq!(outputs.stdout.recv(), Ok("on time".to_owned()));
+    let (_e, output) = handle.join().unwrap();
+    assert_eq!(
+        outputs.late_stdout.recv(),
+        Ok(evcxr::LateOutput {
+            generation: output.generation.unwrap(),
+            build_num: output.build_num.unwrap(),
+            line: "late".to_owned(),
+        })
+    );
+}
+
+#[test]
+fn cells_are_distinguished_across_clears() {
+    let mut e = new_context();
+    e.execute(":clear").unwrap();
+    let first = e.execute("40 + 2").unwrap();
+    e.execute(":clear").unwrap();
+    let second = e.execute("40 + 2").unwrap();
+    assert_eq!(first.build_num, second.build_num);
+    assert_ne!(first.generation, second.generation);
+}
+
+// Control messages are only sent via a separate pipe on Unix.
+#[cfg(unix)]
+#[test]
+fn printing_protocol_strings() {
+    let (mut e, outputs) = new_command_context_and_outputs();
+
+    let handle = std::thread::spawn(move || {
+        eval!(e,
+            print!("partial line ");
+            println!("EVCXR_EXECUTION_COMPLETE");
+            println!("EVCXR_INPUT_REQUEST:Name");
+            println!("EVCXR_BEGIN_CONTENT text/html\n<b>1</b>\nEVCXR_END_CONTENT");
+            println!("EVCXR_BEGIN_CONTENT text/plain");
+            print!("no newline");
+            40 + 2
+        )
+    });
+
+    assert_eq!(
+        outputs.stdout.recv(),
+        Ok("partial line EVCXR_EXECUTION_COMPLETE".to_owned())
+    );
+    for line in [
+        "EVCXR_INPUT_REQUEST:Name",
+        "EVCXR_BEGIN_CONTENT text/html",
+        "<b>1</b>",
+        "EVCXR_END_CONTENT",
+    ] {
+        assert_eq!(outputs.stdout.recv(), Ok(line.to_owned()));
+    }
+    assert_eq!(
+        outputs.stdout.recv(),
+        Ok("EVCXR_BEGIN_CONTENT text/plain".to_owned())
+    );
+    assert_eq!(outputs.stdout.recv(), Ok("no newline".to_owned()));
+
+    assert_eq!(handle.join().unwrap(), text_plain("42"));
+}
+
+// Only on Linux can the subprocess detect that user code is reading from stdin.
+#[cfg(target_os = "linux")]
+#[test]
+fn read_stdin() {
+    let mut e = new_context();
+    add_local_dep(&mut e, "evcxr_input");
+    let requests = Mutex::new(Vec::new());
+    let input_reader = |request: evcxr::InputRequest| {
+        let mut requests = requests.lock().unwrap();
+        requests.push((request.prompt, request.is_password));
+        format!("input {}", requests.len())
+    };
+    let outputs = e
+        .execute_with_callbacks(
+            r#"
+            use std::io::Write as _;
+            print!("Name: ");
+            std::io::stdout().flush().unwrap();
+            let mut name = String::new();
+            std::io::stdin().read_line(&mut name).unwrap();
+            let mut age = String::new();
+            std::io::stdin().read_line(&mut age).unwrap();
+            let secret = evcxr_input::get_password("Secret");
+            format!("{} {} {}", name.trim(), age.trim(), secret.trim())
+            "#,
+            &mut evcxr::EvalCallbacks {
+                input_reader: &input_reader,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    assert_eq!(
+        outputs.get("text/plain"),
+        Some("\"input 1 input 2 input 3\"")
+    );
+    assert_eq!(
+        *requests.lock().unwrap(),
+        vec![
+            ("Name: ".to_owned(), false),
+            (String::new(), false),
+            ("Secret".to_owned(), true)
+        ]
+    );
+}
+
+// Elsewhere on Unix, reads that evcxr_input didn't request should fail rather than wait forever.
+#[cfg(all(unix, not(target_os = "linux")))]
+#[test]
+fn read_stdin_without_request() {
+    let mut e = new_context();
+    add_local_dep(&mut e, "evcxr_input");
+    let input_reader = |_request: evcxr::InputRequest| "input".to_owned();
+    let outputs = e
+        .execute_with_callbacks(
+            r#"
+            let mut line = String::new();
+            let error = std::io::stdin().read_line(&mut line).unwrap_err();
+            let secret = evcxr_input::get_password("Secret");
+            format!("{:?} {}", error.kind(), secret)
+            "#,
+            &mut evcxr::EvalCallbacks {
+                input_reader: &input_reader,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    assert_eq!(outputs.get("text/plain"), Some("\"WouldBlock input\""));
+}
+
+#[test]
+fn session_values() {
+    let (mut e, _) = new_command_context_and_outputs();
+    add_local_dep(&mut e, "evcxr_runtime");
+    eval!(e,
+        use std::sync::atomic::AtomicU32;
+        use std::sync::atomic::Ordering;
+        evcxr_runtime::session_value("counter", || AtomicU32::new(40)).fetch_add(1, Ordering::SeqCst);
+    );
+    assert_eq!(
+        eval!(
+            e,
+            evcxr_runtime::session_value("counter", || AtomicU32::new(0))
+                .fetch_add(1, Ordering::SeqCst)
+        ),
+        text_plain("41")
+    );
+}
+
+#[test]
+fn expand_macros() {
+    let mut e = new_context();
+    eval!(
+        e,
+        macro_rules! double {
+            ($x:expr) => {
+                $x * 2
+            };
+        }
+        let _ = ();
+    );
+    let expanded = eval_and_unwrap(&mut e, ":expand let a = double!(21);");
+    assert_eq!(expanded["text/plain"].trim(), "let a = 21 * 2;");
+    let expanded = eval_and_unwrap(&mut e, ":expand let a = 1; let b = double!(a);");
+    assert_eq!(expanded["text/plain"].trim(), "let a = 1; let b = a * 2;");
+    let expanded = eval_and_unwrap(&mut e, r#":expand let a = 1; println!("{a}");"#);
+    assert!(expanded["text/plain"].starts_with("let a = 1; {"));
+    assert!(!expanded["text/plain"].contains("println!"));
+    let expanded = eval_and_unwrap(&mut e, ":expand let a = double!(1); not_a_macro!();");
+    assert_eq!(
+        expanded["text/plain"].trim(),
+        "let a = 1 * 2; not_a_macro!();\n// Couldn't expand: not_a_macro!"
+    );
+    // Types with the same name in different modules get their own impls.
+    let expanded = eval_and_unwrap(
+        &mut e,
+        ":expand\nmod inner {\n    #[derive(Debug)]\n    pub struct P;\n}\n#[derive(Clone)]\nstruct P;",
+    );
+    let expanded = &expanded["text/plain"];
+    let (inner, outer) = expanded.split_once("\n}\n").unwrap();
+    assert!(inner.contains("impl ::core::fmt::Debug for P"));
+    assert!(!inner.contains("Clone for P"));
+    assert!(outer.contains("impl ::core::clone::Clone for P"));
+    assert!(!outer.contains("Debug for P"));
+    let expanded = eval_and_unwrap(
+        &mut e,
+        ":expand\n#[derive(Debug)]\nstruct Point {\n    x: i32,\n}\nprintln!(\"{}\", double!(1));",
+    );
+    let expanded = &expanded["text/plain"];
+    assert!(expanded.starts_with("struct Point {"));
+    assert!(!expanded.contains("#[derive"));
+    assert!(expanded.contains("impl ::core::fmt::Debug for Point"));
+    assert!(expanded.contains("_print("));
+    // Nothing from the generated code that wraps the user's code should be shown.
+    assert!(!expanded.contains("evcxr"));
+    // The code is expanded rather than run.
+    assert!(variable_names(&e).is_empty());
+    assert!(!defined_item_names(&e).contains(&"Point"));
+}
+
+#[test]
+fn statics_persist_between_cells() {
+    let mut e = new_context();
+    eval!(e,
+        use std::sync::atomic::AtomicUsize;
+        use std::sync::atomic::Ordering;
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        static LIMIT: usize = 5;
+        pub fn next() -> usize { COUNTER.fetch_add(1, Ordering::SeqCst) + 1 }
+        next();
+    );
+    eval!(e, next(););
+    assert_eq!(eval!(e, next() + LIMIT), text_plain("8"));
+    // Redefining the static gives it a new value.
+    eval!(e, static COUNTER: AtomicUsize = AtomicUsize::new(10););
+    assert_eq!(eval!(e, next()), text_plain("11"));
+    // As does redefining a type used by it.
+    eval!(e,
+        struct Foo { a: u8 }
+        static FOOS: std::sync::Mutex<Vec<Foo>> = std::sync::Mutex::new(Vec::new());
+        FOOS.lock().unwrap().push(Foo { a: 1 });
+    );
+    eval!(
+        e,
+        struct Foo {
+            b: String,
+        }
+    );
+    eval!(e, FOOS.lock().unwrap().push(Foo { b: "b".to_owned() }););
+    assert_eq!(
+        eval!(
+            e,
+            FOOS.lock()
+                .unwrap()
+                .iter()
+                .map(|foo| foo.b.clone())
+                .collect::<Vec<_>>()
+        ),
+        text_plain(r#"["b"]"#)
+    );
+    // The value of a `static mut` couldn't persist, so it's rejected.
+    assert!(e.execute("static mut M: u32 = 0;").is_err());
+}
+
+#[test]
+fn thread_locals_persist_between_cells() {
+    let mut e = new_context();
+    eval!(e,
+        use std::cell::Cell;
+        use std::cell::RefCell;
+        thread_local! {
+            static CALLS: Cell<u32> = const { Cell::new(0) };
+            static NAMES: RefCell<Vec<String>> = RefCell::new(Vec::new());
+        }
+        CALLS.set(CALLS.get() + 1);
+    );
+    eval!(e,
+        CALLS.set(CALLS.get() + 1);
+        NAMES.with_borrow_mut(|names| names.push("a".to_owned()));
+    );
+    eval!(e, NAMES.with(|names| names.borrow_mut().push("b".to_owned())););
+    // Other threads have their own values.
+    assert_eq!(
+        eval!(
+            e,
+            std::thread::spawn(|| {
+                CALLS.set(CALLS.get() + 100);
+                CALLS.get()
+            })
+            .join()
+            .unwrap()
+        ),
+        text_plain("100")
+    );
+    assert_eq!(
+        eval!(e, (CALLS.get(), NAMES.take())),
+        text_plain(r#"(2, ["a", "b"])"#)
+    );
+    assert_eq!(
+        eval!(e, CALLS.try_with(|calls| calls.get()).unwrap()),
+        text_plain("2")
+    );
+    // Like with `LocalKey`, setting a value before it's initialized doesn't run the initializer.
+    eval!(
+        e,
+        thread_local! {
+            static LOUD: RefCell<u32> = {
+                println!("initializing");
+                RefCell::new(0)
+            };
+        }
+    );
+    assert_eq!(
+        eval!(e, {
+            LOUD.set(5);
+            LOUD.take()
+        }),
+        text_plain("5")
+    );
+}
+
 #[test]
 fn rc_refcell_etc() {
     let mut e = new_context();
@@ -294,6 +763,57 @@ fn function_panics_with_variable_preserving() {
     );
 }
 
+#[cfg(not(windows))]
+#[test]
+fn lost_variables_are_explained() {
+    fn error_message(e: &mut CommandContext, code: &str) -> String {
+        match e.execute(code) {
+            Err(Error::CompilationErrors(errors)) => errors[0].message(),
+            x => panic!("Unexpected result: {x:?}"),
+        }
+    }
+    let (mut e, _) = new_command_context_and_outputs();
+    eval_and_unwrap(&mut e, ":preserve_vars_on_panic 1");
+    eval!(e, let a = 1;);
+    eval!(e, let b = 2; panic!("Intentional panic"););
+    eval!(e, let c = 3; let d: i32 = "x".parse()?;);
+    assert_eq!(
+        error_message(&mut e, "b"),
+        "`b` was lost in cell 3 because the cell panicked"
+    );
+    assert_eq!(
+        error_message(&mut e, "c"),
+        "`c` was lost in cell 4 because the cell returned an error via `?`"
+    );
+    // Cells are numbered as the user sees them, so cells that fail or only contain commands count.
+    assert!(e.execute("undefined_function()").is_err());
+    eval!(e, let f = 5; panic!("Intentional panic"););
+    assert_eq!(
+        error_message(&mut e, "f"),
+        "`f` was lost in cell 8 because the cell panicked"
+    );
+    // Frontends can supply their own numbers.
+    e.set_execution_count(20);
+    eval!(e, let g = 5; panic!("Intentional panic"););
+    assert_eq!(
+        error_message(&mut e, "g"),
+        "`g` was lost in cell 20 because the cell panicked"
+    );
+    eval_and_unwrap(&mut e, ":restart");
+    assert_eq!(
+        error_message(&mut e, "a + 1"),
+        "`a` was lost because the subprocess was restarted"
+    );
+    // Once the variable is defined again, it's no longer considered lost.
+    eval!(e, let a = "five".to_owned(););
+    assert_eq!(eval!(e, a.len()), text_plain("4"));
+    eval!(e, drop(a););
+    assert_eq!(
+        error_message(&mut e, "a"),
+        "cannot find value `a` in this scope"
+    );
+}
+
 #[test]
 fn function_panics_without_variable_preserving() {
     // Don't allow stderr to be printed here. We don't really want to see the
@@ -662,6 +1182,761 @@ fn abort_and_restart() {
     assert_eq!(e.defined_item_names().next(), None);
 }
 
+#[test]
+fn save_and_load_session() {
+    let mut e = new_context();
+    let tempdir = tempfile::tempdir().unwrap();
+    let path = tempdir.path().join("session.json");
+    eval!(
+        e,
+        use std::collections::HashMap;
+        pub struct Point {
+            pub x: i32,
+        }
+        fn make_point() -> Point {
+            Point { x: 40 }
+        }
+        let p = make_point();
+        let mut m = HashMap::new();
+        m.insert(1, 2);
+    );
+    // Cells that don't define any variables can still change them.
+    eval!(e, m.insert(3, 4););
+    eval_and_unwrap(&mut e, &format!(":save_session {}", path.display()));
+    eval_and_unwrap(&mut e, ":clear");
+    assert_eq!(variable_names(&e), Vec::<&str>::new());
+    eval_and_unwrap(&mut e, &format!(":load_session {}", path.display()));
+    assert_eq!(variable_names(&e), vec!["m", "p"]);
+    assert_eq!(
+        defined_item_names(&e),
+        vec!["HashMap", "Point", "make_point"]
+    );
+    assert_eq!(eval!(e, p.x + m[&1]), text_plain("42"));
+    assert_eq!(eval!(e, m.len()), text_plain("2"));
+}
+
+#[test]
+fn load_and_watch_files() {
+    let mut e = new_context();
+    let tempdir = tempfile::tempdir().unwrap();
+    let path = tempdir.path().join("setup.rs");
+    std::fs::write(
+        &path,
+        ":fmt {:?}\nfn add(x: i32) -> i32 { x + 2 }\nlet a = 40;\nadd(a)",
+    )
+    .unwrap();
+    assert_eq!(
+        eval_and_unwrap(&mut e, &format!(":load {}", path.display())),
+        text_plain("Output format: {:?}\n42")
+    );
+    assert_eq!(variable_names(&e), vec!["a"]);
+
+    std::fs::write(&path, "let a = 10;\nlet b = undefined;").unwrap();
+    match e.execute(&format!(":load {}", path.display())) {
+        Err(Error::CompilationErrors(errors)) => {
+            assert_eq!(errors.len(), 1);
+            assert_eq!(errors[0].source_file(), Some(path.as_path()));
+            assert_eq!(
+                errors[0].message(),
+                format!(
+                    "{}:2:9: cannot find value `undefined` in this scope",
+                    path.display()
+                )
+            );
+        }
+        x => panic!("Unexpected result: {x:?}"),
+    }
+
+    std::fs::write(&path, format!(":load {}", path.display())).unwrap();
+    let error = e.execute(&format!(":load {}", path.display())).unwrap_err();
+    assert!(
+        error
+            .to_string()
+            .contains(&format!("{} is already being loaded", path.display())),
+        "Unexpected error: {error}"
+    );
+
+    let path = tempdir.path().join("watched.rs");
+    std::fs::write(&path, "let w = 1;").unwrap();
+    assert_eq!(
+        eval_and_unwrap(&mut e, &format!(":watch {}", path.display())),
+        text_plain(&format!("Watching {}\n", path.display()))
+    );
+    assert_eq!(eval!(e, w), text_plain("1"));
+    // Make sure the change is noticed even if the filesystem's timestamps are coarse.
+    std::fs::write(&path, "let w = 2;\nw * 10").unwrap();
+    std::fs::File::options()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_modified(std::time::SystemTime::now() + std::time::Duration::from_secs(10))
+        .unwrap();
+    assert_eq!(
+        eval!(e, w),
+        text_plain(&format!("Reran {}: 20\n2", path.display()))
+    );
+    assert_eq!(eval!(e, w), text_plain("2"));
+    eval_and_unwrap(&mut e, ":watch off");
+    std::fs::write(&path, "let w = 3;").unwrap();
+    assert_eq!(eval!(e, w), text_plain("2"));
+}
+
+// Only on Linux can the subprocess detect that user code is reading from stdin.
+#[cfg(target_os = "linux")]
+#[test]
+fn loaded_files_get_input() {
+    let mut e = new_context();
+    let tempdir = tempfile::tempdir().unwrap();
+    let path = tempdir.path().join("input.rs");
+    std::fs::write(
+        &path,
+        "let mut name = String::new();\nstd::io::stdin().read_line(&mut name).unwrap();\nname.trim().to_owned()",
+    )
+    .unwrap();
+    let outputs = e
+        .execute_with_callbacks(
+            &format!(":load {}", path.display()),
+            &mut evcxr::EvalCallbacks {
+                input_reader: &|_| "Ferris".to_owned(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    assert_eq!(outputs.get("text/plain"), Some("\"Ferris\""));
+}
+
+#[test]
+fn export_crate() {
+    let mut e = new_context();
+    let tempdir = tempfile::tempdir().unwrap();
+    let crate_dir = tempdir.path().join("exported");
+    eval!(
+        e,
+        fn r20() -> i32 {
+            20
+        }
+        let mut a = r20();
+    );
+    eval!(e, a += 22;);
+    // A cell that fails shouldn't be included.
+    assert!(e.execute("a += no_such_var;").is_err());
+    eval!(e, a);
+    // A type without Debug should be displayed the way the session displayed it.
+    eval!(
+        e,
+        struct Shown;
+        impl Shown {
+            fn evcxr_display(&self) {
+                println!("shown");
+            }
+        }
+    );
+    eval!(e, Shown);
+    eval_and_unwrap(&mut e, &format!(":export_crate {}", crate_dir.display()));
+    let output = std::process::Command::new("cargo")
+        .arg("run")
+        .arg("--quiet")
+        .env("CARGO_TARGET_DIR", tempdir.path().join("target"))
+        .current_dir(&crate_dir)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "42\nshown\n");
+    // Exporting again to the same directory shouldn't clobber what we wrote.
+    assert!(
+        e.execute(&format!(":export_crate {}", crate_dir.display()))
+            .is_err()
+    );
+}
+
+#[test]
+fn export_test() {
+    let mut e = new_context();
+    let tempdir = tempfile::tempdir().unwrap();
+    let crate_dir = tempdir.path().join("exported");
+    eval!(
+        e,
+        fn r20() -> i32 {
+            20
+        }
+        let mut a = r20();
+    );
+    eval!(e, a += 22;);
+    eval!(e, a);
+    eval_and_unwrap(&mut e, &format!(":export_crate {}", crate_dir.display()));
+    eval_and_unwrap(
+        &mut e,
+        &format!(":export_test from_session {}", crate_dir.display()),
+    );
+    assert!(crate_dir.join("tests").join("from_session.rs").exists());
+    let run_tests = || {
+        std::process::Command::new("cargo")
+            .arg("test")
+            .arg("--quiet")
+            .env("CARGO_TARGET_DIR", tempdir.path().join("target"))
+            .current_dir(&crate_dir)
+            .output()
+            .unwrap()
+    };
+    let output = run_tests();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    // A test recorded from a session that evaluated `a` differently should fail.
+    eval!(e, a += 1;);
+    eval!(e, a);
+    eval_and_unwrap(
+        &mut e,
+        &format!(":export_test changed {}", crate_dir.display()),
+    );
+    let test_path = crate_dir.join("tests").join("changed.rs");
+    let code = std::fs::read_to_string(&test_path).unwrap();
+    std::fs::write(&test_path, code.replace("\"43\"", "\"44\"")).unwrap();
+    assert!(!run_tests().status.success());
+    // A directory that isn't a crate should be rejected.
+    assert!(
+        e.execute(&format!(
+            ":export_test other {}",
+            tempdir.path().join("not_a_crate").display()
+        ))
+        .is_err()
+    );
+}
+
+#[test]
+fn checkpoint_vars_survive_restart() {
+    let mut e = new_context();
+    eval_and_unwrap(&mut e, ":checkpoint_vars on");
+    eval!(
+        e,
+        let mut a = vec![1, 2, 3];
+        // Rc doesn't implement serde traits, so can't be checkpointed.
+        let b = std::rc::Rc::new(5);
+    );
+    eval!(e, a.push(36););
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":restart"),
+        text_plain("Child process restarted. Restored from checkpoint: a\n")
+    );
+    assert_eq!(variable_names(&e), vec!["a"]);
+    assert_eq!(eval!(e, a.iter().sum::<i32>()), text_plain("42"));
+
+    let result = e.execute("a.push(0); std::process::abort();");
+    if let Err(Error::SubprocessTerminated(message)) = result {
+        assert!(
+            message.ends_with("Restored from checkpoint: a"),
+            "Unexpected message: {message}"
+        );
+    } else {
+        panic!("Unexpected result: {result:?}");
+    }
+    // The push happened in the cell that crashed, so shouldn't be reflected.
+    assert_eq!(eval!(e, a.len()), text_plain("4"));
+}
+
+#[test]
+fn checkpoint_only_vars_that_may_have_changed() {
+    let mut e = new_context();
+    eval_and_unwrap(&mut e, ":checkpoint_vars on");
+    eval!(
+        e,
+        let mut a = vec![1, 2, 3];
+        let b = std::cell::RefCell::new(5);
+        let c = String::from("c");
+    );
+    eval!(e, a.push(4); *b.borrow_mut() += 1;);
+    let source = e.last_source().unwrap();
+    assert!(source.contains("EvcxrCheckpoint(&a,"));
+    // Variables can change via interior mutability, even if they're not `mut`.
+    assert!(source.contains("EvcxrCheckpoint(&b,"));
+    assert!(!source.contains("EvcxrCheckpoint(&c,"));
+}
+
+#[test]
+fn migrate_vars_when_type_redefined() {
+    let mut e = new_context();
+    eval_and_unwrap(&mut e, ":migrate_vars on");
+    eval!(
+        e,
+        #[derive(serde::Serialize, serde::Deserialize)]
+        struct Point {
+            x: i32,
+        }
+        let p = Point { x: 10 };
+        // Rc doesn't implement serde traits, so can't be migrated.
+        let q = std::rc::Rc::new(Point { x: 1 });
+    );
+    // Redefining the type with a compatible serde representation converts the variable.
+    eval!(
+        e,
+        #[derive(serde::Serialize, serde::Deserialize)]
+        struct Point {
+            x: i32,
+            #[serde(default)]
+            y: i32,
+        }
+        let _ = ();
+    );
+    assert_eq!(variable_names(&e), vec!["p"]);
+    assert_eq!(eval!(e, p.x + p.y), text_plain("10"));
+    // A From impl in the cell that redefines the type can convert from the old representation.
+    eval!(
+        e,
+        #[derive(serde::Deserialize)]
+        struct OldPoint {
+            x: i32,
+            y: i32,
+        }
+        #[derive(Debug)]
+        struct Point(i32, i32);
+        impl From<OldPoint> for Point {
+            fn from(old: OldPoint) -> Point {
+                Point(old.x, old.y + 5)
+            }
+        }
+        let _ = ();
+    );
+    assert_eq!(eval!(e, p), text_plain("Point(10, 5)"));
+    // The new type doesn't support serde, so this time the variable is lost.
+    eval!(
+        e,
+        struct Point;
+        let _ = ();
+    );
+    assert!(variable_names(&e).is_empty());
+}
+
+#[test]
+fn undo() {
+    let mut e = new_context();
+    assert!(e.execute(":undo").is_err());
+    eval!(
+        e,
+        fn foo() -> i32 {
+            40
+        }
+        let a = 2;
+        let s = String::new();
+    );
+    eval!(
+        e,
+        fn foo() -> i32 {
+            0
+        }
+        fn bar() {}
+        let b = 1;
+        let a = 5;
+        drop(s);
+    );
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":undo"),
+        text_plain(
+            "Undid last cell. Variables that it moved or redefined can't be restored: a, s\n"
+        )
+    );
+    assert_eq!(variable_names(&e), Vec::<&str>::new());
+    assert_eq!(defined_item_names(&e), vec!["foo"]);
+    assert_eq!(eval!(e, foo()), text_plain("40"));
+    eval!(e, let c = 2;);
+    eval_and_unwrap(&mut e, ":undo");
+    eval!(e, use std::collections::HashMap as c;);
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":undo"),
+        text_plain("Undid last cell\n")
+    );
+    assert_eq!(variable_names(&e), Vec::<&str>::new());
+    assert_eq!(defined_item_names(&e), vec!["foo"]);
+}
+
+#[test]
+fn forget_and_unuse() {
+    let mut e = new_context();
+    eval!(
+        e,
+        use std::collections::HashMap;
+        use std::fmt::*;
+        struct Foo(i32);
+        fn bar() -> i32 {
+            42
+        }
+        let f = Foo(1);
+        let x = 2;
+        let y = 3;
+    );
+    eval!(
+        e,
+        mod other {
+            pub struct Foo;
+        }
+        impl Foo {
+            fn value(&self) -> i32 {
+                self.0
+            }
+        }
+        fn make_foo() -> Foo {
+            Foo(2)
+        }
+        fn use_make_foo() -> i32 {
+            make_foo().value()
+        }
+        let o = other::Foo;
+        let v = vec![Foo(3)];
+    );
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":forget Foo"),
+        text_plain(
+            "Forgot Foo, items that use it: make_foo, use_make_foo and variables of those types: \
+            f, v\n"
+        )
+    );
+    assert_eq!(variable_names(&e), vec!["o", "x", "y"]);
+    // Redefining Foo shouldn't conflict with its old impl.
+    eval!(
+        e,
+        struct Foo;
+        impl Foo {
+            fn value(&self) -> i32 {
+                1
+            }
+        }
+    );
+    eval_and_unwrap(&mut e, ":forget o");
+    assert_eq!(variable_names(&e), vec!["x", "y"]);
+    eval_and_unwrap(&mut e, ":forget x");
+    assert_eq!(variable_names(&e), vec!["y"]);
+    assert!(e.execute(":forget x").is_err());
+
+    assert!(e.execute(":unuse std::collections::HashSet").is_err());
+    eval_and_unwrap(&mut e, ":unuse std::collections::HashMap");
+    assert_eq!(defined_item_names(&e), vec!["Foo", "bar", "other"]);
+    eval_and_unwrap(&mut e, ":unuse use std::fmt::*;");
+    assert!(e.execute("fn show(_: &dyn Display) {}").is_err());
+    assert_eq!(eval!(e, bar() + y - 3), text_plain("42"));
+}
+
+#[test]
+fn variable_details() {
+    let mut e = new_context();
+    eval!(e, let a = 42i32; let s = "x".repeat(200););
+    eval!(
+        e,
+        struct NoDebug;
+        let n = NoDebug;
+    );
+    let details = e.variable_details().unwrap();
+    assert_eq!(details.len(), 3);
+    assert_eq!(
+        details[0],
+        VariableDetails {
+            name: "a".to_owned(),
+            type_name: "i32".to_owned(),
+            preview: Some("42".to_owned()),
+            size: Some(4),
+            defining_cell: Some(1),
+        }
+    );
+    assert_eq!(details[1].name, "n");
+    assert_eq!(details[1].preview, None);
+    assert_eq!(details[1].size, Some(0));
+    assert_eq!(details[1].defining_cell, Some(2));
+    let preview = details[2].preview.as_deref().unwrap();
+    assert!(preview.starts_with("\"xxx"), "{preview}");
+    assert!(preview.ends_with('…'), "{preview}");
+    assert_eq!(preview.chars().count(), 101);
+
+    let outputs = eval_and_unwrap(&mut e, ":vars --values");
+    assert!(outputs["text/plain"].contains("a: i32 = 42 (4 bytes, cell 1)\n"));
+}
+
+#[test]
+fn variable_details_with_panicking_debug() {
+    let mut e = new_context();
+    eval!(
+        e,
+        struct Bad;
+        impl std::fmt::Debug for Bad {
+            fn fmt(&self, _: &mut std::fmt::Formatter) -> std::fmt::Result {
+                panic!("Bad Debug");
+            }
+        }
+        let bad = Bad;
+    );
+    let details = e.variable_details().unwrap();
+    assert_eq!(details.len(), 1);
+    assert_eq!(details[0].name, "bad");
+    assert_eq!(details[0].preview, None);
+    // The subprocess should have survived.
+    assert_eq!(eval!(e, 40 + 2), text_plain("42"));
+    assert_eq!(variable_names(&e), vec!["bad"]);
+}
+
+#[test]
+fn reactive() {
+    let mut e = new_context();
+    eval_and_unwrap(&mut e, ":reactive on");
+    eval_and_unwrap(
+        &mut e,
+        "fn foo() -> i32 { 1 } fn bar() -> i32 { foo() * 10 }",
+    );
+    eval!(e, let a = bar(););
+    eval!(e, let b = 5;);
+    eval!(e, a + b);
+    assert_eq!(
+        eval_and_unwrap(&mut e, "fn foo() -> i32 { 2 }"),
+        text_plain("Reran cell 2\nReran cell 4: 25\n")
+    );
+    assert_eq!(eval!(e, a), text_plain("20"));
+    eval_and_unwrap(&mut e, ":reactive list");
+    assert_eq!(
+        eval_and_unwrap(&mut e, "fn foo() -> i32 { 3 }"),
+        text_plain("Stale cells: 2, 4, 6\n")
+    );
+    assert_eq!(eval!(e, a), text_plain("20"));
+}
+
+#[test]
+fn sessions() {
+    let mut e = new_context();
+    eval!(e, let a = 1;);
+    eval_and_unwrap(&mut e, ":timeout 30s");
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":session new sessions_test"),
+        text_plain("Created session sessions_test\n")
+    );
+    assert_eq!(variable_names(&e), Vec::<&str>::new());
+    // New sessions start with the current session's settings.
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":timeout"),
+        text_plain("Timeout: 30s\n")
+    );
+    eval!(e, let b = 2;);
+    assert_eq!(eval!(e, b), text_plain("2"));
+    assert!(e.execute(":session new sessions_test").is_err());
+    assert!(e.execute(":session switch nonexistent").is_err());
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":session list"),
+        text_plain("  default\n* sessions_test\n")
+    );
+    eval_and_unwrap(&mut e, ":session switch default");
+    assert_eq!(variable_names(&e), vec!["a"]);
+    assert_eq!(eval!(e, a), text_plain("1"));
+    eval_and_unwrap(&mut e, ":session switch sessions_test");
+    assert_eq!(variable_names(&e), vec!["b"]);
+    eval_and_unwrap(&mut e, ":session switch default");
+    eval_and_unwrap(&mut e, ":timeout off");
+}
+
+#[test]
+fn background_jobs() {
+    let mut e = new_context();
+    assert_eq!(eval_and_unwrap(&mut e, ":jobs"), text_plain("No jobs\n"));
+    eval!(e,
+        let (tx, rx) = std::sync::mpsc::channel::<i32>();
+        let rx = std::sync::Arc::new(std::sync::Mutex::new(rx));
+        let factor = 2;
+    );
+    assert_eq!(
+        eval_and_unwrap(
+            &mut e,
+            ":spawn\nrx.lock().unwrap().recv().unwrap() * factor"
+        ),
+        text_plain("Started job 1\n")
+    );
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":jobs"),
+        text_plain("Job 1: running - rx.lock().unwrap().recv().unwrap() * factor\n")
+    );
+    eval!(e, tx.send(21).unwrap(););
+    assert_eq!(eval_and_unwrap(&mut e, ":wait 1"), text_plain("42"));
+    // The job was given clones of the variables that it used.
+    assert_eq!(eval!(e, factor), text_plain("2"));
+    assert_eq!(eval!(e, std::sync::Arc::strong_count(&rx)), text_plain("1"));
+    // Variables that can't be cloned can't be used by jobs.
+    eval!(e, let (tx2, rx2) = std::sync::mpsc::channel::<i32>(););
+    assert!(e.execute(":spawn\nrx2.recv().unwrap()").is_err());
+    assert_eq!(eval_and_unwrap(&mut e, ":jobs"), text_plain("No jobs\n"));
+    assert!(e.execute(":wait 1").is_err());
+    assert!(e.execute("let x = 1;\n:spawn x").is_err());
+    // Items and use statements at the start of the cell are kept for later cells.
+    assert_eq!(
+        eval_and_unwrap(
+            &mut e,
+            ":spawn\nuse std::collections::BTreeSet;\nfn double(x: i32) -> i32 { x * 2 }\ndouble(4)"
+        ),
+        text_plain("Started job 1\n")
+    );
+    assert_eq!(eval_and_unwrap(&mut e, ":wait 1"), text_plain("8"));
+    assert_eq!(
+        eval!(e, BTreeSet::from([double(1), double(2)])),
+        text_plain("{2, 4}")
+    );
+    assert!(
+        e.execute(":spawn\ndouble(1)\nfn triple(x: i32) -> i32 { x * 3 }")
+            .is_err()
+    );
+    assert!(e.execute(":spawn\n!echo hello").is_err());
+    assert!(
+        e.execute(":spawn\nfn quadruple(x: i32) -> i32 { x * 4 }")
+            .is_err()
+    );
+}
+
+#[test]
+fn timeout() {
+    let mut e = new_context();
+    eval_and_unwrap(&mut e, ":timeout 2s");
+    eval!(e, let a = 1;);
+    // Pooled contexts have already counted the cells of earlier tests.
+    e.set_execution_count(5);
+    match e.execute("loop { std::thread::sleep(std::time::Duration::from_millis(10)); }") {
+        Err(Error::Timeout {
+            cell,
+            lost_variables,
+            ..
+        }) => {
+            assert_eq!(cell, 5);
+            assert_eq!(lost_variables, vec!["a"]);
+        }
+        x => panic!("Unexpected result: {x:?}"),
+    }
+    assert_eq!(variable_names(&e), Vec::<&str>::new());
+    assert_eq!(eval!(e, 40 + 2), text_plain("42"));
+    // Time spent waiting for input doesn't count.
+    add_local_dep(&mut e, "evcxr_input");
+    let input_reader = |_request: evcxr::InputRequest| {
+        std::thread::sleep(std::time::Duration::from_secs(3));
+        "input".to_owned()
+    };
+    let outputs = e
+        .execute_with_callbacks(
+            r#"evcxr_input::get_string("Name")"#,
+            &mut evcxr::EvalCallbacks {
+                input_reader: &input_reader,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    assert_eq!(outputs.get("text/plain"), Some("\"input\""));
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":timeout off"),
+        text_plain("Timeout: off\n")
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn resource_limits() {
+    let mut e = new_context();
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":limits memory=2G cpu=1s"),
+        text_plain("Limits: memory=2G cpu=1s. Subprocess restarted\n")
+    );
+    match e.execute("let v = vec![1u8; 4_000_000_000]; v.len()") {
+        Err(Error::SubprocessTerminated(message)) => {
+            assert!(message.contains("memory limit of 2G"), "{message}");
+        }
+        x => panic!("Unexpected result: {x:?}"),
+    }
+    match e.execute("let mut x = 0u64; loop { x = std::hint::black_box(x + 1); }") {
+        Err(Error::SubprocessTerminated(message)) => {
+            assert!(message.contains("CPU time limit of 1s"), "{message}");
+        }
+        x => panic!("Unexpected result: {x:?}"),
+    }
+    eval_and_unwrap(&mut e, ":limits memory=off cpu=off");
+    assert_eq!(eval!(e, 40 + 2), text_plain("42"));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn sandbox() {
+    // Not taken from the pool, since the sandbox can't be turned off.
+    let (mut e, _) = new_command_context_and_outputs();
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":sandbox on"),
+        text_plain("Sandbox: on\n")
+    );
+    assert_eq!(
+        eval!(e, std::fs::write("inside.txt", "x").is_ok()),
+        text_plain("true")
+    );
+    assert_eq!(
+        eval!(
+            e,
+            std::fs::write("/tmp/evcxr_sandbox_test.txt", "x").is_ok()
+        ),
+        text_plain("false")
+    );
+    assert_eq!(
+        eval!(e, std::net::UdpSocket::bind("127.0.0.1:0").is_ok()),
+        text_plain("false")
+    );
+    // Unix sockets on the host, e.g. that of a Docker daemon, can't be reached either.
+    let socket_dir = tempfile::tempdir().unwrap();
+    let socket_path = socket_dir.path().join("host.sock");
+    let _listener = std::os::unix::net::UnixListener::bind(&socket_path).unwrap();
+    assert_eq!(
+        eval_and_unwrap(
+            &mut e,
+            &format!(
+                "std::os::unix::net::UnixStream::connect({:?}).is_ok()",
+                socket_path
+            )
+        ),
+        text_plain("false")
+    );
+    // Pairs of connected sockets are still fine, since they can't reach anything else.
+    assert_eq!(
+        eval!(e, std::os::unix::net::UnixStream::pair().is_ok()),
+        text_plain("true")
+    );
+    assert!(e.execute("!echo hello").is_err());
+    assert!(e.execute(":sandbox off").is_err());
+    // Commands that access files run in our process, so would bypass the sandbox.
+    let session_path = socket_dir.path().join("session.json");
+    assert!(
+        e.execute(&format!(":save_session {}", session_path.display()))
+            .is_err()
+    );
+    assert!(!session_path.exists());
+    assert!(
+        e.execute(&format!(":export_crate {}", socket_dir.path().display()))
+            .is_err()
+    );
+    assert!(
+        e.execute(&format!(":load {}", session_path.display()))
+            .is_err()
+    );
+    // Neither are commands that would have the host run code that sandboxed code could write, e.g.
+    // build scripts of local dependencies or programs that cargo runs.
+    let sandbox_dir = socket_dir.path().display();
+    for command in [
+        format!(":dep evil = {{ path = \"{sandbox_dir}/evil\" }}"),
+        format!(":dep evil = {{ git = \"file://{sandbox_dir}/evil\" }}"),
+        format!(":dep {sandbox_dir}/evil"),
+        format!(":build_env RUSTC_WRAPPER={sandbox_dir}/wrapper"),
+        format!(":env LD_PRELOAD={sandbox_dir}/evil.so"),
+        format!(":linker {sandbox_dir}/linker"),
+        format!(":codegen_backend {sandbox_dir}/backend.so"),
+        format!(":toolchain {sandbox_dir}/toolchain"),
+        ":sccache 1".to_owned(),
+    ] {
+        assert!(e.execute(&command).is_err(), "{command} should fail");
+    }
+    assert_eq!(std::env::var_os("LD_PRELOAD"), None);
+    e.reset_config();
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":sandbox"),
+        text_plain("Sandbox: on\n")
+    );
+}
+
 #[test]
 fn variable_assignment_compile_fail_then_use_statement() {
     let mut e = new_context();
@@ -703,14 +1978,41 @@ fn reserved_words() {
 #[test]
 fn unnamable_type_closure() {
     let mut e = new_context();
-    let result = e.execute(stringify!(let v = || {42};));
-    if let Err(Error::Message(message)) = result {
-        if !(message.starts_with("The variable") && message.contains("cannot be persisted")) {
-            panic!("Unexpected error: {message:?}");
-        }
-    } else {
-        panic!("Unexpected result: {result:?}");
-    }
+    eval!(e, let add_one = |x: i32| x + 1;);
+    eval!(e, let mut counter = { let mut n = 0; move || { n += 1; n } };);
+    eval!(e, let greet = |name: &str| println!("Hello {name}"););
+    assert_eq!(eval!(e, add_one(41)), text_plain("42"));
+    assert_eq!(eval!(e, counter(); counter()), text_plain("2"));
+    assert_eq!(
+        variable_names_and_types(&e),
+        vec![
+            ("add_one", "Box<dyn Fn(i32) -> i32>"),
+            ("counter", "Box<dyn FnMut() -> i32>"),
+            ("greet", "Box<dyn Fn(&str)>"),
+        ]
+    );
+}
+
+#[test]
+fn unnamable_type_iterator() {
+    let mut e = new_context();
+    eval!(
+        e,
+        pub fn evens() -> impl Iterator<Item = u32> { (0..).step_by(2) }
+        let mut v = evens();
+        let mut w = (1..4).map(|x| x * 10);
+    );
+    assert_eq!(
+        eval!(e, (v.next(), v.next(), w.next())),
+        text_plain("(Some(0), Some(2), Some(10))")
+    );
+    assert_eq!(
+        variable_names_and_types(&e),
+        vec![
+            ("v", "Box<dyn Iterator<Item = u32>>"),
+            ("w", "Box<dyn Iterator<Item = i32>>"),
+        ]
+    );
 }
 
 #[test]
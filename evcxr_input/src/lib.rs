@@ -9,16 +9,44 @@ pub fn get_password(prompt: &str) -> String {
 }
 
 fn get_input(prompt: &str, is_password: bool) -> Option<String> {
-    if is_password {
-        println!("{}:{}", GET_CMD_PASSWORD, prompt);
+    let request = if is_password {
+        format!("{}:{}", GET_CMD_PASSWORD, prompt)
     } else {
-        println!("{}:{}", GET_CMD, prompt);
+        format!("{}:{}", GET_CMD, prompt)
+    };
+    if !send_to_evcxr(&request) {
+        println!("{request}");
     }
     let mut line = String::new();
     std::io::stdin().read_line(&mut line).ok()?;
     Some(line.trim().to_owned())
 }
 
+/// Sends `header` as a control message to Evcxr, returning false if we're not running inside Evcxr.
+fn send_to_evcxr(header: &str) -> bool {
+    use std::io::Write;
+    type ControlFn = extern "C" fn(*const u8, usize, *const u8, usize);
+    static CONTROL_FN: std::sync::OnceLock<Option<ControlFn>> = std::sync::OnceLock::new();
+    let control_fn = CONTROL_FN.get_or_init(|| {
+        // The variable contains the ID of the process that set it, so that we don't use it if we
+        // inherited it from the Evcxr runtime.
+        let value = std::env::var("EVCXR_CONTROL_FN").ok()?;
+        let (pid, address) = value.split_once(':')?;
+        if pid.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+        // Safety: The function has this signature.
+        Some(unsafe { std::mem::transmute::<usize, ControlFn>(address.parse().ok()?) })
+    });
+    let Some(control_fn) = control_fn else {
+        return false;
+    };
+    // Make sure anything that was printed beforehand is output first.
+    let _ = std::io::stdout().flush();
+    control_fn(header.as_ptr(), header.len(), "".as_ptr(), 0);
+    true
+}
+
 // The following constants are here so that they can be shared between this crate and Evcxr. They're
 // not really intended to be used.
 
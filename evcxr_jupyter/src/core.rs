@@ -20,17 +20,28 @@ use evcxr::CommandContext;
 use evcxr::Theme;
 use serde_json::Value;
 use std::collections::HashMap;
+use std::collections::VecDeque;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::Mutex;
 use yansi::Paint as _;
 
+/// How many cells we remember the execution requests of, for output that cells print after they
+/// complete. Output from older cells is shown with the latest cell.
+const MAX_RECENT_EXECUTION_REQUESTS: usize = 100;
+
+/// The generation and build number of a cell, which together identify it.
+type CellId = (u64, i32);
+
 // Note, to avoid potential deadlocks, each thread should lock at most one mutex at a time.
 #[derive(Clone)]
 pub(crate) struct Server {
     iopub: Arc<Mutex<Connection<zeromq::PubSocket>>>,
     stdin: Arc<Mutex<Connection<zeromq::RouterSocket>>>,
     latest_execution_request: Arc<Mutex<Option<JupyterMessage>>>,
+    /// The execution requests of the cells that most recently ran code, so that output that a cell prints after it
+    /// completes can be associated with it.
+    recent_execution_requests: Arc<Mutex<VecDeque<(CellId, JupyterMessage)>>>,
     io_thread_shutdown_sender: Arc<Mutex<Option<crossbeam_channel::Sender<()>>>>,
     tokio_handle: tokio::runtime::Handle,
 }
@@ -93,6 +104,7 @@ impl Server {
         let server = Server {
             iopub,
             latest_execution_request: Arc::new(Mutex::new(None)),
+            recent_execution_requests: Arc::new(Mutex::new(VecDeque::new())),
             stdin: Arc::new(Mutex::new(stdin_socket)),
             io_thread_shutdown_sender: Arc::new(Mutex::new(Some(shutdown_sender))),
             tokio_handle,
@@ -150,6 +162,10 @@ impl Server {
                 shutdown_receiver.clone(),
             )
             .await;
+        server
+            .clone()
+            .start_late_output_pass_through_thread(outputs.late_stdout, shutdown_receiver.clone())
+            .await;
 
         // Don't keep any outstanding instances of our connection group, otherwise things won't shut
         // down properly.
@@ -214,7 +230,9 @@ impl Server {
             let context = Arc::clone(context);
             let server = self.clone();
             let (eval_result, message) = tokio::task::spawn_blocking(move || {
-                let eval_result = context.lock().unwrap().execute_with_callbacks(
+                let mut context = context.lock().unwrap();
+                context.set_execution_count(execution_count as usize);
+                let eval_result = context.execute_with_callbacks(
                     message.code(),
                     &mut evcxr::EvalCallbacks {
                         input_reader: &|input_request| {
@@ -229,6 +247,31 @@ impl Server {
                                     .unwrap_or_default()
                             })
                         },
+                        display: &|display| {
+                            let message_type = if display.update {
+                                "update_display_data"
+                            } else {
+                                "display_data"
+                            };
+                            let mut transient = object!();
+                            if let Some(display_id) = &display.display_id {
+                                transient["display_id"] = display_id.as_str().into();
+                            }
+                            server.tokio_handle.block_on(async {
+                                let result = message
+                                    .new_message(message_type)
+                                    .with_content(object! {
+                                        "data" => mime_data(&display.content_by_mime_type),
+                                        "metadata" => object!(),
+                                        "transient" => transient,
+                                    })
+                                    .send(&mut *server.iopub.lock().await)
+                                    .await;
+                                if let Err(error) = result {
+                                    eprintln!("Failed to send {message_type}: {error}");
+                                }
+                            })
+                        },
                     },
                 );
                 (eval_result, message)
@@ -236,27 +279,25 @@ impl Server {
             .await?;
             match eval_result {
                 Ok(output) => {
+                    if let (Some(generation), Some(build_num)) =
+                        (output.generation, output.build_num)
+                    {
+                        let mut requests = self.recent_execution_requests.lock().await;
+                        if requests.len() == MAX_RECENT_EXECUTION_REQUESTS {
+                            requests.pop_front();
+                        }
+                        requests.push_back(((generation, build_num), message.clone()));
+                    }
                     if !output.is_empty() {
                         // Increase the odds that stdout will have been finished being sent. A
                         // less hacky alternative would be to add a print statement, then block
                         // waiting for it.
                         tokio::time::sleep(Duration::from_millis(1)).await;
-                        let mut data = HashMap::new();
-                        for (k, v) in output.content_by_mime_type {
-                            if k.contains("json") {
-                                data.insert(
-                                    k,
-                                    serde_json::from_str(&v).unwrap_or_else(|_| Value::from(v)),
-                                );
-                            } else {
-                                data.insert(k, Value::from(v));
-                            }
-                        }
                         message
                             .new_message("execute_result")
                             .with_content(object! {
                                 "execution_count" => execution_count,
-                                "data" => data,
+                                "data" => mime_data(&output.content_by_mime_type),
                                 "metadata" => object!(),
                             })
                             .send(&mut *self.iopub.lock().await)
@@ -536,6 +577,54 @@ impl Server {
         });
     }
 
+    async fn start_late_output_pass_through_thread(
+        self,
+        late_stdout: crossbeam_channel::Receiver<evcxr::LateOutput>,
+        shutdown_recv: crossbeam_channel::Receiver<()>,
+    ) {
+        let handle = tokio::runtime::Handle::current();
+        tokio::task::spawn_blocking(move || {
+            loop {
+                crossbeam_channel::select! {
+                    recv(late_stdout) -> output => match output {
+                        Ok(output) => handle.block_on(self.pass_late_output_line(output)),
+                        Err(_) => return,
+                    },
+                    recv(shutdown_recv) -> _ => return,
+                }
+            }
+        });
+    }
+
+    /// Sends output that a cell printed after it completed with the cell's execution request as
+    /// its parent, so that the frontend shows it with that cell.
+    async fn pass_late_output_line(&self, output: evcxr::LateOutput) {
+        let exec_request = self
+            .recent_execution_requests
+            .lock()
+            .await
+            .iter()
+            .find(|(cell, _)| *cell == (output.generation, output.build_num))
+            .map(|(_, exec_request)| exec_request.clone());
+        // If we've not recorded the cell yet, then it's yet to finish executing, so is the latest.
+        let exec_request = match exec_request {
+            Some(exec_request) => Some(exec_request),
+            None => self.latest_execution_request.lock().await.clone(),
+        };
+        if let Some(exec_request) = exec_request
+            && let Err(error) = exec_request
+                .new_message("stream")
+                .with_content(object! {
+                    "name" => "stdout",
+                    "text" => format!("{}\n", output.line),
+                })
+                .send(&mut *self.iopub.lock().await)
+                .await
+        {
+            eprintln!("output stdout error: {error}");
+        }
+    }
+
     async fn pass_output_line(&self, output_name: &'static str, line: String) {
         let mut message = None;
         if let Some(exec_request) = &*self.latest_execution_request.lock().await {
@@ -760,6 +849,22 @@ fn kernel_info() -> Value {
     }
 }
 
+/// Converts content keyed by mime type into the data of a Jupyter message. JSON content is sent as
+/// JSON rather than as a string, provided that it parses.
+fn mime_data(content_by_mime_type: &HashMap<String, String>) -> HashMap<String, Value> {
+    content_by_mime_type
+        .iter()
+        .map(|(mime_type, content)| {
+            let value = if mime_type.contains("json") {
+                serde_json::from_str(content).unwrap_or_else(|_| Value::from(content.as_str()))
+            } else {
+                Value::from(content.as_str())
+            };
+            (mime_type.clone(), value)
+        })
+        .collect()
+}
+
 async fn handle_completion_request(
     context: &Arc<std::sync::Mutex<CommandContext>>,
     message: JupyterMessage,
@@ -26,9 +26,12 @@ use rustyline::Movement;
 use rustyline::Word;
 use rustyline::error::ReadlineError;
 use rustyline::history::DefaultHistory;
+use std::collections::HashMap;
 use std::fs;
 use std::io;
+use std::io::IsTerminal as _;
 use std::sync::Arc;
+use std::sync::Mutex;
 use yansi::Color;
 use yansi::Paint as _;
 
@@ -37,6 +40,44 @@ const PROMPT: &str = ">> ";
 struct Repl {
     command_context: Arc<BgInitMutex<Result<CommandContext, Error>>>,
     ide_mode: bool,
+    last_display: Arc<Mutex<Option<LastDisplay>>>,
+    cell_numbers: Arc<Mutex<CellNumbers>>,
+}
+
+/// Numbers the cells that the user runs, so that output that they print after they've completed
+/// can be marked with the cell that printed it.
+#[derive(Default)]
+struct CellNumbers {
+    by_build_num: HashMap<i32, usize>,
+    count: usize,
+}
+
+impl CellNumbers {
+    /// Returns the number of a cell that's about to run.
+    fn start_cell(&mut self) -> usize {
+        self.count += 1;
+        self.count
+    }
+
+    /// Records that the cell most recently started had the supplied build number.
+    fn record(&mut self, build_num: i32) {
+        self.by_build_num.insert(build_num, self.count);
+    }
+
+    fn cell_number(&self, build_num: i32) -> usize {
+        // If we don't know the build number yet, then the cell is yet to return from execution.
+        self.by_build_num
+            .get(&build_num)
+            .copied()
+            .unwrap_or(self.count)
+    }
+}
+
+/// The most recently printed display, provided nothing has been printed since. Updates to it can
+/// be drawn in place.
+struct LastDisplay {
+    display_id: String,
+    num_lines: usize,
 }
 
 fn send_output<T: io::Write + Send + 'static>(
@@ -44,9 +85,11 @@ fn send_output<T: io::Write + Send + 'static>(
     mut printer: Option<impl ExternalPrinter + Send + 'static>,
     mut fallback_output: T,
     color: Option<Color>,
+    last_display: Arc<Mutex<Option<LastDisplay>>>,
 ) {
     std::thread::spawn(move || {
         while let Ok(line) = channel.recv() {
+            *last_display.lock().unwrap() = None;
             let to_print = if let Some(color) = color {
                 format!("{}\n", line.paint(color))
             } else {
@@ -71,12 +114,51 @@ impl Repl {
     ) -> Repl {
         let stdout_printer = editor.create_external_printer().ok();
         let stderr_printer = editor.create_external_printer().ok();
+        let late_stdout_printer = editor.create_external_printer().ok();
         let stderr_colour = Some(Color::BrightRed);
+        let last_display = Arc::new(Mutex::new(None));
+        let output_last_display = Arc::clone(&last_display);
+        let cell_numbers = Arc::new(Mutex::new(CellNumbers::default()));
+        let output_cell_numbers = Arc::clone(&cell_numbers);
         let initialize = move || -> Result<CommandContext, Error> {
             let (mut command_context, outputs) = CommandContext::new()?;
 
-            send_output(outputs.stdout, stdout_printer, io::stdout(), None);
-            send_output(outputs.stderr, stderr_printer, io::stderr(), stderr_colour);
+            send_output(
+                outputs.stdout,
+                stdout_printer,
+                io::stdout(),
+                None,
+                Arc::clone(&output_last_display),
+            );
+            send_output(
+                outputs.stderr,
+                stderr_printer,
+                io::stderr(),
+                stderr_colour,
+                Arc::clone(&output_last_display),
+            );
+            let (late_stdout_sender, late_stdout_receiver) = crossbeam_channel::unbounded();
+            std::thread::spawn(move || {
+                while let Ok(output) = outputs.late_stdout.recv() {
+                    let cell = output_cell_numbers
+                        .lock()
+                        .unwrap()
+                        .cell_number(output.build_num);
+                    if late_stdout_sender
+                        .send(format!("[cell {cell}] {}", output.line))
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+            });
+            send_output(
+                late_stdout_receiver,
+                late_stdout_printer,
+                io::stdout(),
+                None,
+                output_last_display,
+            );
             command_context.execute(":load_config --quiet")?;
             if !opt.is_empty() {
                 // Ignore failure
@@ -89,15 +171,76 @@ impl Repl {
         Repl {
             command_context,
             ide_mode,
+            last_display,
+            cell_numbers,
+        }
+    }
+
+    /// Prints a display, redrawing it in place if it's an update to the last thing printed. Displays
+    /// without any text are printed as a placeholder that lists their mime types, e.g. `[image/png]`.
+    fn print_display(&self, display: &evcxr::DisplayBundle) {
+        let text = match display.content_by_mime_type.get("text/plain") {
+            Some(text) => text.clone(),
+            None => {
+                let mut mime_types: Vec<&str> = display
+                    .content_by_mime_type
+                    .keys()
+                    .map(String::as_str)
+                    .collect();
+                mime_types.sort_unstable();
+                format!("[{}]", mime_types.join(", "))
+            }
+        };
+        let mut last_display = self.last_display.lock().unwrap();
+        if let Some(last) = last_display.as_ref()
+            && display.update
+            && display.display_id.as_ref() == Some(&last.display_id)
+            && io::stdout().is_terminal()
+        {
+            // Move the cursor to the start of the previous display and clear from there down.
+            print!("\x1b[{}A\x1b[J", last.num_lines);
         }
+        println!("{text}");
+        *last_display = display.display_id.clone().map(|display_id| LastDisplay {
+            display_id,
+            num_lines: text.lines().count().max(1),
+        });
     }
+
+    /// Reads a line of input for the user's code from our stdin.
+    fn read_input(&self, request: evcxr::InputRequest) -> String {
+        use std::io::Write;
+        // Whatever we print means that the last display can no longer be redrawn.
+        *self.last_display.lock().unwrap() = None;
+        print!("{}", request.prompt);
+        let _ = io::stdout().flush();
+        let mut line = String::new();
+        let _ = io::stdin().read_line(&mut line);
+        line.trim_end_matches(['\r', '\n']).to_owned()
+    }
+
     fn execute(&mut self, to_run: &str) -> Result<(), Error> {
+        // The prompt has been printed since any display from a previous cell.
+        *self.last_display.lock().unwrap() = None;
+        let cell_number = self.cell_numbers.lock().unwrap().start_cell();
         let execution_result = match &mut *self.command_context.lock() {
-            Ok(context) => context.execute(to_run),
+            Ok(context) => {
+                context.set_execution_count(cell_number);
+                context.execute_with_callbacks(
+                    to_run,
+                    &mut evcxr::EvalCallbacks {
+                        input_reader: &|request| self.read_input(request),
+                        display: &|display| self.print_display(display),
+                    },
+                )
+            }
             Err(error) => return Err(error.clone()),
         };
         let success = match execution_result {
             Ok(output) => {
+                if let Some(build_num) = output.build_num {
+                    self.cell_numbers.lock().unwrap().record(build_num);
+                }
                 if let Some(text) = output.get("text/plain") {
                     println!("{text}");
                 }
@@ -5,8 +5,12 @@
 // or https://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
-#[cfg(feature = "bytes")]
-extern crate base64;
+use std::any::Any;
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::OnceLock;
 
 pub trait Display {
     /// Implementation should emit a representation of itself in one or mime
@@ -17,6 +21,7 @@ pub trait Display {
 /// Represents a mime type for some content that is yet to be emitted.
 pub struct ContentMimeType {
     mime_type: String,
+    display_id: Option<String>,
 }
 
 /// Prepares to output some content with the specified mime type.
@@ -26,10 +31,23 @@ pub struct ContentMimeType {
 pub fn mime_type<S: Into<String>>(mime_type: S) -> ContentMimeType {
     ContentMimeType {
         mime_type: mime_type.into(),
+        display_id: None,
     }
 }
 
 impl ContentMimeType {
+    /// Sets an ID for the content, so that it can later be replaced by calling
+    /// `update`. The ID should not contain newlines.
+    /// ```
+    /// evcxr_runtime::mime_type("text/plain")
+    ///     .display_id("progress")
+    ///     .text("0%");
+    /// ```
+    pub fn display_id<S: Into<String>>(mut self, display_id: S) -> Self {
+        self.display_id = Some(display_id.into());
+        self
+    }
+
     /// Emits the supplied content, which should be of the mime type already
     /// specified. If the type is a binary format (e.g. image/png), the content
     /// should have already been base64 encoded.
@@ -38,31 +56,201 @@ impl ContentMimeType {
     ///     .text("<span style=\"color: red\">>Hello world</span>");
     /// ```
     pub fn text<S: AsRef<str>>(self, text: S) {
-        println!(
-            "EVCXR_BEGIN_CONTENT {}\n{}\nEVCXR_END_CONTENT",
-            self.mime_type,
-            text.as_ref()
-        );
+        self.emit("display_id", false, text.as_ref().as_bytes());
+    }
+
+    /// Replaces content previously emitted with the same display ID. Does
+    /// the same as `text` if no display ID was set.
+    /// ```
+    /// for percent in [0, 50, 100] {
+    ///     evcxr_runtime::mime_type("text/plain")
+    ///         .display_id("progress")
+    ///         .update(format!("{percent}%"));
+    /// }
+    /// ```
+    pub fn update<S: AsRef<str>>(self, text: S) {
+        self.emit("update_display_id", false, text.as_ref().as_bytes());
     }
 
     /// Emits the supplied content, which should be of the mime type already
-    /// specified. The content is a binary format (e.g. image/png), the content
-    /// will be base64 encoded.
+    /// specified. If the type is a binary format (e.g. image/png), Evcxr
+    /// base64 encodes the content before passing it on, so it shouldn't be
+    /// encoded beforehand.
     /// ```
     /// let buffer: Vec<u8> = vec![];
     /// evcxr_runtime::mime_type("image/png").bytes(&buffer);
     /// ```
     #[cfg(feature = "bytes")]
     pub fn bytes(self, buffer: &[u8]) {
-        use base64::Engine as _;
+        self.emit("display_id", true, buffer);
+    }
+
+    fn emit(self, display_id_attribute: &str, is_bytes: bool, content: &[u8]) {
+        let mut header = format!("EVCXR_BEGIN_CONTENT {}", self.mime_type);
+        if is_bytes {
+            header.push_str(" bytes");
+        }
+        if let Some(display_id) = &self.display_id {
+            header.push_str(&format!(" {display_id_attribute}={display_id}"));
+        }
+        if send_to_evcxr(&header, content) {
+            return;
+        }
+        // Older versions of Evcxr read content from stdout, which can only carry text.
+        #[cfg(feature = "bytes")]
+        if is_bytes {
+            use base64::Engine as _;
+            let header = header.replacen(" bytes", "", 1);
+            let text = base64::engine::general_purpose::STANDARD.encode(content);
+            println!("{header}\n{text}\nEVCXR_END_CONTENT");
+            return;
+        }
+        let text = String::from_utf8_lossy(content);
+        println!("{header}\n{text}\nEVCXR_END_CONTENT");
+    }
+}
+
+/// Shows the content that `emit` emits via [`mime_type`] as a single display, with each mime type
+/// being an alternative representation of the same thing. Frontends then show whichever of the
+/// representations they prefer, rather than each of them. Otherwise, each piece of content is shown
+/// as a separate display.
+/// ```
+/// evcxr_runtime::display(|| {
+///     evcxr_runtime::mime_type("text/html").text("<b>42</b>");
+///     evcxr_runtime::mime_type("text/plain").text("42");
+/// });
+/// ```
+pub fn display(emit: impl FnOnce()) {
+    thread_local! {
+        static NESTED: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+    }
+    // Ends the display even if `emit` panics.
+    struct EndDisplay;
+    impl Drop for EndDisplay {
+        fn drop(&mut self) {
+            NESTED.set(false);
+            send_to_evcxr("EVCXR_END_DISPLAY", &[]);
+        }
+    }
+    if NESTED.replace(true) {
+        emit();
+        return;
+    }
+    let _end = EndDisplay;
+    send_to_evcxr("EVCXR_BEGIN_DISPLAY", &[]);
+    emit();
+}
+
+/// Sends a message via the function that Evcxr provides for the purpose, provided that we're
+/// running inside Evcxr. This avoids the message getting mixed up with other output. Returns false
+/// if we're not running inside Evcxr.
+fn send_to_evcxr(header: &str, payload: &[u8]) -> bool {
+    use std::io::Write;
+    type ControlFn = extern "C" fn(*const u8, usize, *const u8, usize);
+    static CONTROL_FN: OnceLock<Option<ControlFn>> = OnceLock::new();
+    let control_fn = CONTROL_FN.get_or_init(|| {
+        // Safety: The function has this signature.
+        evcxr_fn_address("EVCXR_CONTROL_FN")
+            .map(|address| unsafe { std::mem::transmute::<usize, ControlFn>(address) })
+    });
+    let Some(control_fn) = control_fn else {
+        return false;
+    };
+    // Make sure anything that was printed beforehand is output first.
+    let _ = std::io::stdout().flush();
+    control_fn(
+        header.as_ptr(),
+        header.len(),
+        payload.as_ptr(),
+        payload.len(),
+    );
+    true
+}
 
-        self.text(base64::engine::general_purpose::STANDARD.encode(buffer))
+/// Returns the value stored under `key` for the rest of the session, calling `init` to create it if
+/// there isn't one yet. When running inside Evcxr, values persist between cells, even though
+/// they're not stored in variables, which makes this useful for things like caches, connections
+/// and values whose types can't be named. Elsewhere, values persist for the life of the process.
+///
+/// If multiple threads ask for the same key at once, `init` may be called more than once, with all
+/// but one of the values created being dropped. Panics if the existing value for `key` isn't a `T`.
+/// ```
+/// let cache = evcxr_runtime::session_value("cache", || {
+///     std::sync::Mutex::new(std::collections::HashMap::<String, u64>::new())
+/// });
+/// cache.lock().unwrap().insert("answer".to_owned(), 42);
+/// ```
+pub fn session_value<T: Any + Send + Sync>(key: &str, init: impl FnOnce() -> T) -> Arc<T> {
+    let value = match session_value_fn() {
+        Some(session_value_fn) => {
+            let mut current = session_value_fn(key.as_ptr(), key.len(), std::ptr::null_mut());
+            if current.is_null() {
+                let created: *mut SessionValue = Box::into_raw(Box::new(Arc::new(init())));
+                current = session_value_fn(key.as_ptr(), key.len(), created.cast());
+                if current != created.cast() {
+                    // Another thread got there first.
+                    // Safety: The value wasn't stored, so is still ours.
+                    drop(unsafe { Box::from_raw(created) });
+                }
+            }
+            // Safety: Values are never removed, and all were created above.
+            Arc::clone(unsafe { &*current.cast::<SessionValue>() })
+        }
+        None => {
+            static VALUES: OnceLock<Mutex<HashMap<String, SessionValue>>> = OnceLock::new();
+            let values = VALUES.get_or_init(Default::default).lock().unwrap();
+            if let Some(value) = values.get(key) {
+                Arc::clone(value)
+            } else {
+                // We don't hold the lock while initialising, in case `init` wants another value.
+                drop(values);
+                let created: SessionValue = Arc::new(init());
+                let mut values = VALUES.get().unwrap().lock().unwrap();
+                Arc::clone(values.entry(key.to_owned()).or_insert(created))
+            }
+        }
+    };
+    match value.downcast() {
+        Ok(value) => value,
+        Err(_) => panic!("Session value `{key}` has a different type"),
     }
 }
 
+type SessionValue = Arc<dyn Any + Send + Sync>;
+
+/// Returns the function that Evcxr provides for storing session values, if we're running inside
+/// Evcxr. Given a key and a null pointer, the function returns the value stored under the key, or
+/// null if there isn't one. Given a non-null pointer to a `SessionValue`, it stores it if there's
+/// no value yet, then returns the stored value.
+fn session_value_fn() -> Option<SessionValueFn> {
+    static SESSION_VALUE_FN: OnceLock<Option<SessionValueFn>> = OnceLock::new();
+    *SESSION_VALUE_FN.get_or_init(|| {
+        // Safety: The function has this signature.
+        evcxr_fn_address("EVCXR_SESSION_VALUE_FN")
+            .map(|address| unsafe { std::mem::transmute::<usize, SessionValueFn>(address) })
+    })
+}
+
+type SessionValueFn = extern "C" fn(*const u8, usize, *mut c_void) -> *mut c_void;
+
+/// Returns the address of a function that the Evcxr runtime has published in the environment
+/// variable `var`, provided that we're running inside Evcxr.
+fn evcxr_fn_address(var: &str) -> Option<usize> {
+    // The variable contains our process ID, so that we don't use it if we inherited it from the
+    // Evcxr runtime.
+    let value = std::env::var(var).ok()?;
+    let (pid, address) = value.split_once(':')?;
+    if pid.parse::<u32>().ok()? != std::process::id() {
+        return None;
+    }
+    address.parse().ok()
+}
+
 #[cfg(test)]
 mod tests {
+    use super::display;
     use super::mime_type;
+    use super::session_value;
 
     #[test]
     fn test_emit_data() {
@@ -73,4 +261,37 @@ mod tests {
     fn test_mime_type_accept_string() {
         mime_type("text/plain".to_owned()).text("Hello world");
     }
+
+    #[test]
+    fn test_session_value() {
+        let counter = session_value("counter", || std::sync::atomic::AtomicU32::new(0));
+        counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let counter = session_value("counter", || -> std::sync::atomic::AtomicU32 {
+            panic!("Should have been stored")
+        });
+        assert_eq!(counter.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Session value `name` has a different type")]
+    fn test_session_value_wrong_type() {
+        session_value("name", || "foo".to_owned());
+        session_value("name", || 42);
+    }
+
+    #[test]
+    fn test_display() {
+        display(|| {
+            mime_type("text/html").text("<b>Hello world</b>");
+            mime_type("text/plain").text("Hello world");
+        });
+    }
+
+    #[test]
+    fn test_update_display() {
+        mime_type("text/plain").display_id("progress").text("0%");
+        mime_type("text/plain")
+            .display_id("progress")
+            .update("100%");
+    }
 }